@@ -0,0 +1,51 @@
+use super::routes::APIResponse;
+use crate::config::Configuration;
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::warn;
+
+/// Middleware protecting the control endpoints: requests must carry the configured api token as
+/// `Authorization: Bearer <token>`. If no token is configured the control endpoints are disabled.
+pub async fn require_api_token(request: Request, next: Next) -> Response {
+    let token = match Configuration::api_token() {
+        Some(token) => token,
+        None => {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(APIResponse::<()>::error(Some(
+                    "Control API is disabled, set an api token to enable it".to_string(),
+                ))),
+            )
+                .into_response()
+        }
+    };
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes()));
+    if authorized {
+        next.run(request).await
+    } else {
+        warn!("Rejected unauthorized request to {}", request.uri());
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(APIResponse::<()>::error(Some("Unauthorized".to_string()))),
+        )
+            .into_response()
+    }
+}
+
+// Compares the tokens without leaking through timing how many bytes matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,229 @@
+"use strict";
+
+const REFRESH_MS = 5000;
+const MAX_REJECTIONS = 50;
+
+const $ = (id) => document.getElementById(id);
+
+async function fetchData(path) {
+  try {
+    const response = await fetch(path);
+    const body = await response.json();
+    return body.success ? body.data : null;
+  } catch (e) {
+    return null;
+  }
+}
+
+function formatHashrate(hashrate) {
+  const units = ["H/s", "KH/s", "MH/s", "GH/s", "TH/s", "PH/s", "EH/s"];
+  let i = 0;
+  while (hashrate >= 1000 && i < units.length - 1) {
+    hashrate /= 1000;
+    i++;
+  }
+  return hashrate.toFixed(2) + " " + units[i];
+}
+
+function formatBytes(bytes) {
+  const units = ["B", "KiB", "MiB", "GiB"];
+  let i = 0;
+  while (bytes >= 1024 && i < units.length - 1) {
+    bytes /= 1024;
+    i++;
+  }
+  return bytes.toFixed(1) + " " + units[i];
+}
+
+function cell(row, text, className) {
+  const td = row.insertCell();
+  td.textContent = text;
+  if (className) {
+    td.className = className;
+  }
+}
+
+// Components are either "Up", "Down" or { "Down": [...] } for downstreams and upstreams
+function componentStatus(value) {
+  if (value === "Up") {
+    return ["Up", "up"];
+  }
+  if (value === "Down") {
+    return ["Down", "down"];
+  }
+  if (value && value.Down) {
+    return ["Down (" + value.Down.join(", ") + ")", "down"];
+  }
+  return [String(value), ""];
+}
+
+async function refreshPool() {
+  const pool = await fetchData("/api/pool/info");
+  $("pool-address").textContent = pool ? pool.address : "unavailable";
+  $("pool-latency").textContent = pool ? pool.latency + " ms" : "-";
+}
+
+async function refreshComponents() {
+  const state = await fetchData("/api/proxy/state");
+  const body = $("components").tBodies[0];
+  body.innerHTML = "";
+  if (!state) {
+    return;
+  }
+  const components = [
+    ["Pool", state.pool],
+    ["Template provider", state.tp],
+    ["Job declarator", state.jd],
+    ["Share accounter", state.share_accounter],
+    ["Translator", state.translator],
+    ["Downstream", state.downstream],
+    ["Upstream", state.upstream],
+  ];
+  for (const [name, value] of components) {
+    const [text, className] = componentStatus(value);
+    const row = body.insertRow();
+    cell(row, name);
+    cell(row, text, className);
+  }
+  if (state.inconsistency !== null) {
+    const row = body.insertRow();
+    cell(row, "Inconsistency");
+    cell(row, "code " + state.inconsistency, "down");
+  }
+}
+
+async function refreshHealth() {
+  const response = await fetch("/api/health").catch(() => null);
+  const health = $("health");
+  if (response && response.ok) {
+    health.textContent = "Healthy";
+    health.className = "badge up";
+  } else {
+    health.textContent = "Degraded";
+    health.className = "badge down";
+  }
+}
+
+async function refreshMiners() {
+  const miners = await fetchData("/api/stats/miners");
+  const body = $("miners").tBodies[0];
+  body.innerHTML = "";
+  if (!miners) {
+    return;
+  }
+  const ids = Object.keys(miners).sort((a, b) => a - b);
+  for (const id of ids) {
+    const miner = miners[id];
+    const row = body.insertRow();
+    cell(row, id);
+    cell(row, miner.worker_name || "-");
+    cell(row, miner.device_name || "-");
+    cell(row, formatHashrate(miner.hashrate));
+    cell(row, miner.current_difficulty.toFixed(2));
+    cell(row, miner.accepted_shares);
+    cell(row, miner.rejected_shares);
+  }
+}
+
+function showJob(jobId, futureJob) {
+  $("job-id").textContent = jobId + (futureJob ? " (future)" : "");
+}
+
+function showBlockFound(timestamp, height, workerName) {
+  const heightText = height !== null ? "height " + height + ", " : "";
+  $("block-found").textContent =
+    new Date(timestamp).toLocaleString() + " (" + heightText + "by " + workerName + ")";
+}
+
+// The template is only known when JD is enabled
+function showTemplate(template) {
+  if (!template) {
+    for (const id of ["id", "height", "version", "value", "outputs", "merkle-path"]) {
+      $("template-" + id).textContent = "-";
+    }
+    return;
+  }
+  $("template-id").textContent =
+    template.template_id + (template.future_template ? " (future)" : "");
+  $("template-height").textContent = template.height !== null ? template.height : "-";
+  $("template-version").textContent = "0x" + template.version.toString(16).padStart(8, "0");
+  $("template-value").textContent =
+    (template.coinbase_tx_value_remaining / 1e8).toFixed(8) + " BTC";
+  $("template-outputs").textContent = template.coinbase_tx_outputs_count;
+  $("template-merkle-path").textContent = template.merkle_path_length + " hashes";
+}
+
+async function refreshJob() {
+  const current = await fetchData("/api/jobs/current");
+  if (!current) {
+    return;
+  }
+  if (current.job) {
+    showJob(current.job.job_id, current.job.future_job);
+  }
+  if (current.prev_hash) {
+    $("prev-hash").textContent = current.prev_hash.prev_hash;
+  }
+  showTemplate(current.template);
+}
+
+async function refreshBlocks() {
+  const blocks = await fetchData("/api/blocks");
+  if (blocks && blocks.length > 0) {
+    const block = blocks[blocks.length - 1];
+    showBlockFound(block.timestamp, block.height, block.worker_name);
+  }
+}
+
+async function refreshSystem() {
+  const system = await fetchData("/api/stats/system");
+  $("cpu").textContent = system ? system["cpu_usage_%"] + " %" : "-";
+  $("memory").textContent = system ? formatBytes(system.memory_usage_bytes) : "-";
+}
+
+async function refresh() {
+  await Promise.all([
+    refreshHealth(),
+    refreshPool(),
+    refreshComponents(),
+    refreshMiners(),
+    refreshJob(),
+    refreshSystem(),
+  ]);
+  $("updated").textContent = "updated " + new Date().toLocaleTimeString();
+}
+
+function listenEvents() {
+  const types = ["share_rejected", "new_job", "new_prev_hash", "block_found"];
+  const source = new EventSource("/api/events?types=" + types.join(","));
+
+  source.addEventListener("share_rejected", (e) => {
+    const event = JSON.parse(e.data);
+    const body = $("rejections").tBodies[0];
+    const row = body.insertRow(0);
+    cell(row, new Date(event.timestamp).toLocaleTimeString());
+    cell(row, event.worker_name);
+    cell(row, event.job_id);
+    cell(row, event.reason);
+    while (body.rows.length > MAX_REJECTIONS) {
+      body.deleteRow(-1);
+    }
+  });
+  source.addEventListener("new_job", (e) => {
+    const event = JSON.parse(e.data);
+    showJob(event.job_id, event.future_job);
+  });
+  source.addEventListener("new_prev_hash", (e) => {
+    const event = JSON.parse(e.data);
+    $("prev-hash").textContent = event.prev_hash;
+  });
+  source.addEventListener("block_found", (e) => {
+    const event = JSON.parse(e.data);
+    showBlockFound(event.timestamp, event.height, event.worker_name);
+  });
+}
+
+refresh();
+refreshBlocks();
+setInterval(refresh, REFRESH_MS);
+listenEvents();
@@ -0,0 +1,372 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+/// One bucket for each minute of the last 24 hours.
+const BUCKET_SECS: u64 = 60;
+const MAX_BUCKETS: usize = 24 * 60;
+/// Workers not seen for longer than the biggest window are dropped.
+const WORKER_TTL_SECS: u64 = BUCKET_SECS * MAX_BUCKETS as u64;
+/// Windows reported by the API, in minutes.
+const WINDOWS: [(&str, usize); 4] = [("1m", 1), ("15m", 15), ("1h", 60), ("24h", 24 * 60)];
+/// Window, in minutes, the hashrate and the reject rate of a worker are evaluated on
+const HEALTH_WINDOW: usize = 15;
+/// Min number of shares in the health window for the hashrate and the reject rate to be
+/// evaluated, with fewer shares the measured hashrate is too noisy
+const MIN_SHARES_FOR_HEALTH: u64 = 10;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    // unix time / BUCKET_SECS
+    minute: u64,
+    accepted_difficulty: f64,
+    accepted: u64,
+    rejected: u64,
+    stale: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ShareOutcome {
+    Accepted(f32),
+    Rejected,
+    Stale,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WindowStats {
+    /// Computed from the sum of the accepted shares difficulty
+    pub hashrate: f64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub stale: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Healthy,
+    /// Connected but no shares for longer than the idle timeout
+    Idle,
+    /// Hashrate below the expected one or reject rate above the max one
+    Degraded,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WorkerHealth {
+    pub state: HealthState,
+    pub reasons: Vec<String>,
+}
+
+/// Limits the health of the workers is evaluated against
+#[derive(Debug, Clone, Copy)]
+pub struct HealthThresholds {
+    pub idle_timeout_secs: u64,
+    /// In h/s
+    pub expected_hashrate: Option<f64>,
+    /// Percentage of rejected and stale shares
+    pub max_reject_rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerStats {
+    pub worker_name: String,
+    pub connected: bool,
+    pub last_share: Option<u64>,
+    pub windows: HashMap<&'static str, WindowStats>,
+    pub health: WorkerHealth,
+}
+
+/// Share history of a worker, kept as a bounded ring of per minute buckets.
+#[derive(Debug, Default)]
+struct WorkerHistory {
+    buckets: VecDeque<Bucket>,
+    // Connections currently authorized with this worker name
+    connections: u32,
+    // When the first of the current connections was authorized
+    connected_since: Option<u64>,
+    last_seen: u64,
+    last_share: Option<u64>,
+}
+
+impl WorkerHistory {
+    fn record(&mut self, outcome: ShareOutcome, now: u64) {
+        let minute = now / BUCKET_SECS;
+        // Shares are recorded in order so only the last bucket can be the current one
+        if !matches!(self.buckets.back(), Some(b) if b.minute >= minute) {
+            self.buckets.push_back(Bucket {
+                minute,
+                ..Default::default()
+            });
+            while self.buckets.len() > MAX_BUCKETS {
+                self.buckets.pop_front();
+            }
+        }
+        let bucket = self.buckets.back_mut().expect("Bucket pushed above");
+        match outcome {
+            ShareOutcome::Accepted(difficulty) => {
+                bucket.accepted_difficulty += difficulty as f64;
+                bucket.accepted += 1;
+            }
+            ShareOutcome::Rejected => bucket.rejected += 1,
+            ShareOutcome::Stale => bucket.stale += 1,
+        }
+        self.last_seen = now;
+        self.last_share = Some(now);
+    }
+
+    // Only complete buckets are counted, so that the hashrate of short windows is not computed on
+    // a few seconds. A window is up to one bucket behind.
+    fn window(&self, minutes: usize, now: u64) -> WindowStats {
+        let current_minute = now / BUCKET_SECS;
+        let first_minute = current_minute.saturating_sub(minutes as u64);
+        let mut stats = WindowStats {
+            hashrate: 0.0,
+            accepted: 0,
+            rejected: 0,
+            stale: 0,
+        };
+        let mut difficulty = 0.0;
+        for bucket in self.buckets.iter().rev() {
+            if bucket.minute >= current_minute {
+                continue;
+            }
+            if bucket.minute < first_minute {
+                break;
+            }
+            difficulty += bucket.accepted_difficulty;
+            stats.accepted += bucket.accepted;
+            stats.rejected += bucket.rejected;
+            stats.stale += bucket.stale;
+        }
+        stats.hashrate = difficulty * 2f64.powi(32) / (minutes as u64 * BUCKET_SECS) as f64;
+        stats
+    }
+
+    fn health(&self, thresholds: &HealthThresholds, now: u64) -> WorkerHealth {
+        let Some(connected_since) = self.connected_since else {
+            return WorkerHealth {
+                state: HealthState::Disconnected,
+                reasons: Vec::new(),
+            };
+        };
+        // A worker that never submitted is idle from when it connected
+        let active_since = self.last_share.unwrap_or(0).max(connected_since);
+        let idle_secs = now.saturating_sub(active_since);
+        if idle_secs >= thresholds.idle_timeout_secs {
+            return WorkerHealth {
+                state: HealthState::Idle,
+                reasons: vec![format!("no shares for {} minutes", idle_secs / 60)],
+            };
+        }
+        let mut reasons = Vec::new();
+        let window = self.window(HEALTH_WINDOW, now);
+        let shares = window.accepted + window.rejected + window.stale;
+        // Only evaluated once the worker has been connected for the whole window
+        let evaluated = shares >= MIN_SHARES_FOR_HEALTH
+            && now.saturating_sub(connected_since) >= HEALTH_WINDOW as u64 * BUCKET_SECS;
+        if evaluated {
+            if let Some(expected) = thresholds.expected_hashrate {
+                if window.hashrate < expected {
+                    reasons.push(format!(
+                        "hashrate {:.2} TH/s below the expected {:.2} TH/s",
+                        window.hashrate / 1e12,
+                        expected / 1e12
+                    ));
+                }
+            }
+            let reject_rate = (window.rejected + window.stale) as f64 * 100.0 / shares as f64;
+            if reject_rate > thresholds.max_reject_rate {
+                reasons.push(format!(
+                    "reject rate {:.1}% above {}%",
+                    reject_rate, thresholds.max_reject_rate
+                ));
+            }
+        }
+        WorkerHealth {
+            state: if reasons.is_empty() {
+                HealthState::Healthy
+            } else {
+                HealthState::Degraded
+            },
+            reasons,
+        }
+    }
+}
+
+/// Time windowed share stats indexed by worker name, so that they survive reconnections.
+#[derive(Debug)]
+pub struct WorkersHistory {
+    workers: HashMap<String, WorkerHistory>,
+    thresholds: HealthThresholds,
+}
+
+impl WorkersHistory {
+    pub fn new(thresholds: HealthThresholds) -> Self {
+        Self {
+            workers: HashMap::new(),
+            thresholds,
+        }
+    }
+
+    pub fn set_thresholds(&mut self, thresholds: HealthThresholds) {
+        self.thresholds = thresholds;
+    }
+
+    pub fn on_connect(&mut self, worker_name: &str, now: u64) {
+        let worker = self.workers.entry(worker_name.to_string()).or_default();
+        worker.connections += 1;
+        worker.connected_since.get_or_insert(now);
+        worker.last_seen = now;
+    }
+
+    pub fn on_disconnect(&mut self, worker_name: &str, now: u64) {
+        if let Some(worker) = self.workers.get_mut(worker_name) {
+            worker.connections = worker.connections.saturating_sub(1);
+            if worker.connections == 0 {
+                worker.connected_since = None;
+            }
+            worker.last_seen = now;
+        }
+    }
+
+    /// Called when all the downstreams have been dropped (e.g. the proxy restarted).
+    pub fn disconnect_all(&mut self, now: u64) {
+        for worker in self.workers.values_mut() {
+            worker.connections = 0;
+            worker.connected_since = None;
+            worker.last_seen = now;
+        }
+    }
+
+    pub fn record(&mut self, worker_name: &str, outcome: ShareOutcome, now: u64) {
+        self.workers
+            .entry(worker_name.to_string())
+            .or_default()
+            .record(outcome, now);
+    }
+
+    pub fn stats(&self, now: u64) -> Vec<WorkerStats> {
+        let mut stats: Vec<WorkerStats> = self
+            .workers
+            .iter()
+            .map(|(name, worker)| self.worker_stats(name, worker, now))
+            .collect();
+        stats.sort_by(|a, b| a.worker_name.cmp(&b.worker_name));
+        stats
+    }
+
+    pub fn worker_stats_by_name(&self, worker_name: &str, now: u64) -> Option<WorkerStats> {
+        self.workers
+            .get(worker_name)
+            .map(|worker| self.worker_stats(worker_name, worker, now))
+    }
+
+    pub fn health(&self, worker_name: &str, now: u64) -> Option<WorkerHealth> {
+        self.workers
+            .get(worker_name)
+            .map(|worker| worker.health(&self.thresholds, now))
+    }
+
+    /// Health of all the known workers
+    pub fn workers_health(&self, now: u64) -> impl Iterator<Item = (&str, WorkerHealth)> {
+        self.workers
+            .iter()
+            .map(move |(name, worker)| (name.as_str(), worker.health(&self.thresholds, now)))
+    }
+
+    fn worker_stats(&self, name: &str, worker: &WorkerHistory, now: u64) -> WorkerStats {
+        WorkerStats {
+            worker_name: name.to_string(),
+            connected: worker.connections > 0,
+            last_share: worker.last_share,
+            windows: WINDOWS
+                .iter()
+                .map(|(label, minutes)| (*label, worker.window(*minutes, now)))
+                .collect(),
+            health: worker.health(&self.thresholds, now),
+        }
+    }
+
+    /// Drops disconnected workers that have not been seen in the last 24 hours. It is called
+    /// periodically rather than for each share.
+    pub fn prune(&mut self, now: u64) {
+        self.workers.retain(|_, worker| {
+            worker.connections > 0 || now.saturating_sub(worker.last_seen) < WORKER_TTL_SECS
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const THRESHOLDS: HealthThresholds = HealthThresholds {
+        idle_timeout_secs: 10 * 60,
+        expected_hashrate: None,
+        max_reject_rate: 5.0,
+    };
+
+    #[test]
+    fn computes_windowed_stats() {
+        let mut history = WorkersHistory::new(THRESHOLDS);
+        let start = 1_700_000_000 - 1_700_000_000 % BUCKET_SECS;
+        history.on_connect("w1", start);
+        // One share of difficulty 1000 per minute for two hours
+        for minute in 0..120 {
+            history.record("w1", ShareOutcome::Accepted(1000.0), start + minute * 60);
+        }
+        history.record("w1", ShareOutcome::Stale, start + 119 * 60);
+        history.record("w1", ShareOutcome::Rejected, start + 119 * 60);
+
+        // The minute of the last shares is complete
+        let now = start + 120 * 60;
+        let stats = history.worker_stats_by_name("w1", now).unwrap();
+        let hour = &stats.windows["1h"];
+        assert_eq!(hour.accepted, 60);
+        assert_eq!(hour.rejected, 1);
+        assert_eq!(hour.stale, 1);
+        let expected = 60.0 * 1000.0 * 2f64.powi(32) / 3600.0;
+        assert!((hour.hashrate - expected).abs() / expected < 1e-9);
+        assert_eq!(stats.windows["24h"].accepted, 120);
+        assert_eq!(stats.windows["1m"].accepted, 1);
+        assert!(stats.connected);
+
+        // Disconnected workers are dropped after 24 hours
+        history.on_disconnect("w1", now);
+        history.prune(now + WORKER_TTL_SECS);
+        assert_eq!(history.stats(now + WORKER_TTL_SECS).len(), 0);
+    }
+
+    #[test]
+    fn evaluates_worker_health() {
+        let mut history = WorkersHistory::new(HealthThresholds {
+            expected_hashrate: Some(100e12),
+            ..THRESHOLDS
+        });
+        let start = 1_700_000_000 - 1_700_000_000 % BUCKET_SECS;
+        history.on_connect("w1", start);
+        // About 6.4 TH/s, with one rejected share every ten
+        for second in (0..20 * 60).step_by(6) {
+            let outcome = if second % 60 == 0 {
+                ShareOutcome::Rejected
+            } else {
+                ShareOutcome::Accepted(10_000.0)
+            };
+            history.record("w1", outcome, start + second);
+        }
+        let now = start + 20 * 60;
+        let health = history.health("w1", now).unwrap();
+        assert_eq!(health.state, HealthState::Degraded);
+        assert_eq!(health.reasons.len(), 2);
+
+        assert_eq!(
+            history.health("w1", now + 10 * 60).unwrap().state,
+            HealthState::Idle
+        );
+        history.on_disconnect("w1", now);
+        assert_eq!(
+            history.health("w1", now).unwrap().state,
+            HealthState::Disconnected
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Prometheus metrics of the workers served at `/metrics`, so that their hashrate and health can
+//! be scraped and alerted on.
+
+use std::fmt::Write;
+
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+
+use super::{
+    history::{HealthState, WorkerStats},
+    AppState,
+};
+
+const HEALTH_STATES: [(HealthState, &str); 4] = [
+    (HealthState::Healthy, "healthy"),
+    (HealthState::Idle, "idle"),
+    (HealthState::Degraded, "degraded"),
+    (HealthState::Disconnected, "disconnected"),
+];
+
+pub async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    match state.stats_sender.collect_workers_stats().await {
+        Ok(workers) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            render(&workers),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            format!("Failed to collect stats: {}\n", e),
+        ),
+    }
+}
+
+fn render(workers: &[WorkerStats]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP dmnd_worker_hashrate Hashrate of the worker over the last 15 minutes, in h/s"
+    );
+    let _ = writeln!(out, "# TYPE dmnd_worker_hashrate gauge");
+    for worker in workers {
+        let hashrate = worker.windows.get("15m").map_or(0.0, |w| w.hashrate);
+        let _ = writeln!(
+            out,
+            "dmnd_worker_hashrate{{worker=\"{}\"}} {}",
+            escape(&worker.worker_name),
+            hashrate
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP dmnd_worker_shares Shares of the worker over the last 15 minutes"
+    );
+    let _ = writeln!(out, "# TYPE dmnd_worker_shares gauge");
+    for worker in workers {
+        if let Some(window) = worker.windows.get("15m") {
+            for (outcome, count) in [
+                ("accepted", window.accepted),
+                ("rejected", window.rejected),
+                ("stale", window.stale),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "dmnd_worker_shares{{worker=\"{}\",outcome=\"{}\"}} {}",
+                    escape(&worker.worker_name),
+                    outcome,
+                    count
+                );
+            }
+        }
+    }
+    let _ = writeln!(
+        out,
+        "# HELP dmnd_worker_health 1 for the current health state of the worker"
+    );
+    let _ = writeln!(out, "# TYPE dmnd_worker_health gauge");
+    for worker in workers {
+        for (state, label) in HEALTH_STATES {
+            let _ = writeln!(
+                out,
+                "dmnd_worker_health{{worker=\"{}\",state=\"{}\"}} {}",
+                escape(&worker.worker_name),
+                label,
+                (worker.health.state == state) as u8
+            );
+        }
+    }
+    out
+}
+
+// Label values are quoted, so backslashes, quotes and new lines must be escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
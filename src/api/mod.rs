@@ -1,36 +1,108 @@
+mod auth;
+mod dashboard;
+pub mod history;
+mod metrics;
 mod routes;
 pub mod stats;
 mod utils;
 use crate::router::Router;
-use axum::{routing::get, Router as AxumRouter};
+use axum::{
+    middleware,
+    routing::{get, post},
+    Router as AxumRouter,
+};
 use routes::Api;
 use stats::StatsSender;
+use std::net::SocketAddr;
+use tokio::sync::{mpsc::Sender, watch};
+use tracing::error;
 
 // Holds shared state (like the router) that so that it can be accessed in all routes.
 #[derive(Clone)]
 pub struct AppState {
-    router: Router,
+    // The API server outlives the proxy restarts, the router is updated on each of them
+    router: watch::Receiver<Router>,
     stats_sender: StatsSender,
+    control_sender: Sender<ControlCommand>,
 }
 
-pub(crate) async fn start(router: Router, stats_sender: StatsSender) {
+/// Commands sent by the control endpoints to the proxy main loop.
+#[derive(Debug)]
+pub enum ControlCommand {
+    // Connect to the given pool, or to the best one if None
+    SwitchPool(Option<SocketAddr>),
+    ReconnectTp,
+    // Sent once the configuration was reloaded, restarts the proxy so that it is applied
+    ReloadConfig,
+    Restart,
+}
+
+pub(crate) async fn start(
+    router: watch::Receiver<Router>,
+    stats_sender: StatsSender,
+    control_sender: Sender<ControlCommand>,
+) {
     let state = AppState {
         router,
         stats_sender,
+        control_sender,
     };
+    let control = AxumRouter::new()
+        .route("/api/control/pool/switch", post(Api::switch_pool))
+        .route(
+            "/api/control/miners/{connection_id}/disconnect",
+            post(Api::disconnect_miner),
+        )
+        .route(
+            "/api/control/miners/{connection_id}/difficulty",
+            post(Api::set_miner_difficulty),
+        )
+        .route("/api/control/tp/reconnect", post(Api::reconnect_tp))
+        .route("/api/control/config/reload", post(Api::reload_config))
+        .route("/api/control/restart", post(Api::restart))
+        .route("/api/control/curtailment", post(Api::set_curtailment))
+        .route_layer(middleware::from_fn(auth::require_api_token));
     let app = AxumRouter::new()
+        .route("/", get(dashboard::index))
+        .route("/dashboard.js", get(dashboard::script))
+        .route("/dashboard.css", get(dashboard::style))
         .route("/api/health", get(Api::health_check))
+        .route("/api/health/live", get(Api::health_live))
+        .route("/api/health/ready", get(Api::health_ready))
+        .route("/api/proxy/state", get(Api::get_proxy_state))
+        .route("/api/proxy/history", get(Api::get_proxy_history))
+        .route("/api/jd/jobs", get(Api::get_declared_jobs))
+        .route("/api/blocks", get(Api::get_found_blocks))
+        .route("/api/jobs/current", get(Api::get_current_job))
+        .route("/api/curtailment", get(Api::get_curtailment))
         .route("/api/pool/info", get(Api::get_pool_info))
         .route("/api/stats/miners", get(Api::get_downstream_stats))
         .route("/api/stats/aggregate", get(Api::get_aggregate_stats))
+        .route("/api/stats/pools", get(Api::get_pools_stats))
+        .route("/api/stats/workers", get(Api::get_workers_stats))
+        .route(
+            "/api/stats/workers/{worker_name}",
+            get(Api::get_worker_stats),
+        )
         .route("/api/stats/system", get(Api::system_stats))
+        .route("/api/events", get(Api::events))
+        .route("/metrics", get(metrics::metrics))
+        .merge(control)
         .with_state(state);
 
-    let api_server_port = crate::config::Configuration::api_server_port();
-    let api_server_addr = format!("0.0.0.0:{}", api_server_port);
-    let listener = tokio::net::TcpListener::bind(api_server_addr)
-        .await
-        .expect("Invalid server address");
-    println!("API Server listening on port {}", api_server_port);
-    axum::serve(listener, app).await.unwrap();
+    let api_server_addr = crate::config::Configuration::api_server_addr();
+    let listener = match tokio::net::TcpListener::bind(&api_server_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(
+                "Failed to bind the API server on {}: {}",
+                api_server_addr, e
+            );
+            return;
+        }
+    };
+    println!("API Server listening on {}", api_server_addr);
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("API server failed: {}", e);
+    }
 }
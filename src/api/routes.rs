@@ -1,7 +1,29 @@
-use super::{utils::get_cpu_and_memory_usage, AppState};
-use crate::proxy_state::ProxyState;
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
-use serde::Serialize;
+use super::{utils::get_cpu_and_memory_usage, AppState, ControlCommand};
+use crate::{
+    blocks,
+    config::Configuration,
+    curtailment, events,
+    jd_client::job_declarator::audit,
+    jobs,
+    proxy_state::{ComponentStatus, ProxyState},
+    upstreams,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Json,
+};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, net::SocketAddr};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, warn};
+
+const DEFAULT_DECLARED_JOBS_LIMIT: usize = 50;
 
 pub struct Api {}
 
@@ -20,6 +42,41 @@ impl Api {
         }
     }
 
+    // Retrieves 1m/15m/1h/24h share stats of the workers seen in the last 24 hours
+    pub async fn get_workers_stats(State(state): State<AppState>) -> impl IntoResponse {
+        match state.stats_sender.collect_workers_stats().await {
+            Ok(stats) => (StatusCode::OK, Json(APIResponse::success(Some(stats)))),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(format!(
+                    "Failed to collect stats: {}",
+                    e
+                )))),
+            ),
+        }
+    }
+
+    // Retrieves the windowed share stats of a single worker
+    pub async fn get_worker_stats(
+        State(state): State<AppState>,
+        Path(worker_name): Path<String>,
+    ) -> impl IntoResponse {
+        match state.stats_sender.collect_worker_stats(worker_name).await {
+            Ok(Some(stats)) => (StatusCode::OK, Json(APIResponse::success(Some(stats)))),
+            Ok(None) => (
+                StatusCode::NOT_FOUND,
+                Json(APIResponse::error(Some("Unknown worker".to_string()))),
+            ),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(format!(
+                    "Failed to collect stats: {}",
+                    e
+                )))),
+            ),
+        }
+    }
+
     // Retrieves system stats (CPU and memory usage)
     pub async fn system_stats() -> impl IntoResponse {
         let (cpu, memory) = get_cpu_and_memory_usage().await;
@@ -64,10 +121,168 @@ impl Api {
         (StatusCode::OK, Json(APIResponse::success(Some(result))))
     }
 
+    // Returns the stats of the miners of each upstream: the main pool, the split pool and the
+    // other accounts
+    pub async fn get_pools_stats(State(state): State<AppState>) -> impl IntoResponse {
+        let stats = match state.stats_sender.collect_stats().await {
+            Ok(stats) => stats,
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(APIResponse::error(Some(format!(
+                        "Failed to collect stats: {}",
+                        e
+                    )))),
+                );
+            }
+        };
+        let mut pools = vec![PoolStats::new(
+            upstreams::MAIN_POOL,
+            "main".to_string(),
+            state.router.borrow().current_pool,
+            Vec::new(),
+        )];
+        if ProxyState::is_fallback() {
+            pools.push(PoolStats::new(
+                crate::fallback::FALLBACK_POOL,
+                "fallback".to_string(),
+                None,
+                Vec::new(),
+            ));
+        }
+        for upstream in upstreams::active_upstreams() {
+            pools.push(PoolStats::new(
+                upstream.index,
+                upstream.name,
+                Some(upstream.address),
+                upstream.worker_prefixes,
+            ));
+        }
+        for (connection_id, downstream) in stats {
+            let index = crate::translator::upstream_index(connection_id);
+            if let Some(pool) = pools.iter_mut().find(|pool| pool.index == index) {
+                pool.connected_devices += 1;
+                pool.hashrate += downstream.hashrate as f64;
+                pool.accepted_shares += downstream.accepted_shares;
+                pool.rejected_shares += downstream.rejected_shares;
+            }
+        }
+        (StatusCode::OK, Json(APIResponse::success(Some(pools))))
+    }
+
+    // Retrieves the state of every proxy component
+    pub async fn get_proxy_state() -> impl IntoResponse {
+        match ProxyState::get_state() {
+            Ok(state) => (StatusCode::OK, Json(APIResponse::success(Some(state)))),
+            Err(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(
+                    "Failed to read proxy state".to_string(),
+                ))),
+            ),
+        }
+    }
+
+    // Retrieves the last state transitions of the proxy components and the reconnection counts
+    pub async fn get_proxy_history() -> impl IntoResponse {
+        match ProxyState::get_history() {
+            Ok((transitions, reconnects)) => {
+                let data = serde_json::json!({
+                    "transitions": transitions,
+                    "reconnects": reconnects,
+                });
+                (StatusCode::OK, Json(APIResponse::success(Some(data))))
+            }
+            Err(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(
+                    "Failed to read proxy history".to_string(),
+                ))),
+            ),
+        }
+    }
+
+    // Retrieves the last jobs declared to the JDS with their outcome, newest first
+    pub async fn get_declared_jobs(Query(query): Query<DeclaredJobsQuery>) -> impl IntoResponse {
+        let limit = query.limit.unwrap_or(DEFAULT_DECLARED_JOBS_LIMIT);
+        match audit::get_declared_jobs(limit) {
+            Ok(jobs) => (StatusCode::OK, Json(APIResponse::success(Some(jobs)))),
+            Err(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(
+                    "Failed to read declared jobs".to_string(),
+                ))),
+            ),
+        }
+    }
+
+    // Retrieves the blocks found by the connected miners, newest first
+    pub async fn get_found_blocks() -> impl IntoResponse {
+        match blocks::get_found_blocks() {
+            Ok(blocks) => (StatusCode::OK, Json(APIResponse::success(Some(blocks)))),
+            Err(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(
+                    "Failed to read found blocks".to_string(),
+                ))),
+            ),
+        }
+    }
+
+    // Retrieves the job the miners are working on and the template it was built from
+    pub async fn get_current_job() -> impl IntoResponse {
+        match jobs::get_current_job() {
+            Ok(job) => (StatusCode::OK, Json(APIResponse::success(Some(job)))),
+            Err(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(
+                    "Failed to read the current job".to_string(),
+                ))),
+            ),
+        }
+    }
+
+    // Retrieves the curtailment state of the miners
+    pub async fn get_curtailment() -> impl IntoResponse {
+        match curtailment::status() {
+            Ok(status) => (StatusCode::OK, Json(APIResponse::success(Some(status)))),
+            Err(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(
+                    "Failed to read curtailment state".to_string(),
+                ))),
+            ),
+        }
+    }
+
+    // Curtails the miners, for the given minutes if set, or resumes them
+    pub async fn set_curtailment(Json(request): Json<CurtailmentRequest>) -> impl IntoResponse {
+        let duration = match request.minutes.map(|minutes| minutes.checked_mul(60)) {
+            Some(Some(secs)) => Some(std::time::Duration::from_secs(secs)),
+            Some(None) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(APIResponse::<String>::error(Some(
+                        "Invalid minutes: too large".to_string(),
+                    ))),
+                )
+                    .into_response()
+            }
+            None => None,
+        };
+        if request.curtailed {
+            curtailment::curtail(duration);
+        } else {
+            curtailment::resume();
+        }
+        Self::get_curtailment().await.into_response()
+    }
+
     // Retrieves the current pool information
     pub async fn get_pool_info(State(state): State<AppState>) -> impl IntoResponse {
-        let current_pool_address = state.router.current_pool;
-        let latency = *state.router.latency_rx.borrow();
+        let router = state.router.borrow().clone();
+        let current_pool_address = router.current_pool;
+        let latency = *router.latency_rx.borrow();
 
         match (current_pool_address, latency) {
             (Some(address), Some(latency)) => {
@@ -89,8 +304,216 @@ impl Api {
         }
     }
 
+    // Switches to the given pool, or to the pool with the best latency if no address is given
+    pub async fn switch_pool(
+        State(state): State<AppState>,
+        Json(request): Json<SwitchPoolRequest>,
+    ) -> impl IntoResponse {
+        let router = state.router.borrow().clone();
+        let address = match request.address.map(|a| a.parse::<SocketAddr>()) {
+            Some(Ok(address)) if router.is_known_pool(&address) => Some(address),
+            Some(Ok(address)) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(APIResponse::error(Some(format!(
+                        "{} is not a known pool",
+                        address
+                    )))),
+                )
+            }
+            Some(Err(e)) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(APIResponse::error(Some(format!("Invalid address: {}", e)))),
+                )
+            }
+            None => None,
+        };
+        Self::send_control_command(&state, ControlCommand::SwitchPool(address)).await
+    }
+
+    // Closes the connection with a miner
+    pub async fn disconnect_miner(Path(connection_id): Path<u32>) -> impl IntoResponse {
+        match crate::translator::disconnect_downstream(connection_id) {
+            Ok(true) => (
+                StatusCode::OK,
+                Json(APIResponse::success(Some(format!(
+                    "Miner {} disconnected",
+                    connection_id
+                )))),
+            ),
+            Ok(false) => (
+                StatusCode::NOT_FOUND,
+                Json(APIResponse::error(Some(format!(
+                    "Miner {} not found",
+                    connection_id
+                )))),
+            ),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(format!(
+                    "Failed to disconnect miner: {}",
+                    e
+                )))),
+            ),
+        }
+    }
+
+    // Fixes the difficulty of a miner, a null difficulty gives it back to vardiff
+    pub async fn set_miner_difficulty(
+        Path(connection_id): Path<u32>,
+        Json(request): Json<SetDifficultyRequest>,
+    ) -> impl IntoResponse {
+        if let Some(difficulty) = request.difficulty {
+            if !difficulty.is_finite() || difficulty <= 0.0 {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(APIResponse::error(Some(
+                        "Difficulty must be a positive number".to_string(),
+                    ))),
+                );
+            }
+        }
+        match crate::translator::set_downstream_difficulty(connection_id, request.difficulty).await
+        {
+            Ok(true) => (
+                StatusCode::OK,
+                Json(APIResponse::success(Some(format!(
+                    "Difficulty of miner {} updated",
+                    connection_id
+                )))),
+            ),
+            Ok(false) => (
+                StatusCode::NOT_FOUND,
+                Json(APIResponse::error(Some(format!(
+                    "Miner {} not found",
+                    connection_id
+                )))),
+            ),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(format!(
+                    "Failed to update difficulty: {}",
+                    e
+                )))),
+            ),
+        }
+    }
+
+    // Reconnects to the template provider
+    pub async fn reconnect_tp(State(state): State<AppState>) -> impl IntoResponse {
+        Self::send_control_command(&state, ControlCommand::ReconnectTp).await
+    }
+
+    // Reloads the configuration and restarts the proxy so that it is applied. An invalid
+    // configuration is rejected and the running one is kept.
+    pub async fn reload_config(State(state): State<AppState>) -> impl IntoResponse {
+        // Addresses are resolved while the configuration is validated
+        match tokio::task::spawn_blocking(Configuration::reload).await {
+            Ok(Ok(())) => Self::send_control_command(&state, ControlCommand::ReloadConfig).await,
+            Ok(Err(e)) => (
+                StatusCode::BAD_REQUEST,
+                Json(APIResponse::error(Some(format!(
+                    "Invalid configuration: {}",
+                    e
+                )))),
+            ),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(APIResponse::error(Some(format!(
+                    "Failed to reload the configuration: {}",
+                    e
+                )))),
+            ),
+        }
+    }
+
+    // Restarts the proxy on the current pool
+    pub async fn restart(State(state): State<AppState>) -> impl IntoResponse {
+        Self::send_control_command(&state, ControlCommand::Restart).await
+    }
+
+    async fn send_control_command(
+        state: &AppState,
+        command: ControlCommand,
+    ) -> (StatusCode, Json<APIResponse<String>>) {
+        let description = format!("{:?}", command);
+        match state.control_sender.send(command).await {
+            Ok(()) => (
+                StatusCode::ACCEPTED,
+                Json(APIResponse::success(Some(format!(
+                    "{} scheduled",
+                    description
+                )))),
+            ),
+            Err(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(APIResponse::error(Some(
+                    "Proxy is not accepting commands".to_string(),
+                ))),
+            ),
+        }
+    }
+
+    // Streams proxy events as server-sent events. Events can be filtered by type (comma
+    // separated list) and worker name: /api/events?types=share_rejected,miner_connected&worker=w1
+    pub async fn events(
+        Query(filter): Query<EventsFilter>,
+    ) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+        let types: Option<Vec<String>> = filter
+            .types
+            .map(|types| types.split(',').map(|t| t.trim().to_string()).collect());
+        let worker = filter.worker;
+        let stream = futures::stream::unfold(events::subscribe(), move |mut receiver| {
+            let types = types.clone();
+            let worker = worker.clone();
+            async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) => {
+                            if let Some(types) = &types {
+                                if !types.iter().any(|t| t == event.event_type()) {
+                                    continue;
+                                }
+                            }
+                            if let Some(worker) = &worker {
+                                if event.worker_name() != Some(worker.as_str()) {
+                                    continue;
+                                }
+                            }
+                            let sse_event = match SseEvent::default()
+                                .event(event.event_type())
+                                .json_data(&event)
+                            {
+                                Ok(sse_event) => sse_event,
+                                Err(e) => {
+                                    error!("Failed to serialize event: {}", e);
+                                    continue;
+                                }
+                            };
+                            return Some((Ok::<_, Infallible>(sse_event), receiver));
+                        }
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Events stream lagging, {} events skipped", skipped);
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        Sse::new(stream).keep_alive(KeepAlive::default())
+    }
+
     // Returns the status of the Proxy
     pub async fn health_check() -> impl IntoResponse {
+        if ProxyState::is_fallback() {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(APIResponse::error(Some(
+                    "No SV2 pool is reachable, mining on the SV1 fallback pool".to_string(),
+                ))),
+            );
+        }
         match ProxyState::is_proxy_down() {
             (false, None) => (
                 StatusCode::OK,
@@ -106,6 +529,52 @@ impl Api {
             ),
         }
     }
+
+    // The proxy is alive as long as it answers, the components status is reported for debugging
+    pub async fn health_live() -> impl IntoResponse {
+        match ProxyState::get_components_status() {
+            Ok(components) => (
+                StatusCode::OK,
+                Json(APIResponse::success(Some(HealthReport {
+                    healthy: true,
+                    fallback: ProxyState::is_fallback(),
+                    components,
+                }))),
+            ),
+            Err(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(APIResponse::error(Some(
+                    "Failed to read proxy state".to_string(),
+                ))),
+            ),
+        }
+    }
+
+    // The proxy is ready when every component is up and miners can get jobs
+    pub async fn health_ready() -> impl IntoResponse {
+        let ready = ProxyState::is_ready();
+        let status = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        match ProxyState::get_components_status() {
+            Ok(components) => (
+                status,
+                Json(APIResponse::success(Some(HealthReport {
+                    healthy: ready,
+                    fallback: ProxyState::is_fallback(),
+                    components,
+                }))),
+            ),
+            Err(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(APIResponse::error(Some(
+                    "Failed to read proxy state".to_string(),
+                ))),
+            ),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -117,15 +586,83 @@ struct AggregateStates {
     aggregate_diff: f64,
 }
 
+#[derive(Serialize)]
+struct PoolStats {
+    #[serde(skip)]
+    index: u32,
+    pool: String,
+    address: Option<SocketAddr>,
+    worker_prefixes: Vec<String>,
+    connected_devices: u32,
+    hashrate: f64,
+    accepted_shares: u64,
+    rejected_shares: u64,
+}
+
+impl PoolStats {
+    fn new(
+        index: u32,
+        pool: String,
+        address: Option<SocketAddr>,
+        worker_prefixes: Vec<String>,
+    ) -> Self {
+        Self {
+            index,
+            pool,
+            address,
+            worker_prefixes,
+            connected_devices: 0,
+            hashrate: 0.0,
+            accepted_shares: 0,
+            rejected_shares: 0,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HealthReport {
+    healthy: bool,
+    /// True while the miners are relayed to the SV1 fallback pool
+    fallback: bool,
+    components: Vec<ComponentStatus>,
+}
+
+#[derive(Deserialize)]
+pub struct EventsFilter {
+    types: Option<String>,
+    worker: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeclaredJobsQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct SwitchPoolRequest {
+    address: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SetDifficultyRequest {
+    difficulty: Option<f32>,
+}
+
+#[derive(Deserialize)]
+pub struct CurtailmentRequest {
+    curtailed: bool,
+    minutes: Option<u64>,
+}
+
 #[derive(Debug, Serialize)]
-struct APIResponse<T> {
+pub(super) struct APIResponse<T> {
     success: bool,
     message: Option<String>,
     data: Option<T>,
 }
 
 impl<T: Serialize> APIResponse<T> {
-    fn success(data: Option<T>) -> Self {
+    pub(super) fn success(data: Option<T>) -> Self {
         APIResponse {
             success: true,
             message: None,
@@ -133,7 +670,7 @@ impl<T: Serialize> APIResponse<T> {
         }
     }
 
-    fn error(message: Option<String>) -> Self {
+    pub(super) fn error(message: Option<String>) -> Self {
         APIResponse {
             success: false,
             message,
@@ -1,37 +1,57 @@
+use super::history::{
+    HealthState, HealthThresholds, ShareOutcome, WorkerHealth, WorkerStats, WorkersHistory,
+};
+use crate::{
+    config::Configuration,
+    events::{self, EventKind},
+};
 use serde::Serialize;
 use std::collections::HashMap;
 use tokio::sync::{mpsc, oneshot};
-use tracing::warn;
+use tracing::{info, warn};
+
+/// How often the health of the workers is evaluated and the workers gone for 24 hours dropped
+const HEALTH_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
 
 #[derive(Debug)]
 enum StatsCommand {
     SetupStats(u32),
     UpdateHashrate(u32, f32),
     UpdateDiff(u32, f32),
-    UpdateAcceptedShares(u32),
+    UpdateAcceptedShares(u32, f32),
     UpdateRejectedShares(u32),
+    UpdateStaleShares(u32),
     UpdateDeviceName(u32, String),
+    UpdateWorkerName(u32, String),
     RemoveStats(u32),
+    ResetConnections,
     GetStats(oneshot::Sender<HashMap<u32, DownstreamConnectionStats>>),
+    GetWorkersStats(oneshot::Sender<Vec<WorkerStats>>),
+    GetWorkerStats(String, oneshot::Sender<Option<WorkerStats>>),
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct DownstreamConnectionStats {
     pub device_name: Option<String>,
+    pub worker_name: Option<String>,
     pub hashrate: f32,
     pub accepted_shares: u64,
     pub rejected_shares: u64,
     pub current_difficulty: f32,
+    /// Health of the worker the connection is authorized as
+    pub health: Option<WorkerHealth>,
 }
 
 impl DownstreamConnectionStats {
     fn new() -> Self {
         Self {
             device_name: None,
+            worker_name: None,
             hashrate: 0.0,
             accepted_shares: 0,
             rejected_shares: 0,
             current_difficulty: 0.0,
+            health: None,
         }
     }
 }
@@ -66,22 +86,39 @@ impl StatsSender {
         self.send(StatsCommand::UpdateDiff(connection_id, diff));
     }
 
-    pub fn update_accepted_shares(&self, connection_id: u32) {
-        self.send(StatsCommand::UpdateAcceptedShares(connection_id));
+    pub fn update_accepted_shares(&self, connection_id: u32, difficulty: f32) {
+        self.send(StatsCommand::UpdateAcceptedShares(
+            connection_id,
+            difficulty,
+        ));
     }
 
     pub fn update_rejected_shares(&self, connection_id: u32) {
         self.send(StatsCommand::UpdateRejectedShares(connection_id));
     }
 
+    /// Share rejected because it refers to a job that is not valid anymore
+    pub fn update_stale_shares(&self, connection_id: u32) {
+        self.send(StatsCommand::UpdateStaleShares(connection_id));
+    }
+
     pub fn update_device_name(&self, connection_id: u32, name: String) {
         self.send(StatsCommand::UpdateDeviceName(connection_id, name));
     }
 
+    pub fn update_worker_name(&self, connection_id: u32, name: String) {
+        self.send(StatsCommand::UpdateWorkerName(connection_id, name));
+    }
+
     pub fn remove_stats(&self, connection_id: u32) {
         self.send(StatsCommand::RemoveStats(connection_id));
     }
 
+    /// Drops the stats of all the connections, workers history is kept.
+    pub fn reset_connections(&self) {
+        self.send(StatsCommand::ResetConnections);
+    }
+
     pub async fn collect_stats(&self) -> Result<HashMap<u32, DownstreamConnectionStats>, String> {
         let (tx, rx) = oneshot::channel();
         self.send(StatsCommand::GetStats(tx));
@@ -90,10 +127,28 @@ impl StatsSender {
             Err(e) => Err(e.to_string()),
         }
     }
+
+    pub async fn collect_workers_stats(&self) -> Result<Vec<WorkerStats>, String> {
+        let (tx, rx) = oneshot::channel();
+        self.send(StatsCommand::GetWorkersStats(tx));
+        rx.await.map_err(|e| e.to_string())
+    }
+
+    pub async fn collect_worker_stats(
+        &self,
+        worker_name: String,
+    ) -> Result<Option<WorkerStats>, String> {
+        let (tx, rx) = oneshot::channel();
+        self.send(StatsCommand::GetWorkerStats(worker_name, tx));
+        rx.await.map_err(|e| e.to_string())
+    }
 }
 
 struct StatsManager {
     stats: HashMap<u32, DownstreamConnectionStats>,
+    workers: WorkersHistory,
+    // Last evaluated health state of each worker
+    health: HashMap<String, HealthState>,
     receiver: mpsc::Receiver<StatsCommand>,
 }
 
@@ -101,15 +156,71 @@ impl StatsManager {
     fn new(receiver: mpsc::Receiver<StatsCommand>) -> Self {
         Self {
             stats: HashMap::new(),
+            workers: WorkersHistory::new(health_thresholds()),
+            health: HashMap::new(),
             receiver,
         }
     }
 
+    // Logs and publishes the workers whose health state changed since the last check
+    fn check_health(&mut self) {
+        // The thresholds can change with a config reload
+        self.workers.set_thresholds(health_thresholds());
+        let now = now();
+        let mut current = HashMap::new();
+        for (worker_name, health) in self.workers.workers_health(now) {
+            let old_state = self
+                .health
+                .get(worker_name)
+                .copied()
+                .unwrap_or(HealthState::Healthy);
+            if health.state != old_state {
+                match health.state {
+                    HealthState::Idle | HealthState::Degraded => warn!(
+                        "Worker {} is {:?}: {}",
+                        worker_name,
+                        health.state,
+                        health.reasons.join(", ")
+                    ),
+                    _ => info!("Worker {} is {:?}", worker_name, health.state),
+                }
+                events::publish(EventKind::WorkerHealthChanged {
+                    worker_name: worker_name.to_string(),
+                    old_state,
+                    new_state: health.state,
+                    reasons: health.reasons.clone(),
+                });
+            }
+            current.insert(worker_name.to_string(), health.state);
+        }
+        self.health = current;
+    }
+
+    fn record_share(&mut self, id: u32, outcome: ShareOutcome) {
+        if let Some(worker_name) = self.stats.get(&id).and_then(|s| s.worker_name.as_deref()) {
+            self.workers.record(worker_name, outcome, now());
+        }
+    }
+
     async fn run(mut self) {
-        while let Some(msg) = self.receiver.recv().await {
+        let mut health_check = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+        loop {
+            let msg = tokio::select! {
+                msg = self.receiver.recv() => match msg {
+                    Some(msg) => msg,
+                    None => return,
+                },
+                _ = health_check.tick() => {
+                    self.workers.prune(now());
+                    self.check_health();
+                    continue;
+                }
+            };
             match msg {
                 StatsCommand::SetupStats(id) => {
-                    self.stats.insert(id, DownstreamConnectionStats::new());
+                    self.stats
+                        .entry(id)
+                        .or_insert_with(DownstreamConnectionStats::new);
                 }
                 StatsCommand::UpdateHashrate(id, hashrate) => {
                     if let Some(stats) = self.stats.get_mut(&id) {
@@ -121,28 +232,79 @@ impl StatsManager {
                         stats.current_difficulty = diff
                     }
                 }
-                StatsCommand::UpdateAcceptedShares(id) => {
+                StatsCommand::UpdateAcceptedShares(id, difficulty) => {
                     if let Some(stats) = self.stats.get_mut(&id) {
                         stats.accepted_shares += 1
                     }
+                    self.record_share(id, ShareOutcome::Accepted(difficulty));
                 }
                 StatsCommand::UpdateRejectedShares(id) => {
                     if let Some(stats) = self.stats.get_mut(&id) {
                         stats.rejected_shares += 1
                     }
+                    self.record_share(id, ShareOutcome::Rejected);
+                }
+                StatsCommand::UpdateStaleShares(id) => {
+                    if let Some(stats) = self.stats.get_mut(&id) {
+                        stats.rejected_shares += 1
+                    }
+                    self.record_share(id, ShareOutcome::Stale);
                 }
                 StatsCommand::UpdateDeviceName(id, name) => {
                     if let Some(stats) = self.stats.get_mut(&id) {
                         stats.device_name = Some(name)
                     }
                 }
+                StatsCommand::UpdateWorkerName(id, name) => {
+                    self.workers.on_connect(&name, now());
+                    // Authorize can be handled before the stats are set up
+                    self.stats
+                        .entry(id)
+                        .or_insert_with(DownstreamConnectionStats::new)
+                        .worker_name = Some(name);
+                }
                 StatsCommand::RemoveStats(id) => {
-                    self.stats.remove(&id);
+                    if let Some(worker_name) = self.stats.remove(&id).and_then(|s| s.worker_name) {
+                        self.workers.on_disconnect(&worker_name, now());
+                    }
+                }
+                StatsCommand::ResetConnections => {
+                    self.stats.clear();
+                    self.workers.disconnect_all(now());
                 }
                 StatsCommand::GetStats(tx) => {
-                    let _ = tx.send(self.stats.clone());
+                    let now = now();
+                    let mut stats = self.stats.clone();
+                    for connection in stats.values_mut() {
+                        connection.health = connection
+                            .worker_name
+                            .as_deref()
+                            .and_then(|name| self.workers.health(name, now));
+                    }
+                    let _ = tx.send(stats);
+                }
+                StatsCommand::GetWorkersStats(tx) => {
+                    let _ = tx.send(self.workers.stats(now()));
+                }
+                StatsCommand::GetWorkerStats(worker_name, tx) => {
+                    let _ = tx.send(self.workers.worker_stats_by_name(&worker_name, now()));
                 }
             }
         }
     }
 }
+
+fn health_thresholds() -> HealthThresholds {
+    HealthThresholds {
+        idle_timeout_secs: Configuration::worker_idle_timeout().as_secs(),
+        expected_hashrate: Configuration::worker_expected_hashrate().map(|h| h as f64),
+        max_reject_rate: Configuration::worker_max_reject_rate(),
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
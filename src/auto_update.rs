@@ -1,9 +1,61 @@
+use lazy_static::lazy_static;
+use roles_logic_sv2::utils::Mutex;
 use self_update::{backends, cargo_crate_version, update::UpdateStatus, TempDir};
 use tracing::{debug, error, info};
 
+use crate::{
+    events::{self, EventKind},
+    outbound,
+};
+
 const REPO_OWNER: &str = "dmnd-pool";
 const REPO_NAME: &str = "dmnd-client";
 const BIN_NAME: &str = "dmnd-client";
+/// Set for the updated binary to the version it replaced
+const UPDATED_FROM_ENV: &str = "DMND_CLIENT_UPDATED_FROM";
+
+lazy_static! {
+    // The update runs before the notifier is started, a failure is notified once it is
+    static ref UPDATE_FAILURE: Mutex<Option<String>> = Mutex::new(None);
+    // Version this process replaced, read from the environment before the runtime is started
+    static ref PREVIOUS_VERSION: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Reads the version this process replaced if it was started by an update, and removes it from
+/// the environment. It must be called before the tokio runtime is started, when no other thread
+/// can read the environment.
+pub fn read_previous_version() {
+    if let Ok(version) = std::env::var(UPDATED_FROM_ENV) {
+        std::env::remove_var(UPDATED_FROM_ENV);
+        if PREVIOUS_VERSION.safe_lock(|v| *v = Some(version)).is_err() {
+            error!("PREVIOUS_VERSION Mutex Corrupted");
+        }
+    }
+}
+
+/// Notifies the update if this process was started by one. It is notified once.
+pub fn publish_update() {
+    if let Ok(Some(from_version)) = PREVIOUS_VERSION.safe_lock(|v| v.take()) {
+        events::publish(EventKind::ProxyUpdated {
+            from_version,
+            to_version: cargo_crate_version!().to_string(),
+        });
+    }
+}
+
+fn report_failure(error: String) {
+    error!("{}", error);
+    if UPDATE_FAILURE.safe_lock(|e| *e = Some(error)).is_err() {
+        error!("UPDATE_FAILURE Mutex Corrupted");
+    }
+}
+
+/// Notifies the failure of the update check, if it failed. It is notified once.
+pub fn publish_failure() {
+    if let Ok(Some(error)) = UPDATE_FAILURE.safe_lock(|e| e.take()) {
+        events::publish(EventKind::ProxyUpdateFailed { error });
+    }
+}
 
 pub fn check_update_proxy() {
     info!("Checking for latest released version...");
@@ -38,12 +90,15 @@ pub fn check_update_proxy() {
     {
         Ok(updater) => updater,
         Err(e) => {
-            error!("Failed to configure update: {}", e);
+            report_failure(format!("Failed to configure update: {}", e));
             return;
         }
     };
 
-    match updater.update_extended() {
+    // self_update builds its own HTTP clients, they go through the outbound proxy with the proxy
+    // environment variables. They are only set meanwhile so that the other HTTP clients and the
+    // restarted process do not inherit them.
+    match outbound::with_proxy_env(|| updater.update_extended()) {
         Ok(status) => match status {
             UpdateStatus::UpToDate => {
                 info!("Package is up to date");
@@ -64,25 +119,25 @@ pub fn check_update_proxy() {
                             reqwest::header::ACCEPT,
                             reqwest::header::HeaderValue::from_static("application/octet-stream"), // to triggers a redirect to the actual binary.
                         );
-                        download
-                            .download_to(&mut file)
+                        outbound::with_proxy_env(|| download.download_to(&mut file))
                             .expect("Failed to download file");
                     }
                 }
                 let bin_name = std::path::PathBuf::from(target_bin);
                 let new_exe = tmp_dir.path().join(&bin_name);
                 if let Err(e) = std::fs::rename(&new_exe, &original_path) {
-                    error!(
+                    report_failure(format!(
                         "Failed to move new binary to {}: {}",
                         original_path.display(),
                         e
-                    );
+                    ));
                     return;
                 }
 
                 let _ = std::fs::remove_dir_all(tmp_dir); // clean up tmp dir
                                                           // Get original cli rgs
                 let args = std::env::args().skip(1).collect::<Vec<_>>();
+                std::env::set_var(UPDATED_FROM_ENV, cargo_crate_version!());
 
                 #[cfg(unix)]
                 {
@@ -93,11 +148,11 @@ pub fn check_update_proxy() {
                         &original_path,
                         std::fs::Permissions::from_mode(0o755),
                     ) {
-                        error!(
+                        report_failure(format!(
                             "Failed to set executable permissions on {}: {}",
                             original_path.display(),
                             e
-                        );
+                        ));
                         return;
                     }
 
@@ -120,7 +175,7 @@ pub fn check_update_proxy() {
             }
         },
         Err(e) => {
-            error!("Failed to update proxy: {}", e);
+            report_failure(format!("Failed to update proxy: {}", e));
         }
     }
 }
@@ -0,0 +1,226 @@
+//! Blocks found by the miners connected to the proxy.
+//!
+//! Every share is checked against the network target when it is validated by the translator, so
+//! blocks are detected both with and without JD. Found blocks are kept in memory for
+//! `/api/blocks` together with the outcome of each path the block was submitted through, and
+//! when `--block-webhook-url` is set they are posted there as JSON.
+
+use std::collections::VecDeque;
+
+use bitcoin::{blockdata::transaction::Transaction, BlockHash};
+use lazy_static::lazy_static;
+use roles_logic_sv2::utils::Mutex;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::Configuration,
+    events::{self, EventKind},
+    jd_client::block_submitter::SubmissionResult,
+};
+
+/// Max number of found blocks kept in memory
+const FOUND_BLOCKS_HISTORY_SIZE: usize = 100;
+/// Time given to the submission paths to report before the webhook is called
+const WEBHOOK_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
+const INITIAL_SUBSIDY: u64 = 50 * 100_000_000;
+const HALVING_INTERVAL: u64 = 210_000;
+
+lazy_static! {
+    static ref FOUND_BLOCKS: Mutex<VecDeque<FoundBlock>> =
+        Mutex::new(VecDeque::with_capacity(FOUND_BLOCKS_HISTORY_SIZE));
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FoundBlock {
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+    pub worker_name: String,
+    pub connection_id: u32,
+    pub block_hash: String,
+    pub height: Option<u64>,
+    /// Total value of the coinbase outputs, in sats
+    pub reward: Option<u64>,
+    pub submissions: Vec<Submission>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Submission {
+    pub path: String,
+    pub result: SubmissionResult,
+}
+
+/// Records a share that meets the network target. `coinbase` is the serialized coinbase of the
+/// share, used to read the height and the reward.
+pub fn record_found(
+    worker_name: String,
+    connection_id: u32,
+    block_hash: BlockHash,
+    coinbase: &[u8],
+) {
+    let block_hash = block_hash.to_string();
+    let coinbase: Option<Transaction> = bitcoin::consensus::deserialize(coinbase).ok();
+    let height = coinbase
+        .as_ref()
+        .and_then(|tx| tx.input.first())
+        .and_then(|input| block_height(input.script_sig.as_bytes()));
+    let reward = coinbase.as_ref().map(|tx| {
+        tx.output
+            .iter()
+            .map(|output| output.value.to_sat())
+            .sum::<u64>()
+    });
+    info!("==================== BLOCK FOUND ====================");
+    info!(
+        "Block {} at height {:?} found by {} (connection {}), reward {:?} sats",
+        block_hash, height, worker_name, connection_id, reward
+    );
+    info!("=====================================================");
+    let block = FoundBlock {
+        timestamp: now(),
+        worker_name,
+        connection_id,
+        block_hash: block_hash.clone(),
+        height,
+        reward,
+        submissions: Vec::new(),
+    };
+    events::publish(EventKind::BlockFound {
+        worker_name: block.worker_name.clone(),
+        block_hash: block.block_hash.clone(),
+        height,
+        reward,
+    });
+    if FOUND_BLOCKS
+        .safe_lock(|blocks| {
+            if blocks.len() == FOUND_BLOCKS_HISTORY_SIZE {
+                blocks.pop_front();
+            }
+            blocks.push_back(block);
+        })
+        .is_err()
+    {
+        error!("Found blocks Mutex Corrupted");
+    }
+    if let Some(url) = Configuration::block_webhook_url() {
+        tokio::spawn(notify_webhook(url, block_hash));
+    }
+}
+
+/// Records the outcome of a submission path for the found block with `block_hash`.
+pub fn record_submission(block_hash: BlockHash, path: &str, result: SubmissionResult) {
+    let block_hash = block_hash.to_string();
+    let recorded = FOUND_BLOCKS
+        .safe_lock(|blocks| {
+            match blocks
+                .iter_mut()
+                .rev()
+                .find(|block| block.block_hash == block_hash)
+            {
+                Some(block) => {
+                    block.submissions.push(Submission {
+                        path: path.to_string(),
+                        result,
+                    });
+                    true
+                }
+                None => false,
+            }
+        })
+        .unwrap_or_else(|_| {
+            error!("Found blocks Mutex Corrupted");
+            false
+        });
+    if !recorded {
+        warn!(
+            "Block submission by {} for {} without a found block",
+            path, block_hash
+        );
+    }
+}
+
+/// Returns the found blocks, newest first.
+pub fn get_found_blocks() -> Result<Vec<FoundBlock>, ()> {
+    FOUND_BLOCKS
+        .safe_lock(|blocks| blocks.iter().rev().cloned().collect())
+        .map_err(|_| error!("Found blocks Mutex Corrupted"))
+}
+
+async fn notify_webhook(url: String, block_hash: String) {
+    tokio::time::sleep(WEBHOOK_DELAY).await;
+    let block = match FOUND_BLOCKS.safe_lock(|blocks| {
+        blocks
+            .iter()
+            .rev()
+            .find(|block| block.block_hash == block_hash)
+            .cloned()
+    }) {
+        Ok(Some(block)) => block,
+        Ok(None) => return,
+        Err(_) => {
+            error!("Found blocks Mutex Corrupted");
+            return;
+        }
+    };
+    let result = crate::outbound::http_client()
+        .post(url)
+        .json(&block)
+        .timeout(std::time::Duration::from_secs(15))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(e) = result {
+        error!("Failed to notify block found webhook: {}", e);
+    }
+}
+
+/// Reads the BIP34 height at the start of the coinbase script.
+pub(crate) fn block_height(coinbase_script: &[u8]) -> Option<u64> {
+    match *coinbase_script.first()? {
+        0x00 => Some(0),
+        // OP_1 to OP_16
+        op @ 0x51..=0x60 => Some((op - 0x50) as u64),
+        len @ 1..=8 => {
+            let bytes = coinbase_script.get(1..1 + len as usize)?;
+            Some(
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |height, byte| (height << 8) | *byte as u64),
+            )
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn block_subsidy(height: u64) -> u64 {
+    let halvings = height / HALVING_INTERVAL;
+    if halvings >= 64 {
+        0
+    } else {
+        INITIAL_SUBSIDY >> halvings
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_height_and_subsidy_from_coinbase() {
+        // Height 840000, first block after the fourth halving
+        let height = block_height(&[0x03, 0x40, 0xd1, 0x0c, 0xff]).unwrap();
+        assert_eq!(height, 840_000);
+        assert_eq!(block_subsidy(height), 312_500_000);
+        assert_eq!(block_height(&[0x51]), Some(1));
+        assert_eq!(block_height(&[0x03, 0x40]), None);
+    }
+}
@@ -0,0 +1,330 @@
+//! Curtailment of the connected miners, for demand response.
+//!
+//! Miners are curtailed during the time windows given with `--curtailment-schedule` (e.g.
+//! `17:00-19:30,22:00-02:00`, in UTC) or on demand with `/api/control/curtailment`. While curtailed
+//! no jobs are sent to the miners and `--curtailment-difficulty` (a very high difficulty by
+//! default) is sent so that they stop submitting shares on the last job. Miners that connect
+//! meanwhile get the difficulty in place of their first job. When the curtailment ends the miners
+//! are resumed progressively over `--curtailment-ramp-up`, so that the power draw does not spike,
+//! and the nominal hashrate sent upstream follows the same ramp.
+
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use roles_logic_sv2::utils::Mutex;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::Configuration,
+    events::{self, EventKind},
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Set by the control API, it takes precedence over the schedule until it expires
+    manual: Option<Manual>,
+    curtailed: bool,
+    /// Unix time in seconds of the last change
+    since: Option<u64>,
+    /// Set while the miners are being resumed
+    ramp_up_started: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Manual {
+    curtailed: bool,
+    /// Unix time in seconds, None until the next control API call
+    until: Option<u64>,
+}
+
+/// Daily window in seconds since midnight UTC, it wraps around midnight when end < start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Window {
+    start: u64,
+    end: u64,
+}
+
+impl Window {
+    pub(crate) fn parse(window: &str) -> Result<Self, String> {
+        let (start, end) = window
+            .split_once('-')
+            .ok_or_else(|| format!("invalid curtailment window {}", window))?;
+        let time = |time: &str| -> Result<u64, String> {
+            let (hours, minutes) = time
+                .trim()
+                .split_once(':')
+                .ok_or_else(|| format!("invalid time {}", time))?;
+            let hours: u64 = hours
+                .parse()
+                .map_err(|_| format!("invalid time {}", time))?;
+            let minutes: u64 = minutes
+                .parse()
+                .map_err(|_| format!("invalid time {}", time))?;
+            if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+                return Err(format!("invalid time {}", time));
+            }
+            Ok(hours * 3600 + minutes * 60)
+        };
+        Ok(Self {
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+
+    /// Seconds until the end of the window if `secs_of_day` is in it
+    fn remaining(&self, secs_of_day: u64) -> Option<u64> {
+        if self.start <= self.end {
+            (self.start..self.end)
+                .contains(&secs_of_day)
+                .then(|| self.end - secs_of_day)
+        } else if secs_of_day >= self.start {
+            Some(SECS_PER_DAY - secs_of_day + self.end)
+        } else if secs_of_day < self.end {
+            Some(self.end - secs_of_day)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CurtailmentStatus {
+    pub curtailed: bool,
+    /// `manual` or `schedule`
+    pub reason: Option<&'static str>,
+    /// Unix time in seconds of the last change
+    pub since: Option<u64>,
+    /// Unix time in seconds when the manual curtailment or resume expires
+    pub manual_until: Option<u64>,
+    /// Fraction of the hashrate the miners are allowed to produce
+    pub hashrate_factor: f32,
+    pub schedule: Vec<String>,
+}
+
+/// Curtails the miners now, for `duration` or until `resume` is called.
+pub fn curtail(duration: Option<Duration>) {
+    let until = duration.map(|duration| now().saturating_add(duration.as_secs()));
+    info!("Curtailment requested until {:?}", until);
+    set_manual(Some(Manual {
+        curtailed: true,
+        until,
+    }));
+}
+
+/// Resumes the miners. If a scheduled window is in progress the miners stay resumed until it
+/// ends.
+pub fn resume() {
+    let manual = scheduled_remaining(now()).map(|remaining| Manual {
+        curtailed: false,
+        until: Some(now() + remaining),
+    });
+    info!(
+        "Resume requested until {:?}",
+        manual.and_then(|manual| manual.until)
+    );
+    set_manual(manual);
+}
+
+fn set_manual(manual: Option<Manual>) {
+    if STATE.safe_lock(|s| s.manual = manual).is_err() {
+        error!("Curtailment Mutex Corrupted");
+    }
+}
+
+pub fn status() -> Result<CurtailmentStatus, ()> {
+    let manual = STATE
+        .safe_lock(|s| s.manual)
+        .map_err(|_| error!("Curtailment Mutex Corrupted"))?;
+    let (curtailed, reason) = desired_state(manual, now());
+    let since = STATE
+        .safe_lock(|s| s.since)
+        .map_err(|_| error!("Curtailment Mutex Corrupted"))?;
+    Ok(CurtailmentStatus {
+        curtailed,
+        reason,
+        since,
+        manual_until: manual.and_then(|manual| manual.until),
+        hashrate_factor: hashrate_factor(),
+        schedule: Configuration::curtailment_schedule(),
+    })
+}
+
+/// Fraction of the channel nominal hashrate the miners produce: 0 while curtailed, growing
+/// linearly to 1 during the ramp up.
+pub fn hashrate_factor() -> f32 {
+    STATE
+        .safe_lock(|s| {
+            if s.curtailed {
+                0.0
+            } else {
+                s.ramp_up_started
+                    .map_or(1.0, |started| ramp_up_progress(started.elapsed()))
+            }
+        })
+        .unwrap_or_else(|_| {
+            error!("Curtailment Mutex Corrupted");
+            1.0
+        })
+}
+
+/// True if the miner with `connection_id` must not get jobs now: the miners are curtailed or it
+/// is not resumed yet by the ramp up.
+pub fn is_paused(connection_id: u32) -> bool {
+    STATE
+        .safe_lock(|s| {
+            let progress = s
+                .ramp_up_started
+                .map(|started| ramp_up_progress(started.elapsed()));
+            is_paused_by(s.curtailed, progress, connection_id)
+        })
+        .unwrap_or_else(|_| {
+            error!("Curtailment Mutex Corrupted");
+            false
+        })
+}
+
+fn is_paused_by(curtailed: bool, ramp_up_progress: Option<f32>, connection_id: u32) -> bool {
+    curtailed
+        || ramp_up_progress.is_some_and(|progress| ramp_up_position(connection_id) >= progress)
+}
+
+fn ramp_up_progress(elapsed: Duration) -> f32 {
+    let ramp_up = Configuration::curtailment_ramp_up();
+    if ramp_up.is_zero() {
+        1.0
+    } else {
+        (elapsed.as_secs_f32() / ramp_up.as_secs_f32()).min(1.0)
+    }
+}
+
+/// Position of the connection in the ramp up, in [0, 1). Connection ids are sequential so they
+/// are spread with a multiplicative hash, the miners are resumed in this order.
+fn ramp_up_position(connection_id: u32) -> f32 {
+    connection_id.wrapping_mul(0x9E37_79B1) as f32 / u32::MAX as f32
+}
+
+// The windows are validated when the configuration is loaded
+fn schedule() -> Vec<Window> {
+    Configuration::curtailment_schedule()
+        .iter()
+        .filter_map(|window| Window::parse(window).ok())
+        .collect()
+}
+
+// Seconds until the end of the scheduled window in progress, if any
+fn scheduled_remaining(now: u64) -> Option<u64> {
+    schedule()
+        .iter()
+        .filter_map(|window| window.remaining(now % SECS_PER_DAY))
+        .max()
+}
+
+fn desired_state(manual: Option<Manual>, now: u64) -> (bool, Option<&'static str>) {
+    match manual {
+        Some(manual) if manual.until.is_none_or(|until| now < until) => {
+            (manual.curtailed, Some("manual"))
+        }
+        _ if scheduled_remaining(now).is_some() => (true, Some("schedule")),
+        _ => (false, None),
+    }
+}
+
+/// Applies the curtailment state to the connected miners. Runs forever.
+pub async fn run() {
+    loop {
+        let now = now();
+        let update = STATE.safe_lock(|s| {
+            if s.manual
+                .is_some_and(|manual| manual.until.is_some_and(|until| now >= until))
+            {
+                s.manual = None;
+            }
+            let (curtailed, reason) = desired_state(s.manual, now);
+            let changed = curtailed != s.curtailed;
+            if changed {
+                s.curtailed = curtailed;
+                s.since = Some(now);
+                s.ramp_up_started = (!curtailed).then(Instant::now);
+            }
+            if s.ramp_up_started
+                .is_some_and(|started| ramp_up_progress(started.elapsed()) >= 1.0)
+            {
+                s.ramp_up_started = None;
+            }
+            (
+                changed.then_some((curtailed, reason)),
+                s.curtailed,
+                s.ramp_up_started,
+            )
+        });
+        let (changed, curtailed, ramp_up_started) = match update {
+            Ok(update) => update,
+            Err(_) => {
+                error!("Curtailment Mutex Corrupted");
+                return;
+            }
+        };
+        if let Some((curtailed, reason)) = changed {
+            if curtailed {
+                warn!("Curtailing miners ({})", reason.unwrap_or("manual"));
+            } else {
+                info!(
+                    "Curtailment ended, resuming miners over {:?}",
+                    Configuration::curtailment_ramp_up()
+                );
+            }
+            events::publish(EventKind::CurtailmentChanged {
+                curtailed,
+                reason: reason.map(str::to_string),
+            });
+        }
+        let progress = ramp_up_started.map(|started| ramp_up_progress(started.elapsed()));
+        let difficulty = Configuration::curtailment_difficulty();
+        for connection_id in crate::translator::downstream_connection_ids() {
+            let paused = is_paused_by(curtailed, progress, connection_id);
+            if let Err(e) =
+                crate::translator::set_downstream_paused(connection_id, paused, Some(difficulty))
+                    .await
+            {
+                error!(
+                    "Failed to apply curtailment to miner {}: {}",
+                    connection_id, e
+                );
+            }
+        }
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_windows_wrapping_midnight() {
+        let window = Window::parse("22:00-02:30").unwrap();
+        assert_eq!(window.remaining(23 * 3600), Some(3 * 3600 + 1800));
+        assert_eq!(window.remaining(3600), Some(5400));
+        assert_eq!(window.remaining(12 * 3600), None);
+        let window = Window::parse("17:00-19:30").unwrap();
+        assert_eq!(window.remaining(19 * 3600), Some(1800));
+        assert_eq!(window.remaining(19 * 3600 + 1800), None);
+        assert!(Window::parse("25:00-02:00").is_err());
+        assert!(Window::parse("17:00").is_err());
+    }
+}
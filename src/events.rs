@@ -0,0 +1,178 @@
+//! Typed events describing what happens in the proxy. Events are broadcasted to every subscriber
+//! (e.g. the `/api/events` stream), if nobody is listening they are dropped.
+
+use std::net::SocketAddr;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{
+    api::history::HealthState, jd_client::block_submitter::SubmissionResult,
+    monitor::shares::RejectionReason,
+};
+
+const EVENTS_BUFFER_SIZE: usize = 1024;
+
+lazy_static! {
+    static ref EVENTS: broadcast::Sender<Event> = broadcast::channel(EVENTS_BUFFER_SIZE).0;
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// Unix time in milliseconds
+    pub timestamp: u128,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    MinerConnected {
+        connection_id: u32,
+        worker_name: String,
+        user_agent: String,
+    },
+    MinerDisconnected {
+        connection_id: u32,
+        worker_name: String,
+    },
+    ShareAccepted {
+        connection_id: u32,
+        worker_name: String,
+        job_id: i64,
+        difficulty: f32,
+    },
+    ShareRejected {
+        connection_id: u32,
+        worker_name: String,
+        job_id: i64,
+        reason: RejectionReason,
+    },
+    DifficultyChanged {
+        connection_id: u32,
+        worker_name: Option<String>,
+        difficulty: f32,
+    },
+    NewJob {
+        job_id: u32,
+        future_job: bool,
+    },
+    NewPrevHash {
+        job_id: u32,
+        prev_hash: String,
+    },
+    PoolSwitched {
+        from: Option<SocketAddr>,
+        to: SocketAddr,
+    },
+    ComponentStateChanged {
+        component: String,
+        old_state: String,
+        new_state: String,
+    },
+    BlockFound {
+        worker_name: String,
+        block_hash: String,
+        height: Option<u64>,
+        reward: Option<u64>,
+    },
+    JobDeclarationRejected {
+        request_id: u32,
+        template_id: Option<u64>,
+        error_code: String,
+        error_details: String,
+        retrying: bool,
+    },
+    BlockSubmitted {
+        template_id: Option<u64>,
+        path: String,
+        result: SubmissionResult,
+    },
+    /// The proxy stopped declaring its own jobs and mines on the pool jobs
+    JdFallback {
+        reason: String,
+    },
+    ProxyUpdated {
+        from_version: String,
+        to_version: String,
+    },
+    ProxyUpdateFailed {
+        error: String,
+    },
+    WorkerHealthChanged {
+        worker_name: String,
+        old_state: HealthState,
+        new_state: HealthState,
+        reasons: Vec<String>,
+    },
+    CurtailmentChanged {
+        curtailed: bool,
+        /// `manual` or `schedule`, None when the curtailment ends
+        reason: Option<String>,
+    },
+    /// The miners are relayed to the SV1 fallback pool because no SV2 pool is reachable, or are
+    /// back on the SV2 pools
+    FallbackPoolChanged {
+        active: bool,
+        pool_url: String,
+    },
+}
+
+impl Event {
+    /// Name of the event type, the same used for the `type` field when serialized.
+    pub fn event_type(&self) -> &'static str {
+        match self.kind {
+            EventKind::MinerConnected { .. } => "miner_connected",
+            EventKind::MinerDisconnected { .. } => "miner_disconnected",
+            EventKind::ShareAccepted { .. } => "share_accepted",
+            EventKind::ShareRejected { .. } => "share_rejected",
+            EventKind::DifficultyChanged { .. } => "difficulty_changed",
+            EventKind::NewJob { .. } => "new_job",
+            EventKind::NewPrevHash { .. } => "new_prev_hash",
+            EventKind::PoolSwitched { .. } => "pool_switched",
+            EventKind::ComponentStateChanged { .. } => "component_state_changed",
+            EventKind::BlockFound { .. } => "block_found",
+            EventKind::JobDeclarationRejected { .. } => "job_declaration_rejected",
+            EventKind::BlockSubmitted { .. } => "block_submitted",
+            EventKind::JdFallback { .. } => "jd_fallback",
+            EventKind::ProxyUpdated { .. } => "proxy_updated",
+            EventKind::ProxyUpdateFailed { .. } => "proxy_update_failed",
+            EventKind::WorkerHealthChanged { .. } => "worker_health_changed",
+            EventKind::CurtailmentChanged { .. } => "curtailment_changed",
+            EventKind::FallbackPoolChanged { .. } => "fallback_pool_changed",
+        }
+    }
+
+    /// Worker the event refers to, if any.
+    pub fn worker_name(&self) -> Option<&str> {
+        match &self.kind {
+            EventKind::MinerConnected { worker_name, .. }
+            | EventKind::MinerDisconnected { worker_name, .. }
+            | EventKind::ShareAccepted { worker_name, .. }
+            | EventKind::ShareRejected { worker_name, .. }
+            | EventKind::BlockFound { worker_name, .. }
+            | EventKind::WorkerHealthChanged { worker_name, .. } => Some(worker_name),
+            EventKind::DifficultyChanged { worker_name, .. } => worker_name.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Publishes an event to all the current subscribers.
+pub fn publish(kind: EventKind) {
+    let event = Event {
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis(),
+        kind,
+    };
+    // An error only means that there are no subscribers
+    let _ = EVENTS.send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    EVENTS.subscribe()
+}
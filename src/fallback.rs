@@ -0,0 +1,261 @@
+//! SV1 fallback pool, used when no SV2 pool is reachable.
+//!
+//! When `--fallback-pool-url` is set and the SV2 pools can not be reached, each miner connection
+//! is relayed as is to the fallback pool, with the `mining.authorize` and `mining.submit` user
+//! replaced by `--fallback-pool-user` when it is set. The SV2 pools are probed every
+//! `PROBE_INTERVAL` and the miners are disconnected as soon as one is reachable, so that they
+//! reconnect to it. Curtailment and the other features of the translator do not apply meanwhile.
+
+use std::{collections::HashSet, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::{error, info, warn};
+
+use crate::{
+    api::{stats::StatsSender, ControlCommand},
+    config::Configuration,
+    events::{self, EventKind},
+    ingress::sv1_ingress,
+    outbound,
+    proxy_state::ProxyState,
+    router::Router,
+    shared::utils::AbortOnDrop,
+    translator::CONNECTION_ID_SPAN,
+};
+
+/// How long the SV2 pools have to accept the connection before the miners are relayed to the
+/// fallback pool
+pub const SV2_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the SV2 pools are probed while on the fallback pool
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+/// Upstream index of the fallback pool connections in the stats, see
+/// `translator::upstream_index`
+pub const FALLBACK_POOL: u32 = 255;
+
+/// `host:port` of the fallback pool URL, with or without the `stratum+tcp://` scheme
+pub(crate) fn pool_address(url: &str) -> Result<String, String> {
+    let address = url
+        .strip_prefix("stratum+tcp://")
+        .unwrap_or(url)
+        .trim_end_matches('/');
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(address.to_string())
+        }
+        _ => Err(format!("invalid fallback pool URL {}", url)),
+    }
+}
+
+/// Relays the miners to the fallback pool until an SV2 pool is reachable or a control command is
+/// received. Returns the pool to connect to, None for the best one.
+pub async fn run(
+    router: &Router,
+    stats_sender: StatsSender,
+    control_receiver: &mut Receiver<ControlCommand>,
+) -> Option<std::net::SocketAddr> {
+    let url = Configuration::fallback_pool_url()?;
+    let address = match pool_address(&url) {
+        Ok(address) => address,
+        Err(e) => {
+            error!("{}", e);
+            return None;
+        }
+    };
+    warn!(
+        "No SV2 pool is reachable, relaying the miners to the fallback pool {}",
+        url
+    );
+    ProxyState::update_fallback(true);
+    events::publish(EventKind::FallbackPoolChanged {
+        active: true,
+        pool_url: url.clone(),
+    });
+
+    let (downs_sv1_tx, mut downs_sv1_rx) = channel(10);
+    let _sv1_ingress = sv1_ingress::start_listen_for_downstream(downs_sv1_tx);
+
+    let mut relays: Vec<AbortOnDrop> = Vec::new();
+    let mut next_connection_id = FALLBACK_POOL * CONNECTION_ID_SPAN;
+    let mut probe = tokio::time::interval(PROBE_INTERVAL);
+    // First tick completes immediately
+    probe.tick().await;
+    let next_pool = loop {
+        tokio::select! {
+            Some((send_to_miner, recv_from_miner, miner)) = downs_sv1_rx.recv() => {
+                info!("Relaying miner {} to the fallback pool", miner);
+                relays.retain(|relay| !relay.is_finished());
+                next_connection_id += 1;
+                relays.push(
+                    tokio::spawn(relay(
+                        address.clone(),
+                        send_to_miner,
+                        recv_from_miner,
+                        next_connection_id,
+                        stats_sender.clone(),
+                    ))
+                    .into(),
+                );
+            }
+            Some(command) = control_receiver.recv() => {
+                info!("Received control command {:?}, trying the SV2 pools again", command);
+                match command {
+                    ControlCommand::SwitchPool(pool) => break pool,
+                    _ => break None,
+                }
+            }
+            _ = probe.tick() => {
+                if let Some(pool) = router.reachable_pool().await {
+                    info!("SV2 pool {} is reachable again, leaving the fallback pool", pool);
+                    break Some(pool);
+                }
+            }
+        }
+    };
+
+    // Dropping the relays disconnects the miners, they reconnect to the SV2 pool
+    drop(relays);
+    ProxyState::update_fallback(false);
+    events::publish(EventKind::FallbackPoolChanged {
+        active: false,
+        pool_url: url,
+    });
+    next_pool
+}
+
+async fn relay(
+    pool_address: String,
+    send_to_miner: Sender<String>,
+    mut recv_from_miner: Receiver<String>,
+    connection_id: u32,
+    stats_sender: StatsSender,
+) {
+    let stream = match outbound::connect(&pool_address).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!(
+                "Failed to connect to the fallback pool {}: {}",
+                pool_address, e
+            );
+            return;
+        }
+    };
+    let codec = LinesCodec::new_with_max_length(crate::MAX_LEN_DOWN_MSG as usize);
+    let (mut send_to_pool, mut recv_from_pool) = Framed::new(stream, codec).split();
+    stats_sender.setup_stats(connection_id);
+    let mut session = Session::default();
+    loop {
+        tokio::select! {
+            message = recv_from_miner.recv() => {
+                let Some(message) = message else { break };
+                let message = session.rewrite_request(message, &stats_sender, connection_id);
+                if send_to_pool.send(message).await.is_err() {
+                    warn!("Fallback pool dropped the connection {}", connection_id);
+                    break;
+                }
+            }
+            message = recv_from_pool.next() => {
+                let Some(Ok(message)) = message else {
+                    warn!("Fallback pool dropped the connection {}", connection_id);
+                    break;
+                };
+                session.on_pool_message(&message, &stats_sender, connection_id);
+                if send_to_miner.send(message).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    stats_sender.remove_stats(connection_id);
+}
+
+/// State of a relayed connection needed to rewrite the user and to count the shares
+#[derive(Debug, Default)]
+struct Session {
+    /// User sent to the fallback pool in place of the one of the miner
+    user: Option<String>,
+    difficulty: f32,
+    /// Ids of the submits waiting for the response of the pool
+    pending_submits: HashSet<String>,
+}
+
+impl Session {
+    fn rewrite_request(&mut self, message: String, stats_sender: &StatsSender, id: u32) -> String {
+        let Ok(mut request) = serde_json::from_str::<Value>(&message) else {
+            return message;
+        };
+        match request.get("method").and_then(Value::as_str) {
+            Some("mining.authorize") => {
+                let worker_name = request["params"][0]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string();
+                stats_sender.update_worker_name(id, worker_name.clone());
+                let Some(user) = Configuration::fallback_pool_user() else {
+                    return message;
+                };
+                let user = fallback_user(&user, &worker_name);
+                request["params"] = json!([
+                    user,
+                    Configuration::fallback_pool_password().unwrap_or_default()
+                ]);
+                self.user = Some(user);
+            }
+            Some("mining.submit") => {
+                self.pending_submits.insert(request["id"].to_string());
+                match &self.user {
+                    Some(user) => request["params"][0] = json!(user),
+                    None => return message,
+                }
+            }
+            _ => return message,
+        }
+        serde_json::to_string(&request).unwrap_or(message)
+    }
+
+    fn on_pool_message(&mut self, message: &str, stats_sender: &StatsSender, id: u32) {
+        let Ok(message) = serde_json::from_str::<Value>(message) else {
+            return;
+        };
+        if message.get("method").and_then(Value::as_str) == Some("mining.set_difficulty") {
+            if let Some(difficulty) = message["params"][0].as_f64() {
+                self.difficulty = difficulty as f32;
+                stats_sender.update_diff(id, self.difficulty);
+            }
+        } else if self.pending_submits.remove(&message["id"].to_string()) {
+            match message["result"].as_bool() {
+                Some(true) => stats_sender.update_accepted_shares(id, self.difficulty),
+                _ => stats_sender.update_rejected_shares(id),
+            }
+        }
+    }
+}
+
+fn fallback_user(user: &str, worker_name: &str) -> String {
+    match worker_name.is_empty() {
+        true => user.to_string(),
+        false => format!("{}.{}", user, worker_name),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_the_pool_url() {
+        assert_eq!(
+            pool_address("stratum+tcp://pool.example.com:3333"),
+            Ok("pool.example.com:3333".to_string())
+        );
+        assert_eq!(
+            pool_address("10.0.0.1:3333/"),
+            Ok("10.0.0.1:3333".to_string())
+        );
+        assert!(pool_address("stratum+tcp://pool.example.com").is_err());
+        assert_eq!(fallback_user("account", ""), "account");
+        assert_eq!(fallback_user("account", "rig1"), "account.rig1");
+    }
+}
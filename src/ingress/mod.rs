@@ -1,3 +1,4 @@
+pub mod proxy_protocol;
 pub mod sv1_ingress;
 //pub mod sv2_up_connection;
 //pub mod task_manager;
@@ -0,0 +1,241 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+/// PROXY protocol v2 signature, every v2 header starts with these 12 bytes.
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+/// A v1 header can not be longer than 107 bytes, CRLF included.
+const V1_MAX_LEN: usize = 107;
+/// Max time we wait for the load balancer to send the header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum ProxyProtocolError {
+    Io(std::io::Error),
+    Timeout,
+    InvalidHeader(String),
+}
+
+impl fmt::Display for ProxyProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyProtocolError::Io(e) => write!(f, "IO error while reading PROXY header: {}", e),
+            ProxyProtocolError::Timeout => write!(f, "Timed out waiting for PROXY header"),
+            ProxyProtocolError::InvalidHeader(e) => write!(f, "Invalid PROXY header: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ProxyProtocolError {
+    fn from(err: std::io::Error) -> Self {
+        ProxyProtocolError::Io(err)
+    }
+}
+
+/// Reads a PROXY protocol header (v1 or v2) from the beginning of `stream` and returns the
+/// address of the real client. Returns `None` when the header does not carry an address
+/// (v1 `UNKNOWN` or v2 `LOCAL`, used by load balancers for health checks): in that case the
+/// caller should fall back to the peer address.
+///
+/// Only the header bytes are consumed, so the stream can be framed right after.
+pub async fn read_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>, ProxyProtocolError> {
+    tokio::time::timeout(HEADER_TIMEOUT, read_header_inner(stream))
+        .await
+        .map_err(|_| ProxyProtocolError::Timeout)?
+}
+
+async fn read_header_inner(
+    stream: &mut TcpStream,
+) -> Result<Option<SocketAddr>, ProxyProtocolError> {
+    // v1 starts with "PROXY", v2 with the binary signature. We never read past the header so
+    // that the first stratum message is left in the socket.
+    let mut first = [0u8; 5];
+    stream.read_exact(&mut first).await?;
+    if &first == b"PROXY" {
+        let mut header = first.to_vec();
+        while !header.ends_with(b"\r\n") {
+            if header.len() >= V1_MAX_LEN {
+                return Err(ProxyProtocolError::InvalidHeader(
+                    "v1 header too long".to_string(),
+                ));
+            }
+            header.push(stream.read_u8().await?);
+        }
+        let header = std::str::from_utf8(&header)
+            .map_err(|_| ProxyProtocolError::InvalidHeader("v1 header not ascii".to_string()))?;
+        parse_v1(header)
+    } else if first == V2_SIGNATURE[..5] {
+        let mut rest = [0u8; 11];
+        stream.read_exact(&mut rest).await?;
+        let mut fixed = [0u8; 16];
+        fixed[..5].copy_from_slice(&first);
+        fixed[5..].copy_from_slice(&rest);
+        let len = u16::from_be_bytes([fixed[14], fixed[15]]) as usize;
+        let mut addresses = vec![0u8; len];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(&fixed, &addresses)
+    } else {
+        Err(ProxyProtocolError::InvalidHeader(
+            "missing PROXY protocol signature".to_string(),
+        ))
+    }
+}
+
+/// Parses a v1 header like `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n`.
+fn parse_v1(header: &str) -> Result<Option<SocketAddr>, ProxyProtocolError> {
+    let invalid = || ProxyProtocolError::InvalidHeader(header.trim_end().to_string());
+    let parts: Vec<&str> = header.trim_end_matches("\r\n").split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src_ip, dst_ip, src_port, dst_port] => {
+            let src_ip: IpAddr = src_ip.parse().map_err(|_| invalid())?;
+            dst_ip.parse::<IpAddr>().map_err(|_| invalid())?;
+            let src_port: u16 = src_port.parse().map_err(|_| invalid())?;
+            dst_port.parse::<u16>().map_err(|_| invalid())?;
+            Ok(Some(SocketAddr::new(src_ip, src_port)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses a v2 header, `fixed` are the first 16 bytes and `addresses` the variable part.
+fn parse_v2(fixed: &[u8; 16], addresses: &[u8]) -> Result<Option<SocketAddr>, ProxyProtocolError> {
+    if fixed[..12] != V2_SIGNATURE {
+        return Err(ProxyProtocolError::InvalidHeader(
+            "invalid v2 signature".to_string(),
+        ));
+    }
+    let version = fixed[12] >> 4;
+    let command = fixed[12] & 0x0F;
+    if version != 2 {
+        return Err(ProxyProtocolError::InvalidHeader(format!(
+            "unsupported version {}",
+            version
+        )));
+    }
+    match command {
+        // LOCAL: connection opened by the proxy itself
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => (),
+        _ => {
+            return Err(ProxyProtocolError::InvalidHeader(format!(
+                "unsupported command {}",
+                command
+            )))
+        }
+    }
+    let family = fixed[13] >> 4;
+    let too_short = || ProxyProtocolError::InvalidHeader("address block too short".to_string());
+    match family {
+        // AF_INET: src_addr(4) dst_addr(4) src_port(2) dst_port(2)
+        0x1 => {
+            if addresses.len() < 12 {
+                return Err(too_short());
+            }
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6: src_addr(16) dst_addr(16) src_port(2) dst_port(2)
+        0x2 => {
+            if addresses.len() < 36 {
+                return Err(too_short());
+            }
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        }
+        // AF_UNSPEC or AF_UNIX, nothing useful to report
+        0x0 | 0x3 => Ok(None),
+        _ => Err(ProxyProtocolError::InvalidHeader(format!(
+            "unsupported address family {}",
+            family
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_v1_headers() {
+        let addr = parse_v1("PROXY TCP4 192.168.0.1 192.168.0.11 56324 3333\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(addr, "192.168.0.1:56324".parse().unwrap());
+        let addr = parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 4000 3333\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(addr, "[2001:db8::1]:4000".parse().unwrap());
+        assert!(parse_v1("PROXY UNKNOWN\r\n").unwrap().is_none());
+        assert!(parse_v1("PROXY TCP4 nope 192.168.0.11 1 2\r\n").is_err());
+    }
+
+    #[test]
+    fn parses_v2_headers() {
+        let mut fixed = [0u8; 16];
+        fixed[..12].copy_from_slice(&V2_SIGNATURE);
+        fixed[12] = 0x21;
+        fixed[13] = 0x11;
+        fixed[14..].copy_from_slice(&12u16.to_be_bytes());
+        let addresses = [10, 0, 0, 7, 10, 0, 0, 1, 0x1F, 0x90, 0x0D, 0x05];
+        let addr = parse_v2(&fixed, &addresses).unwrap().unwrap();
+        assert_eq!(addr, "10.0.0.7:8080".parse().unwrap());
+
+        // Address block of an AF_INET6 header too short
+        fixed[13] = 0x21;
+        let err = parse_v2(&fixed, &addresses).unwrap_err().to_string();
+        assert!(err.contains("too short"), "{}", err);
+
+        // Unknown address family
+        fixed[13] = 0x41;
+        let err = parse_v2(&fixed, &addresses).unwrap_err().to_string();
+        assert!(err.contains("unsupported address family 4"), "{}", err);
+
+        // LOCAL command
+        fixed[12] = 0x20;
+        assert!(parse_v2(&fixed, &addresses).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_header_from_stream_and_leaves_the_payload() {
+        use tokio::io::AsyncWriteExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut header = V2_SIGNATURE.to_vec();
+            header.extend_from_slice(&[0x21, 0x11]);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&[10, 0, 0, 7, 10, 0, 0, 1, 0x1F, 0x90, 0x0D, 0x05]);
+            stream.write_all(&header).await.unwrap();
+            stream
+                .write_all(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 3333\r\n")
+                .await
+                .unwrap();
+            stream.write_all(b"{\"id\":1}\n").await.unwrap();
+        });
+
+        // The v2 header, then a v1 header read from where the first one ended
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let addr = read_header(&mut stream).await.unwrap().unwrap();
+        assert_eq!(addr, "10.0.0.7:8080".parse().unwrap());
+        let addr = read_header(&mut stream).await.unwrap().unwrap();
+        assert_eq!(addr, "192.168.0.1:56324".parse().unwrap());
+        client.await.unwrap();
+
+        let mut payload = String::new();
+        stream.read_to_string(&mut payload).await.unwrap();
+        assert_eq!(payload, "{\"id\":1}\n");
+    }
+}
@@ -5,7 +5,8 @@ use std::{
 
 use crate::{
     config::Configuration,
-    shared::{error::Sv1IngressError, utils::AbortOnDrop},
+    ingress::proxy_protocol,
+    shared::{error::Sv1IngressError, supervisor, utils::AbortOnDrop},
 };
 use futures::{
     stream::{SplitSink, SplitStream},
@@ -19,46 +20,108 @@ use tokio::{
 use tokio_util::codec::{Framed, LinesCodec};
 use tracing::{error, info, warn};
 
+/// Listens on every configured address, each listener is restarted on its own when it exits. An
+/// address that is invalid or can not be bound is skipped, if none can be bound the returned task
+/// finishes at once.
 pub fn start_listen_for_downstream(
     downstreams: Sender<(Sender<String>, Receiver<String>, IpAddr)>,
 ) -> AbortOnDrop {
-    tokio::task::spawn(async move {
-        let down_addr: String = Configuration::downstream_listening_addr()
-            .unwrap_or(crate::DEFAULT_LISTEN_ADDRESS.to_string());
-        let downstream_addr: SocketAddr = down_addr.parse().expect("Invalid listen address");
+    let mut listeners: Option<AbortOnDrop> = None;
+    for down_addr in Configuration::downstream_listening_addrs() {
+        let downstream_addr: SocketAddr = match down_addr.parse() {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("Invalid downstream address {}: {}", down_addr, e);
+                continue;
+            }
+        };
         info!(
             "Trying to bind to address {} for downstream(miner) connections",
             downstream_addr
         );
-        let downstream_listener = TcpListener::bind(downstream_addr)
-            .await
-            .expect("impossible to bind downstream");
+        // Bound once, the supervisor only restarts the accept loop
+        let downstream_listener = match bind(downstream_addr) {
+            Ok(listener) => Arc::new(listener),
+            Err(e) => {
+                error!(
+                    "Impossible to bind downstream address {}: {}",
+                    downstream_addr, e
+                );
+                continue;
+            }
+        };
         info!(
             "Listening for downstream connections on {:?}",
             downstream_addr
         );
-        while let Ok((stream, addr)) = downstream_listener.accept().await {
-            info!("Try to connect {:#?}", addr);
-            Downstream::initialize(
-                stream,
-                crate::MAX_LEN_DOWN_MSG,
-                addr.ip(),
-                downstreams.clone(),
-            );
+        let downstreams = downstreams.clone();
+        let listener = supervisor::supervise("sv1_ingress", move || {
+            listen(downstream_listener.clone(), downstreams.clone())
+        });
+        match listeners.as_mut() {
+            Some(listeners) => listeners.merge(listener),
+            None => listeners = Some(listener),
         }
+    }
+    listeners.unwrap_or_else(|| {
+        error!("No downstream address could be bound");
+        AbortOnDrop::new(tokio::spawn(async {}))
     })
-    .into()
+}
+
+// Same options as `TcpListener::bind`, which can not be used outside of an async context
+fn bind(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        None,
+    )?;
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+async fn listen(
+    downstream_listener: Arc<TcpListener>,
+    downstreams: Sender<(Sender<String>, Receiver<String>, IpAddr)>,
+) {
+    while let Ok((stream, addr)) = downstream_listener.accept().await {
+        info!("Try to connect {:#?}", addr);
+        // IPv4 miners connecting to a dual stack `[::]` listener show up as `::ffff:a.b.c.d`
+        Downstream::initialize(
+            stream,
+            crate::MAX_LEN_DOWN_MSG,
+            addr.ip().to_canonical(),
+            downstreams.clone(),
+        );
+    }
 }
 struct Downstream {}
 
 impl Downstream {
     pub fn initialize(
-        stream: TcpStream,
+        mut stream: TcpStream,
         max_len_for_downstream_messages: u32,
-        address: IpAddr,
+        mut address: IpAddr,
         downstreams: Sender<(Sender<String>, Receiver<String>, IpAddr)>,
     ) {
         tokio::spawn(async move {
+            if Configuration::proxy_protocol() {
+                match proxy_protocol::read_header(&mut stream).await {
+                    Ok(Some(client)) => {
+                        info!("PROXY header from {}: real client is {}", address, client);
+                        address = client.ip();
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        warn!("Dropping connection from {}: {}", address, e);
+                        return;
+                    }
+                }
+            }
             info!("spawning downstream");
             let (send_to_upstream, recv) = channel(10);
             let (send, recv_from_upstream) = channel(10);
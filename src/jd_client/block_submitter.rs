@@ -0,0 +1,345 @@
+//! Redundant submission of the blocks found in JD mode.
+//!
+//! Found blocks are sent to the TP and to the JDS as `SubmitSolution`; when Bitcoin Core RPC
+//! endpoints are configured the full block is also assembled locally and sent to each of them
+//! with `submitblock`, so that a block is not lost if the TP connection is flaky at that moment.
+//! The outcome of every path is logged and published as a `block_submitted` event.
+
+use bitcoin::{
+    block::{Header, Version},
+    blockdata::transaction::Transaction,
+    consensus::encode::serialize_hex,
+    hashes::Hash,
+    Block, BlockHash, CompactTarget, TxMerkleNode,
+};
+use serde::Serialize;
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::{
+    blocks,
+    config::Configuration,
+    events::{self, EventKind},
+};
+
+/// Max time to wait for a `submitblock` response
+const RPC_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Script of the coinbase output that commits to the witnesses of the block (BIP141):
+/// OP_RETURN, push of 36 bytes, then this header
+pub const WITNESS_COMMITMENT_PREFIX: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+/// Outcome of a block submission on one path. The TP and the JDS do not acknowledge solutions,
+/// so for them the block can only be reported as sent.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SubmissionResult {
+    Sent,
+    Accepted,
+    Rejected { reason: String },
+    Failed { error: String },
+}
+
+/// Header fields of the share that met the network target
+#[derive(Debug, Clone, Copy)]
+pub struct SolutionHeader {
+    pub version: u32,
+    pub prev_hash: [u8; 32],
+    pub ntime: u32,
+    pub nbits: u32,
+    pub nonce: u32,
+}
+
+/// Logs the outcome of a submission path and publishes it. `block_hash` is None when the block
+/// could not be assembled, the submission is then not linked to a found block.
+pub fn report(
+    template_id: Option<u64>,
+    block_hash: Option<BlockHash>,
+    path: &str,
+    result: SubmissionResult,
+) {
+    match &result {
+        SubmissionResult::Sent | SubmissionResult::Accepted => {
+            info!(
+                "Block for template {:?} {:?} by {}",
+                template_id, result, path
+            )
+        }
+        _ => error!(
+            "Block for template {:?} {:?} by {}",
+            template_id, result, path
+        ),
+    }
+    match block_hash {
+        Some(block_hash) => blocks::record_submission(block_hash, path, result.clone()),
+        None => warn!(
+            "Block submission by {} for template {:?} not linked to a found block",
+            path, template_id
+        ),
+    }
+    events::publish(EventKind::BlockSubmitted {
+        template_id,
+        path: path.to_string(),
+        result,
+    });
+}
+
+/// Builds the full block from the solution header, the coinbase and the template transactions.
+pub fn assemble_block(
+    header: SolutionHeader,
+    coinbase: &[u8],
+    transactions: Vec<Transaction>,
+) -> Result<Block, String> {
+    let mut coinbase: Transaction = bitcoin::consensus::deserialize(coinbase)
+        .map_err(|e| format!("invalid coinbase: {}", e))?;
+    // The witness commitment in the template coinbase outputs is built with a zero reserved
+    // value, that must be the coinbase witness whenever the coinbase has the commitment, even
+    // when no transaction of the block has a witness
+    let has_commitment = coinbase.output.iter().any(|output| {
+        output
+            .script_pubkey
+            .as_bytes()
+            .starts_with(&WITNESS_COMMITMENT_PREFIX)
+    });
+    if let Some(input) = coinbase.input.first_mut() {
+        if has_commitment && input.witness.is_empty() {
+            input.witness.push([0u8; 32]);
+        }
+    }
+    let mut txdata = vec![coinbase];
+    txdata.extend(transactions);
+    let mut block = Block {
+        header: Header {
+            version: Version::from_consensus(header.version as i32),
+            prev_blockhash: BlockHash::from_byte_array(header.prev_hash),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: header.ntime,
+            bits: CompactTarget::from_consensus(header.nbits),
+            nonce: header.nonce,
+        },
+        txdata,
+    };
+    block.header.merkle_root = block
+        .compute_merkle_root()
+        .ok_or("block without transactions")?;
+    Ok(block)
+}
+
+/// Submits the block to every configured Bitcoin Core RPC endpoint.
+pub async fn submit_to_bitcoind(template_id: Option<u64>, block: &Block) {
+    let urls = Configuration::bitcoind_rpc_urls();
+    if urls.is_empty() {
+        return;
+    }
+    info!(
+        "Submitting block {} to {} bitcoind RPC endpoints",
+        block.block_hash(),
+        urls.len()
+    );
+    let block_hex = serialize_hex(block);
+    let client = reqwest::Client::new();
+    let submissions = urls.iter().map(|url| {
+        let client = &client;
+        let block_hex = &block_hex;
+        async move {
+            let result = submit_block(client, url, block_hex).await;
+            report(
+                template_id,
+                Some(block.block_hash()),
+                &format!("bitcoind {}", redact(url)),
+                result,
+            );
+        }
+    });
+    futures::future::join_all(submissions).await;
+}
+
+async fn submit_block(client: &reqwest::Client, url: &str, block_hex: &str) -> SubmissionResult {
+    let request = client
+        .post(url)
+        .json(&json!({
+            "jsonrpc": "1.0",
+            "id": "dmnd-client",
+            "method": "submitblock",
+            "params": [block_hex],
+        }))
+        .timeout(RPC_TIMEOUT);
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            return SubmissionResult::Failed {
+                error: e.to_string(),
+            }
+        }
+    };
+    let status = response.status();
+    // bitcoind answers RPC errors with a JSON body and an error status
+    let body: serde_json::Value = match response.json().await {
+        Ok(body) => body,
+        Err(_) => {
+            return SubmissionResult::Failed {
+                error: format!("HTTP {}", status),
+            }
+        }
+    };
+    if !body["error"].is_null() {
+        return SubmissionResult::Failed {
+            error: body["error"].to_string(),
+        };
+    }
+    match body["result"].as_str() {
+        None => SubmissionResult::Accepted,
+        // Already submitted through another path
+        Some("duplicate") => SubmissionResult::Accepted,
+        Some(reason) => SubmissionResult::Rejected {
+            reason: reason.to_string(),
+        },
+    }
+}
+
+// Removes the credentials from the url so that it can be logged
+pub fn redact(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut url) => {
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url.to_string()
+        }
+        Err(_) => {
+            warn!("Invalid bitcoind RPC url");
+            "<invalid url>".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{
+        absolute::LockTime, transaction, Amount, OutPoint, ScriptBuf, Sequence, TxIn, TxOut,
+        Witness,
+    };
+
+    fn header() -> SolutionHeader {
+        SolutionHeader {
+            version: 0x2000_0000,
+            prev_hash: [1; 32],
+            ntime: 1_700_000_000,
+            nbits: 0x1703_6e3a,
+            nonce: 42,
+        }
+    }
+
+    // Coinbase as built from the template, serialized without witness, committing to the
+    // witnesses of `transactions`
+    fn coinbase(transactions: &[Transaction]) -> Vec<u8> {
+        let mut coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(vec![0x03, 0x01, 0x02, 0x03]),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(312_500_000),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let mut txdata = vec![coinbase.clone()];
+        txdata.extend_from_slice(transactions);
+        let block = Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce: 0,
+            },
+            txdata,
+        };
+        let witness_root = block.witness_root().unwrap();
+        let commitment = Block::compute_witness_commitment(&witness_root, &[0u8; 32]);
+        let mut script = WITNESS_COMMITMENT_PREFIX.to_vec();
+        script.extend_from_slice(commitment.as_byte_array());
+        coinbase.output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::from_bytes(script),
+        });
+        bitcoin::consensus::serialize(&coinbase)
+    }
+
+    fn segwit_transaction() -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(bitcoin::Txid::from_byte_array([2; 32]), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[vec![3u8; 72], vec![4u8; 33]]),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey: ScriptBuf::from_bytes(
+                    vec![0x00, 0x14].into_iter().chain([5; 20]).collect(),
+                ),
+            }],
+        }
+    }
+
+    #[test]
+    fn assembles_empty_block() {
+        let block = assemble_block(header(), &coinbase(&[]), vec![]).unwrap();
+        assert_eq!(block.txdata.len(), 1);
+        assert_eq!(block.txdata[0].input[0].witness.len(), 1);
+        assert!(block.check_merkle_root());
+        assert!(block.check_witness_commitment());
+        assert_eq!(
+            block.header.prev_blockhash,
+            BlockHash::from_byte_array([1; 32])
+        );
+    }
+
+    #[test]
+    fn assembles_block_with_segwit_transaction() {
+        let transactions = vec![segwit_transaction()];
+        let block = assemble_block(header(), &coinbase(&transactions), transactions).unwrap();
+        assert_eq!(block.txdata.len(), 2);
+        assert!(block.check_merkle_root());
+        assert!(block.check_witness_commitment());
+    }
+
+    #[tokio::test]
+    async fn submits_block_to_rpc_endpoint() {
+        // Local stand-in for the bitcoind RPC
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::post(|body: axum::Json<serde_json::Value>| async move {
+                assert_eq!(body["method"], "submitblock");
+                let result = if body["params"][0] == "00" {
+                    serde_json::Value::Null
+                } else {
+                    json!("high-hash")
+                };
+                axum::Json(json!({ "result": result, "error": null, "id": body["id"] }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://user:password@{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        assert_eq!(
+            submit_block(&client, &url, "00").await,
+            SubmissionResult::Accepted
+        );
+        assert_eq!(
+            submit_block(&client, &url, "01").await,
+            SubmissionResult::Rejected {
+                reason: "high-hash".to_string()
+            }
+        );
+    }
+}
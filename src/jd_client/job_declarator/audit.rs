@@ -0,0 +1,301 @@
+//! Audit log of the jobs declared to the JDS.
+//!
+//! Every `DeclareMiningJob` sent is recorded together with the JDS response and the job id that
+//! the pool assigns with `SetCustomMiningJobSuccess`. The last jobs are kept in memory for
+//! `/api/jd/jobs`; when `--jd-audit-file` is set each job is also appended as a JSON line once
+//! its outcome is known, or when it is dropped from memory without one. The jobs still in memory
+//! are appended with `flush` when the proxy stops.
+
+use std::{
+    collections::VecDeque,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use bitcoin::{blockdata::transaction::Transaction, hex::DisplayHex};
+use lazy_static::lazy_static;
+use roles_logic_sv2::{template_distribution_sv2::NewTemplate, utils::Mutex};
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::{
+    blocks::{block_height, block_subsidy},
+    config::Configuration,
+};
+
+/// Max number of declared jobs kept in memory
+const DECLARED_JOBS_HISTORY_SIZE: usize = 200;
+/// Size after which the audit file is rotated
+const MAX_AUDIT_FILE_SIZE: u64 = 50 * 1024 * 1024;
+/// Number of rotated audit files kept, as `<file>.1` (newest) to `<file>.N`
+const ROTATED_AUDIT_FILES: usize = 5;
+/// Max time `flush` waits for the jobs to be written
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref DECLARED_JOBS: Mutex<VecDeque<DeclaredJob>> =
+        Mutex::new(VecDeque::with_capacity(DECLARED_JOBS_HISTORY_SIZE));
+    // The audit file is written by a dedicated thread so that no IO is done on the runtime
+    static ref AUDIT_WRITER: mpsc::Sender<AuditWrite> = spawn_audit_writer();
+}
+
+enum AuditWrite {
+    Job(PathBuf, String),
+    // Answered once the writes sent before are done
+    Flush(mpsc::Sender<()>),
+}
+
+/// Response of the JDS to a `DeclareMiningJob`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DeclareOutcome {
+    Pending,
+    Success,
+    Error {
+        error_code: String,
+        error_details: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeclaredJob {
+    pub request_id: u32,
+    pub template_id: u64,
+    pub future_template: bool,
+    /// Set when the job is sent to the pool with `SetCustomMiningJob`
+    pub prev_hash: Option<String>,
+    pub tx_count: usize,
+    /// Coinbase value minus the block subsidy, if the height can be read from the coinbase
+    pub total_fees: Option<u64>,
+    pub coinbase_value: u64,
+    pub txids: Vec<String>,
+    pub outcome: DeclareOutcome,
+    /// Job id assigned by the pool with `SetCustomMiningJobSuccess`
+    pub pool_job_id: Option<u32>,
+    /// Unix time in milliseconds
+    pub declared_at: u64,
+    pub outcome_at: Option<u64>,
+    pub custom_job_set_at: Option<u64>,
+    #[serde(skip)]
+    persisted: bool,
+}
+
+impl DeclaredJob {
+    fn is_final(&self) -> bool {
+        matches!(self.outcome, DeclareOutcome::Error { .. }) || self.pool_job_id.is_some()
+    }
+}
+
+/// Records a `DeclareMiningJob` that is about to be sent.
+pub fn record_declared(request_id: u32, template: &NewTemplate, transactions: &[Transaction]) {
+    let coinbase_value = template.coinbase_tx_value_remaining;
+    let total_fees = block_height(&template.coinbase_prefix.to_vec())
+        .map(|height| coinbase_value.saturating_sub(block_subsidy(height)));
+    let job = DeclaredJob {
+        request_id,
+        template_id: template.template_id,
+        future_template: template.future_template,
+        prev_hash: None,
+        tx_count: transactions.len(),
+        total_fees,
+        coinbase_value,
+        txids: transactions
+            .iter()
+            .map(|tx| tx.compute_txid().to_string())
+            .collect(),
+        outcome: DeclareOutcome::Pending,
+        pool_job_id: None,
+        declared_at: now(),
+        outcome_at: None,
+        custom_job_set_at: None,
+        persisted: false,
+    };
+    let evicted = DECLARED_JOBS
+        .safe_lock(|jobs| {
+            let evicted = if jobs.len() == DECLARED_JOBS_HISTORY_SIZE {
+                jobs.pop_front()
+            } else {
+                None
+            };
+            jobs.push_back(job);
+            evicted
+        })
+        .unwrap_or_else(|_| {
+            error!("Declared jobs Mutex Corrupted");
+            None
+        });
+    if let Some(job) = evicted.filter(|job| !job.persisted) {
+        persist(&job);
+    }
+}
+
+/// Records the JDS response to the declared job with `request_id`.
+pub fn record_outcome(request_id: u32, outcome: DeclareOutcome) {
+    update(
+        |job| job.request_id == request_id,
+        |job| {
+            job.outcome = outcome;
+            job.outcome_at = Some(now());
+        },
+    );
+}
+
+/// Records the prev hash the job of `template_id` is sent to the pool with.
+pub fn record_custom_job_set(template_id: u64, prev_hash: &[u8]) {
+    let mut prev_hash = prev_hash.to_vec();
+    prev_hash.reverse();
+    let prev_hash = prev_hash.as_hex().to_string();
+    update(
+        |job| job.template_id == template_id,
+        |job| {
+            job.prev_hash = Some(prev_hash);
+            job.custom_job_set_at = Some(now());
+        },
+    );
+}
+
+/// Records the job id assigned by the pool to the job of `template_id`.
+pub fn record_pool_job_id(template_id: u64, job_id: u32) {
+    update(
+        |job| job.template_id == template_id,
+        |job| job.pool_job_id = Some(job_id),
+    );
+}
+
+/// Returns the last `limit` declared jobs, newest first.
+pub fn get_declared_jobs(limit: usize) -> Result<Vec<DeclaredJob>, ()> {
+    DECLARED_JOBS
+        .safe_lock(|jobs| jobs.iter().rev().take(limit).cloned().collect())
+        .map_err(|_| error!("Declared jobs Mutex Corrupted"))
+}
+
+/// Appends the jobs that are not in the audit file yet, e.g. the ones without a pool job id, and
+/// waits for them to be written. Called when the proxy stops.
+pub fn flush() {
+    if Configuration::jd_audit_file().is_none() {
+        return;
+    }
+    let pending: Vec<DeclaredJob> = DECLARED_JOBS
+        .safe_lock(|jobs| {
+            jobs.iter_mut()
+                .filter(|job| !job.persisted)
+                .map(|job| {
+                    job.persisted = true;
+                    job.clone()
+                })
+                .collect()
+        })
+        .unwrap_or_else(|_| {
+            error!("Declared jobs Mutex Corrupted");
+            Vec::new()
+        });
+    for job in &pending {
+        persist(job);
+    }
+    let (done, written) = mpsc::channel();
+    if AUDIT_WRITER.send(AuditWrite::Flush(done)).is_err()
+        || written.recv_timeout(FLUSH_TIMEOUT).is_err()
+    {
+        warn!("Declared jobs may not have been written to the audit file");
+    }
+}
+
+// Updates the newest job matching `find` and persists it if it became final
+fn update(find: impl Fn(&DeclaredJob) -> bool, f: impl FnOnce(&mut DeclaredJob)) {
+    let to_persist = DECLARED_JOBS
+        .safe_lock(|jobs| {
+            let job = jobs.iter_mut().rev().find(|job| find(job))?;
+            f(job);
+            if job.is_final() && !job.persisted {
+                job.persisted = true;
+                Some(job.clone())
+            } else {
+                None
+            }
+        })
+        .unwrap_or_else(|_| {
+            error!("Declared jobs Mutex Corrupted");
+            None
+        });
+    if let Some(job) = to_persist {
+        persist(&job);
+    }
+}
+
+// Failures are only logged so that a read only disk does not take down the proxy
+fn persist(job: &DeclaredJob) {
+    let Some(path) = Configuration::jd_audit_file() else {
+        return;
+    };
+    let line = match serde_json::to_string(job) {
+        Ok(line) => line,
+        Err(e) => {
+            warn!("Failed to serialize declared job: {}", e);
+            return;
+        }
+    };
+    if AUDIT_WRITER.send(AuditWrite::Job(path, line)).is_err() {
+        warn!("Audit writer is gone, declared job not written");
+    }
+}
+
+fn spawn_audit_writer() -> mpsc::Sender<AuditWrite> {
+    let (sender, receiver) = mpsc::channel();
+    let spawned = std::thread::Builder::new()
+        .name("jd-audit".to_string())
+        .spawn(move || {
+            for write in receiver {
+                match write {
+                    AuditWrite::Job(path, line) => append(&path, &line),
+                    AuditWrite::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        warn!("Failed to start the audit writer: {}", e);
+    }
+    sender
+}
+
+fn append(path: &Path, line: &str) {
+    rotate_if_needed(path);
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(e) = result {
+        warn!("Failed to write declared job to {}: {}", path.display(), e);
+    }
+}
+
+fn rotate_if_needed(path: &Path) {
+    let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    if size < MAX_AUDIT_FILE_SIZE {
+        return;
+    }
+    let rotated = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    };
+    // The oldest file is overwritten by the rename
+    for n in (1..ROTATED_AUDIT_FILES).rev() {
+        let _ = std::fs::rename(rotated(n), rotated(n + 1));
+    }
+    if let Err(e) = std::fs::rename(path, rotated(1)) {
+        warn!("Failed to rotate {}: {}", path.display(), e);
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
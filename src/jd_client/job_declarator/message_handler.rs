@@ -1,4 +1,8 @@
-use super::JobDeclarator;
+use super::{
+    audit::{self, DeclareOutcome},
+    JobDeclarator,
+};
+use bitcoin::hex::DisplayHex;
 use roles_logic_sv2::{
     handlers::{job_declaration::ParseServerJobDeclarationMessages, SendTo_},
     job_declaration_sv2::{
@@ -24,17 +28,26 @@ impl ParseServerJobDeclarationMessages for JobDeclarator {
         &mut self,
         message: DeclareMiningJobSuccess,
     ) -> Result<SendTo, Error> {
+        audit::record_outcome(message.request_id, DeclareOutcome::Success);
+        self.declare_errors = 0;
         let message = JobDeclaration::DeclareMiningJobSuccess(message.into_static());
         Ok(SendTo::None(Some(message)))
     }
 
     fn handle_declare_mining_job_error(
         &mut self,
-        _message: DeclareMiningJobError,
+        message: DeclareMiningJobError,
     ) -> Result<SendTo, Error> {
-        // TODO consider using declarative names instead of setting states
-        super::super::IS_CUSTOM_JOB_SET.store(true, std::sync::atomic::Ordering::Release);
-        Ok(SendTo::None(None))
+        audit::record_outcome(
+            message.request_id,
+            DeclareOutcome::Error {
+                error_code: String::from_utf8_lossy(&message.error_code.to_vec()).to_string(),
+                error_details: message.error_details.to_vec().as_hex().to_string(),
+            },
+        );
+        // Retried or reported by JobDeclarator::on_declare_mining_job_error
+        let message = JobDeclaration::DeclareMiningJobError(message.into_static());
+        Ok(SendTo::None(Some(message)))
     }
 
     fn handle_provide_missing_transactions(
@@ -1,18 +1,23 @@
+pub mod audit;
 pub mod message_handler;
 mod task_manager;
+pub mod tx_policy;
 use binary_sv2::{Seq0255, Seq064K, B016M, B064K, U256};
-use bitcoin::{blockdata::transaction::Transaction, hashes::Hash};
+use bitcoin::{blockdata::transaction::Transaction, hashes::Hash, hex::DisplayHex, Block};
 use codec_sv2::{HandshakeRole, Initiator, StandardEitherFrame, StandardSv2Frame};
 use demand_sv2_connection::noise_connection_tokio::Connection;
 use roles_logic_sv2::{
     handlers::SendTo_,
-    job_declaration_sv2::{AllocateMiningJobTokenSuccess, SubmitSolutionJd},
+    job_declaration_sv2::{AllocateMiningJobTokenSuccess, DeclareMiningJobError, SubmitSolutionJd},
     mining_sv2::SubmitSharesExtended,
     parsers::{JobDeclaration, PoolMessages},
     template_distribution_sv2::SetNewPrevHash,
     utils::Mutex,
 };
-use std::{collections::HashMap, convert::TryInto};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+};
 use task_manager::TaskManager;
 use tokio::sync::mpsc::{Receiver as TReceiver, Sender as TSender};
 use tracing::{error, info};
@@ -35,11 +40,36 @@ pub mod setup_connection;
 use setup_connection::SetupConnectionHandler;
 
 use crate::{
+    events::{self, EventKind},
     proxy_state::{JdState, PoolState, ProxyState},
     shared::utils::AbortOnDrop,
 };
 
-use super::{error::Error, mining_upstream::Upstream};
+use super::{
+    block_submitter::{self, SolutionHeader},
+    error::Error,
+    mining_upstream::Upstream,
+};
+
+/// Prefix of the error codes of the declarations rejected for their content, declaring them again
+/// would get the same error
+const INVALID_JOB_ERROR_PREFIX: &str = "invalid-job-param-value";
+/// Max times the same job is declared again after a retryable error
+const MAX_DECLARE_RETRIES: u8 = 2;
+/// Rejected declarations in a row after which the proxy falls back to the pool jobs
+const MAX_CONSECUTIVE_DECLARE_ERRORS: u32 = 3;
+/// Number of templates whose transactions are kept to assemble found blocks
+const TEMPLATE_TRANSACTIONS_HISTORY: usize = 8;
+
+/// Transactions and prev hash of a declared template, kept to assemble found blocks
+#[derive(Debug)]
+struct DeclaredTemplate {
+    template_id: u64,
+    // Known when the template is declared for a non future template, or when its SetNewPrevHash
+    // arrives for a future one
+    prev_hash: Option<SetNewPrevHash<'static>>,
+    transactions: Vec<Transaction>,
+}
 
 #[derive(Debug, Clone)]
 pub struct LastDeclareJob {
@@ -47,6 +77,7 @@ pub struct LastDeclareJob {
     template: NewTemplate<'static>,
     coinbase_pool_output: Vec<u8>,
     tx_list: Seq064K<'static, B016M<'static>>,
+    retries: u8,
 }
 
 #[derive(Debug)]
@@ -75,6 +106,10 @@ pub struct JobDeclarator {
     pub coinbase_tx_prefix: B064K<'static>,
     pub coinbase_tx_suffix: B064K<'static>,
     pub task_manager: Arc<Mutex<TaskManager>>,
+    // Declarations rejected in a row, reset by a successful one
+    declare_errors: u32,
+    // Last declared templates
+    declared_templates: VecDeque<DeclaredTemplate>,
 }
 
 impl JobDeclarator {
@@ -84,7 +119,7 @@ impl JobDeclarator {
         up: Arc<Mutex<Upstream>>,
         should_log_when_connected: bool,
     ) -> Result<(Arc<Mutex<Self>>, AbortOnDrop), Error> {
-        let stream = tokio::net::TcpStream::connect(address).await?;
+        let stream = crate::outbound::connect(address).await?;
         let initiator = Initiator::from_raw_k(authority_public_key)?;
         let (mut receiver, mut sender, _, _) =
             Connection::new(stream, HandshakeRole::Initiator(initiator))
@@ -117,6 +152,8 @@ impl JobDeclarator {
             coinbase_tx_suffix: vec![].try_into().expect("Internal error: this operation can not fail because Vec can always be converted into Inner"),
             set_new_prev_hash_counter: 0,
             task_manager,
+            declare_errors: 0,
+            declared_templates: VecDeque::with_capacity(TEMPLATE_TRANSACTIONS_HISTORY),
         }));
 
         Self::allocate_tokens(&self_, 2).await;
@@ -231,7 +268,13 @@ impl JobDeclarator {
                     "Failed to set custom job after 2 minutes for new template with id {}",
                     template.template_id
                 );
-                ProxyState::update_jd_state(JdState::Down);
+                ProxyState::update_jd_state(
+                    JdState::Down,
+                    &format!(
+                        "Failed to set custom job after 2 minutes for new template with id {}",
+                        template.template_id
+                    ),
+                );
                 return Err(Error::Unrecoverable);
             }
             tokio::task::yield_now().await;
@@ -278,11 +321,29 @@ impl JobDeclarator {
             tx_list: tx_ids,
             excess_data, // request transaction data
         };
+        audit::record_declared(id, &template, &tx_list);
+        self_mutex
+            .safe_lock(|s| {
+                if s.declared_templates.len() == TEMPLATE_TRANSACTIONS_HISTORY {
+                    s.declared_templates.pop_front();
+                }
+                // The SetNewPrevHash of a future template can arrive before it is declared
+                let prev_hash = s.last_set_new_prev_hash.clone().filter(|prev_hash| {
+                    !template.future_template || prev_hash.template_id == template.template_id
+                });
+                s.declared_templates.push_back(DeclaredTemplate {
+                    template_id: template.template_id,
+                    prev_hash,
+                    transactions: tx_list,
+                });
+            })
+            .map_err(|_| Error::JobDeclaratorMutexCorrupted)?;
         let last_declare = LastDeclareJob {
             declare_job: declare_job.clone(),
             template,
             coinbase_pool_output,
             tx_list: tx_list_.clone(),
+            retries: 0,
         };
         Self::update_last_declare_job_sent(self_mutex, id, last_declare)?;
         let frame: StdFrame =
@@ -314,7 +375,10 @@ impl JobDeclarator {
                     }),
                     None => {
                         error!("Failed to receive msg from Pool");
-                        ProxyState::update_pool_state(PoolState::Down);
+                        ProxyState::update_pool_state(
+                            PoolState::Down,
+                            "Failed to receive msg from Pool",
+                        );
                         break;
                     }
                 };
@@ -340,7 +404,7 @@ impl JobDeclarator {
                                 Ok(last_declare) => last_declare,
                                 Err(e) => {
                                     error!("{e}");
-                                    ProxyState::update_jd_state(JdState::Down);
+                                    ProxyState::update_jd_state(JdState::Down, &e.to_string());
                                     break;
                                 }
                             };
@@ -367,7 +431,7 @@ impl JobDeclarator {
                                 );
                             }) {
                                 error!("{e}");
-                                ProxyState::update_jd_state(JdState::Down);
+                                ProxyState::update_jd_state(JdState::Down, &e.to_string());
                                 break;
                             };
                         } else {
@@ -376,7 +440,7 @@ impl JobDeclarator {
                                     Ok(set_new_prev_hash) => set_new_prev_hash,
                                     Err(e) => {
                                         error!("{e}");
-                                        ProxyState::update_jd_state(JdState::Down);
+                                        ProxyState::update_jd_state(JdState::Down, &e.to_string());
                                         break;
                                     }
                                 };
@@ -397,13 +461,17 @@ impl JobDeclarator {
                                     pool_outs,
                                     template.coinbase_tx_locktime,
                                     template.template_id
-                                    ).await {error!("Failed to set custom jobd: {e}"); ProxyState::update_jd_state(JdState::Down);break;},
+                                    ).await {error!("Failed to set custom jobd: {e}"); ProxyState::update_jd_state(JdState::Down, &format!("Failed to set custom jobd: {e}"));break;},
                                 None => panic!("Invalid state we received a NewTemplate not future, without having received a set new prev hash")
                             }
                         }
                     }
                     Ok(SendTo::None(Some(JobDeclaration::DeclareMiningJobError(m)))) => {
-                        error!("Job is not verified: {:?}", m);
+                        if let Err(e) = Self::on_declare_mining_job_error(&self_mutex, m).await {
+                            error!("{e}");
+                            ProxyState::update_jd_state(JdState::Down, &e.to_string());
+                            break;
+                        }
                     }
                     Ok(SendTo::None(None)) => (),
                     Ok(SendTo::Respond(m)) => {
@@ -414,20 +482,23 @@ impl JobDeclarator {
                             Ok(sender) => sender,
                             Err(e) => {
                                 error!("{e}");
-                                ProxyState::update_jd_state(JdState::Down);
+                                ProxyState::update_jd_state(JdState::Down, &e.to_string());
                                 break;
                             }
                         };
                         if sender.send(sv2_frame.into()).await.is_err() {
                             error!("Job declarator failed to send message");
-                            ProxyState::update_jd_state(JdState::Down);
+                            ProxyState::update_jd_state(
+                                JdState::Down,
+                                "Job declarator failed to send message",
+                            );
                             break;
                         };
                     }
                     Ok(_) => unreachable!(),
                     Err(e) => {
                         error!("{e}");
-                        ProxyState::update_jd_state(JdState::Down);
+                        ProxyState::update_jd_state(JdState::Down, &e.to_string());
                         break;
                     }
                 }
@@ -438,6 +509,90 @@ impl JobDeclarator {
             .map_err(|_| Error::JobDeclaratorTaskManagerFailed)
     }
 
+    /// Declares the rejected job again with a fresh token when the error is retryable, otherwise
+    /// lets the next template be declared. After too many rejections in a row the proxy falls
+    /// back to the pool jobs.
+    async fn on_declare_mining_job_error(
+        self_mutex: &Arc<Mutex<Self>>,
+        m: DeclareMiningJobError<'static>,
+    ) -> Result<(), Error> {
+        let error_code = String::from_utf8_lossy(&m.error_code.to_vec()).to_string();
+        let error_details = m.error_details.to_vec().as_hex().to_string();
+        let last_declare = self_mutex
+            .safe_lock(|s| s.last_declare_mining_jobs_sent.remove(&m.request_id))
+            .map_err(|_| Error::JobDeclaratorMutexCorrupted)?
+            .flatten();
+        let template_id = last_declare.as_ref().map(|j| j.template.template_id);
+        let to_retry =
+            last_declare.filter(|j| is_retryable(&error_code) && j.retries < MAX_DECLARE_RETRIES);
+        error!(
+            "Job declaration {} for template {:?} rejected: {} {}",
+            m.request_id, template_id, error_code, error_details
+        );
+        events::publish(EventKind::JobDeclarationRejected {
+            request_id: m.request_id,
+            template_id,
+            error_code,
+            error_details,
+            retrying: to_retry.is_some(),
+        });
+        if let Some(last_declare) = to_retry {
+            return Self::declare_again(self_mutex, last_declare).await;
+        }
+
+        super::IS_CUSTOM_JOB_SET.store(true, std::sync::atomic::Ordering::Release);
+        let declare_errors = self_mutex
+            .safe_lock(|s| {
+                s.declare_errors += 1;
+                s.declare_errors
+            })
+            .map_err(|_| Error::JobDeclaratorMutexCorrupted)?;
+        if declare_errors >= MAX_CONSECUTIVE_DECLARE_ERRORS {
+            error!(
+                "{} job declarations rejected in a row, falling back to pool jobs",
+                declare_errors
+            );
+            super::fall_back_to_pool_jobs();
+        }
+        Ok(())
+    }
+
+    // Sends a rejected job with a new token and request id. The coinbase is kept, the pool
+    // coinbase output does not change between tokens.
+    async fn declare_again(
+        self_mutex: &Arc<Mutex<Self>>,
+        mut last_declare: LastDeclareJob,
+    ) -> Result<(), Error> {
+        let token = Self::get_last_token(self_mutex).await?;
+        let (id, sender) = self_mutex
+            .safe_lock(|s| (s.req_ids.next(), s.sender.clone()))
+            .map_err(|_| Error::JobDeclaratorMutexCorrupted)?;
+        info!(
+            "Declaring again template {} with a new token",
+            last_declare.template.template_id
+        );
+        last_declare.declare_job.request_id = id;
+        last_declare.declare_job.mining_job_token = token.mining_job_token;
+        last_declare.retries += 1;
+        let tx_list: Vec<Transaction> = last_declare
+            .tx_list
+            .to_vec()
+            .iter()
+            .filter_map(|tx| bitcoin::consensus::deserialize(tx).ok())
+            .collect();
+        audit::record_declared(id, &last_declare.template, &tx_list);
+        let declare_job = last_declare.declare_job.clone();
+        Self::update_last_declare_job_sent(self_mutex, id, last_declare)?;
+        let frame: StdFrame =
+            PoolMessages::JobDeclaration(JobDeclaration::DeclareMiningJob(declare_job))
+                .try_into()
+                .expect("Infallable operation");
+        sender
+            .send(frame.into())
+            .await
+            .map_err(|_| Error::Unrecoverable)
+    }
+
     pub async fn on_set_new_prev_hash(
         self_mutex: Arc<Mutex<Self>>,
         set_new_prev_hash: SetNewPrevHash<'static>,
@@ -451,6 +606,13 @@ impl JobDeclarator {
                 .safe_lock(|s| {
                     s.last_set_new_prev_hash = Some(set_new_prev_hash.clone());
                     s.set_new_prev_hash_counter += 1;
+                    if let Some(template) = s
+                        .declared_templates
+                        .iter_mut()
+                        .find(|template| template.template_id == id)
+                    {
+                        template.prev_hash = Some(set_new_prev_hash.clone());
+                    }
                 })
                 .is_err()
             {
@@ -530,7 +692,7 @@ impl JobDeclarator {
                 Err(e) => {
                     error!("{e}");
                     //Poison lock
-                    ProxyState::update_jd_state(JdState::Down);
+                    ProxyState::update_jd_state(JdState::Down, &e.to_string());
                     return;
                 }
             };
@@ -542,7 +704,7 @@ impl JobDeclarator {
 
             if sender.send(frame.into()).await.is_err() {
                 error!("Job declarator failed to send message");
-                ProxyState::update_jd_state(JdState::Down);
+                ProxyState::update_jd_state(JdState::Down, "Job declarator failed to send message");
             }
         }
     }
@@ -580,4 +742,70 @@ impl JobDeclarator {
             Error::Unrecoverable
         })
     }
+
+    /// Assembles the block found on `template_id` from the share and the coinbase. Returns None
+    /// when the prev hash or the transactions of the template are not known anymore.
+    pub fn assemble_block(
+        self_mutex: &Arc<Mutex<Self>>,
+        template_id: u64,
+        share: &SubmitSharesExtended<'static>,
+        coinbase: &[u8],
+    ) -> Result<Option<Block>, Error> {
+        let template = self_mutex
+            .safe_lock(|s| {
+                s.declared_templates
+                    .iter()
+                    .find(|template| template.template_id == template_id)
+                    .and_then(|template| {
+                        Some((template.prev_hash.clone()?, template.transactions.clone()))
+                    })
+            })
+            .map_err(|_| Error::JobDeclaratorMutexCorrupted)?;
+        let Some((prev_hash, transactions)) = template else {
+            error!(
+                "Prev hash or transactions of template {} not available, block not assembled",
+                template_id
+            );
+            return Ok(None);
+        };
+        let header = SolutionHeader {
+            version: share.version,
+            prev_hash: prev_hash
+                .prev_hash
+                .to_vec()
+                .try_into()
+                .map_err(|_| Error::Unrecoverable)?,
+            ntime: share.ntime,
+            nbits: prev_hash.n_bits,
+            nonce: share.nonce,
+        };
+        match block_submitter::assemble_block(header, coinbase, transactions) {
+            Ok(block) => Ok(Some(block)),
+            Err(e) => {
+                error!(
+                    "Failed to assemble block for template {}: {}",
+                    template_id, e
+                );
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Rejections not caused by the content of the job, such as an expired token or an error of the
+/// JDS, are worth declaring again with a fresh token.
+fn is_retryable(error_code: &str) -> bool {
+    !error_code.starts_with(INVALID_JOB_ERROR_PREFIX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retries_only_errors_not_caused_by_the_job() {
+        assert!(is_retryable("invalid-mining-job-token"));
+        assert!(is_retryable("internal-error"));
+        assert!(!is_retryable("invalid-job-param-value-coinbase_tx_prefix"));
+    }
 }
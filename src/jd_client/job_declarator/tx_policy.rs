@@ -0,0 +1,645 @@
+//! Local policy on the transactions of the declared jobs.
+//!
+//! The policy is read from the TOML file given with `--tx-policy-file` and it is reloaded when
+//! the file changes, e.g.:
+//!
+//! ```toml
+//! denied_txids = ["<txid>"]
+//! denied_scripts = ["<output script hex>"]
+//! max_transactions = 2000
+//! max_weight = 3000000
+//! deny_inscriptions = true
+//! # sat/vB
+//! min_fee_rate = 2.0
+//! ```
+//!
+//! When a policy is configured the transactions of each template are requested to the TP before
+//! miners get its job. The filtered transactions are removed from the template: its merkle path
+//! and witness commitment are computed again and the fees of the removed transactions are taken
+//! out of the coinbase value, so that miners work on the job that is declared. The TP does not
+//! give the fee of each transaction, they are read from the mempool of the `--bitcoind-rpc-url`
+//! endpoints; a template is declared unfiltered when the fee of a filtered transaction is not
+//! known, and the fee rate is not checked for transactions whose fee is not known.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use binary_sv2::{Seq0255, Seq064K, B016M, U256};
+use bitcoin::{
+    blockdata::transaction::Transaction,
+    consensus::{Decodable, Encodable},
+    hashes::{sha256d, Hash, HashEngine},
+    Amount, Block, ScriptBuf, TxOut, Txid, WitnessMerkleNode, Wtxid,
+};
+use lazy_static::lazy_static;
+use roles_logic_sv2::{template_distribution_sv2::NewTemplate, utils::Mutex};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::Configuration,
+    jd_client::block_submitter::{redact, WITNESS_COMMITMENT_PREFIX},
+};
+
+/// `OP_FALSE OP_IF OP_PUSHBYTES_3 "ord"`, the start of an ordinals inscription envelope
+const INSCRIPTION_ENVELOPE: [u8; 6] = [0x00, 0x63, 0x03, b'o', b'r', b'd'];
+/// Max time to wait for the fees of the template transactions from a bitcoind RPC endpoint, the
+/// job of the template is sent to the miners only after
+const FEES_TIMEOUT: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    // Last loaded policy, with the file it was loaded from and the file modification time
+    static ref POLICY: Mutex<Option<(PathBuf, SystemTime, TxPolicy)>> = Mutex::new(None);
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TxPolicyFile {
+    #[serde(default)]
+    denied_txids: Vec<String>,
+    #[serde(default)]
+    denied_scripts: Vec<String>,
+    max_transactions: Option<usize>,
+    max_weight: Option<u64>,
+    #[serde(default)]
+    deny_inscriptions: bool,
+    min_fee_rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TxPolicy {
+    denied_txids: HashSet<Txid>,
+    denied_scripts: HashSet<ScriptBuf>,
+    max_transactions: Option<usize>,
+    max_weight: Option<u64>,
+    deny_inscriptions: bool,
+    // sat/vB
+    min_fee_rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterReason {
+    DeniedTxid,
+    DeniedScript,
+    Inscription,
+    LowFeeRate,
+    MaxTransactions,
+    MaxWeight,
+    // Spends an output of a filtered transaction
+    FilteredParent,
+}
+
+/// Result of the policy evaluation on the transactions of a template
+#[derive(Debug, Default)]
+pub struct PolicyReport {
+    pub filtered: Vec<(Txid, FilterReason)>,
+}
+
+impl TxPolicy {
+    fn from_file(file: TxPolicyFile) -> Result<Self, String> {
+        let denied_txids = file
+            .denied_txids
+            .iter()
+            .map(|txid| Txid::from_str(txid).map_err(|e| format!("invalid txid {}: {}", txid, e)))
+            .collect::<Result<_, _>>()?;
+        let denied_scripts = file
+            .denied_scripts
+            .iter()
+            .map(|script| {
+                ScriptBuf::from_hex(script).map_err(|e| format!("invalid script {}: {}", script, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            denied_txids,
+            denied_scripts,
+            max_transactions: file.max_transactions,
+            max_weight: file.max_weight,
+            deny_inscriptions: file.deny_inscriptions,
+            min_fee_rate: file.min_fee_rate,
+        })
+    }
+
+    /// Evaluates the policy on the transactions of a template, in template order. Transactions
+    /// that spend outputs of filtered transactions are filtered too, so that the remaining ones
+    /// are still a valid block. `fees` are the known fees in sat of the transactions.
+    pub fn evaluate(
+        &self,
+        transactions: &[Transaction],
+        fees: &HashMap<Txid, u64>,
+    ) -> PolicyReport {
+        let mut report = PolicyReport::default();
+        let mut filtered_txids = HashSet::new();
+        let mut kept = 0;
+        let mut weight = 0;
+        for tx in transactions {
+            let txid = tx.compute_txid();
+            let tx_weight = tx.weight().to_wu();
+            let reason = if self.denied_txids.contains(&txid) {
+                Some(FilterReason::DeniedTxid)
+            } else if tx
+                .input
+                .iter()
+                .any(|input| filtered_txids.contains(&input.previous_output.txid))
+            {
+                Some(FilterReason::FilteredParent)
+            } else if tx
+                .output
+                .iter()
+                .any(|output| self.denied_scripts.contains(&output.script_pubkey))
+            {
+                Some(FilterReason::DeniedScript)
+            } else if self.deny_inscriptions && has_inscription(tx) {
+                Some(FilterReason::Inscription)
+            } else if self.min_fee_rate.is_some_and(|min| {
+                fees.get(&txid)
+                    .is_some_and(|fee| (*fee as f64) < min * tx.vsize() as f64)
+            }) {
+                Some(FilterReason::LowFeeRate)
+            } else if self.max_transactions.is_some_and(|max| kept >= max) {
+                Some(FilterReason::MaxTransactions)
+            } else if self.max_weight.is_some_and(|max| weight + tx_weight > max) {
+                Some(FilterReason::MaxWeight)
+            } else {
+                None
+            };
+            match reason {
+                Some(reason) => {
+                    filtered_txids.insert(txid);
+                    report.filtered.push((txid, reason));
+                }
+                None => {
+                    kept += 1;
+                    weight += tx_weight;
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Applies the policy to a template and to its transactions as received from the TP, and returns
+/// the template and the transactions to use for the job. They are returned unchanged when nothing
+/// is filtered or when the template can not be filtered.
+pub async fn apply(
+    policy: &TxPolicy,
+    template: NewTemplate<'static>,
+    tx_data: Seq064K<'static, B016M<'static>>,
+) -> (NewTemplate<'static>, Seq064K<'static, B016M<'static>>) {
+    let mut transactions = Vec::new();
+    for tx in tx_data.to_vec() {
+        match bitcoin::consensus::deserialize::<Transaction>(&tx) {
+            Ok(tx) => transactions.push(tx),
+            Err(e) => {
+                error!(
+                    "Template {}: invalid transaction, the tx policy is not applied: {}",
+                    template.template_id, e
+                );
+                return (template, tx_data);
+            }
+        }
+    }
+    let mut fees = if policy.min_fee_rate.is_some() {
+        let txids: Vec<Txid> = transactions.iter().map(|tx| tx.compute_txid()).collect();
+        mempool_fees(&txids).await
+    } else {
+        HashMap::new()
+    };
+    let report = policy.evaluate(&transactions, &fees);
+    if report.filtered.is_empty() {
+        return (template, tx_data);
+    }
+    warn!(
+        "Template {}: {} of {} transactions filtered by the tx policy: {:?}",
+        template.template_id,
+        report.filtered.len(),
+        transactions.len(),
+        report.filtered
+    );
+    let unknown_fees: Vec<Txid> = report
+        .filtered
+        .iter()
+        .map(|(txid, _)| *txid)
+        .filter(|txid| !fees.contains_key(txid))
+        .collect();
+    fees.extend(mempool_fees(&unknown_fees).await);
+    let filtered_template = match filter_template(&template, &transactions, &report, &fees) {
+        Ok(filtered_template) => filtered_template,
+        Err(e) => {
+            error!(
+                "Template {}: can not remove the filtered transactions, the template is \
+                declared unfiltered: {}",
+                template.template_id, e
+            );
+            return (template, tx_data);
+        }
+    };
+    let filtered: HashSet<Txid> = report.filtered.iter().map(|(txid, _)| *txid).collect();
+    // The transactions are kept to declare the template unfiltered if the filtered list is invalid
+    let kept_data: Vec<B016M<'static>> = tx_data
+        .clone()
+        .into_inner()
+        .into_iter()
+        .zip(&transactions)
+        .filter(|(_, tx)| !filtered.contains(&tx.compute_txid()))
+        .map(|(data, _)| data)
+        .collect();
+    match Seq064K::new(kept_data) {
+        Ok(kept_data) => (filtered_template, kept_data),
+        Err(e) => {
+            error!(
+                "Template {}: invalid filtered transactions list, the template is declared \
+                unfiltered: {:?}",
+                template.template_id, e
+            );
+            (template, tx_data)
+        }
+    }
+}
+
+/// Removes the filtered transactions from the template. The merkle path and the witness
+/// commitment of the coinbase outputs are computed from the remaining transactions, and the fees
+/// of the removed ones are taken out of the coinbase value. Fails if the fee of a removed
+/// transaction is not known.
+fn filter_template(
+    template: &NewTemplate<'static>,
+    transactions: &[Transaction],
+    report: &PolicyReport,
+    fees: &HashMap<Txid, u64>,
+) -> Result<NewTemplate<'static>, String> {
+    let filtered: HashSet<Txid> = report.filtered.iter().map(|(txid, _)| *txid).collect();
+    let mut removed_fees: u64 = 0;
+    let mut kept = Vec::with_capacity(transactions.len());
+    for tx in transactions {
+        let txid = tx.compute_txid();
+        if filtered.contains(&txid) {
+            removed_fees += *fees
+                .get(&txid)
+                .ok_or_else(|| format!("unknown fee of {}", txid))?;
+        } else {
+            kept.push(tx);
+        }
+    }
+
+    let mut outputs_data = &template.coinbase_tx_outputs.to_vec()[..];
+    let mut outputs = Vec::with_capacity(template.coinbase_tx_outputs_count as usize);
+    for _ in 0..template.coinbase_tx_outputs_count {
+        outputs.push(
+            TxOut::consensus_decode(&mut outputs_data)
+                .map_err(|e| format!("invalid coinbase output: {}", e))?,
+        );
+    }
+    let commitment = witness_commitment_script(&kept);
+    for output in outputs.iter_mut() {
+        if output
+            .script_pubkey
+            .as_bytes()
+            .starts_with(&WITNESS_COMMITMENT_PREFIX)
+        {
+            output.script_pubkey = commitment.clone();
+        }
+    }
+    let mut coinbase_tx_outputs = vec![];
+    for output in &outputs {
+        output
+            .consensus_encode(&mut coinbase_tx_outputs)
+            .map_err(|e| e.to_string())?;
+    }
+
+    let merkle_path: Vec<U256<'static>> = merkle_path(&kept)
+        .into_iter()
+        .map(|hash| hash.into())
+        .collect();
+    let mut filtered_template = template.clone();
+    filtered_template.coinbase_tx_value_remaining = template
+        .coinbase_tx_value_remaining
+        .checked_sub(removed_fees)
+        .ok_or("the fees of the removed transactions exceed the coinbase value")?;
+    filtered_template.coinbase_tx_outputs = coinbase_tx_outputs
+        .try_into()
+        .map_err(|e| format!("invalid coinbase outputs: {:?}", e))?;
+    filtered_template.merkle_path =
+        Seq0255::new(merkle_path).map_err(|e| format!("invalid merkle path: {:?}", e))?;
+    Ok(filtered_template)
+}
+
+/// Merkle path of the coinbase in a block with these transactions after it
+fn merkle_path(transactions: &[&Transaction]) -> Vec<[u8; 32]> {
+    let mut path = vec![];
+    let mut level: Vec<[u8; 32]> = transactions
+        .iter()
+        .map(|tx| tx.compute_txid().to_byte_array())
+        .collect();
+    // The branch of the coinbase is not known, its sibling at each level is the first hash of
+    // the rest of the level
+    while let Some(sibling) = level.first() {
+        path.push(*sibling);
+        level = level[1..]
+            .chunks(2)
+            .map(|pair| {
+                let mut engine = sha256d::Hash::engine();
+                engine.input(&pair[0]);
+                engine.input(pair.get(1).unwrap_or(&pair[0]));
+                sha256d::Hash::from_engine(engine).to_byte_array()
+            })
+            .collect();
+    }
+    path
+}
+
+/// Coinbase output script committing to the witnesses of these transactions, with a witness
+/// reserved value of zeros
+fn witness_commitment_script(transactions: &[&Transaction]) -> ScriptBuf {
+    // The wtxid of the coinbase counts as zeros
+    let wtxids = std::iter::once(Wtxid::all_zeros())
+        .chain(transactions.iter().map(|tx| tx.compute_wtxid()))
+        .map(|wtxid| wtxid.to_raw_hash());
+    let witness_root = bitcoin::merkle_tree::calculate_root(wtxids)
+        .map(WitnessMerkleNode::from_raw_hash)
+        .unwrap_or_else(WitnessMerkleNode::all_zeros);
+    let commitment = Block::compute_witness_commitment(&witness_root, &[0u8; 32]);
+    let mut script = WITNESS_COMMITMENT_PREFIX.to_vec();
+    script.extend_from_slice(commitment.as_byte_array());
+    ScriptBuf::from_bytes(script)
+}
+
+/// Fees in sat of transactions in the mempool of the first `--bitcoind-rpc-url` endpoint that
+/// answers. Transactions that are not found are left out.
+async fn mempool_fees(txids: &[Txid]) -> HashMap<Txid, u64> {
+    if txids.is_empty() {
+        return HashMap::new();
+    }
+    let requests: Vec<serde_json::Value> = txids
+        .iter()
+        .enumerate()
+        .map(|(id, txid)| {
+            json!({
+                "jsonrpc": "1.0",
+                "id": id,
+                "method": "getmempoolentry",
+                "params": [txid],
+            })
+        })
+        .collect();
+    let client = crate::outbound::http_client();
+    for url in Configuration::bitcoind_rpc_urls() {
+        let request = client.post(&url).json(&requests).timeout(FEES_TIMEOUT);
+        let responses: Vec<serde_json::Value> = match request.send().await {
+            Ok(response) => match response.json().await {
+                Ok(responses) => responses,
+                Err(e) => {
+                    warn!(
+                        "Invalid getmempoolentry response from {}: {}",
+                        redact(&url),
+                        e
+                    );
+                    continue;
+                }
+            },
+            Err(e) => {
+                warn!("Failed to get the fees from {}: {}", redact(&url), e);
+                continue;
+            }
+        };
+        return responses
+            .iter()
+            .filter_map(|response| {
+                let txid = txids.get(response["id"].as_u64()? as usize)?;
+                let fee = Amount::from_btc(response["result"]["fees"]["base"].as_f64()?).ok()?;
+                Some((*txid, fee.to_sat()))
+            })
+            .collect();
+    }
+    HashMap::new()
+}
+
+fn has_inscription(tx: &Transaction) -> bool {
+    tx.input
+        .iter()
+        .any(|input| input.witness.iter().any(contains_inscription_envelope))
+}
+
+fn contains_inscription_envelope(witness_element: &[u8]) -> bool {
+    witness_element
+        .windows(INSCRIPTION_ENVELOPE.len())
+        .any(|window| window == INSCRIPTION_ENVELOPE)
+}
+
+fn load(path: &Path) -> Result<TxPolicy, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let file: TxPolicyFile = toml::from_str(&content).map_err(|e| e.to_string())?;
+    TxPolicy::from_file(file)
+}
+
+/// Returns the configured policy, reloading it if the file changed since the last call. If the
+/// new file is not valid the previous policy is kept.
+pub fn current() -> Option<TxPolicy> {
+    let path = Configuration::tx_policy_file()?;
+    let modified = match std::fs::metadata(&path).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(e) => {
+            warn!("Can not read tx policy file {}: {}", path.display(), e);
+            SystemTime::UNIX_EPOCH
+        }
+    };
+    POLICY
+        .safe_lock(|policy| {
+            let up_to_date = matches!(
+                policy,
+                Some((loaded_path, loaded_at, _)) if *loaded_path == path && *loaded_at == modified
+            );
+            if !up_to_date {
+                match load(&path) {
+                    Ok(loaded) => {
+                        info!("Loaded tx policy from {}", path.display());
+                        *policy = Some((path.clone(), modified, loaded));
+                    }
+                    Err(e) => error!("Invalid tx policy file {}: {}", path.display(), e),
+                }
+            }
+            policy.as_ref().map(|(_, _, policy)| policy.clone())
+        })
+        .unwrap_or_else(|_| {
+            error!("Tx policy Mutex Corrupted");
+            None
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{absolute::LockTime, transaction, OutPoint, Sequence, TxIn, Witness};
+
+    fn transaction(spends: Txid, script_pubkey: ScriptBuf, witness: bool) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(spends, 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: if witness {
+                    Witness::from_slice(&[vec![3u8; 72], vec![4u8; 33]])
+                } else {
+                    Witness::new()
+                },
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey,
+            }],
+        }
+    }
+
+    fn p2wpkh(byte: u8) -> ScriptBuf {
+        ScriptBuf::from_bytes(vec![0x00, 0x14].into_iter().chain([byte; 20]).collect())
+    }
+
+    fn template(transactions: &[Transaction]) -> NewTemplate<'static> {
+        let transactions: Vec<&Transaction> = transactions.iter().collect();
+        let mut outputs = vec![];
+        TxOut {
+            value: Amount::ZERO,
+            script_pubkey: witness_commitment_script(&transactions),
+        }
+        .consensus_encode(&mut outputs)
+        .unwrap();
+        let merkle_path: Vec<U256<'static>> = merkle_path(&transactions)
+            .into_iter()
+            .map(|hash| hash.into())
+            .collect();
+        NewTemplate {
+            template_id: 1,
+            future_template: false,
+            version: 0x2000_0000,
+            coinbase_tx_version: 2,
+            coinbase_prefix: vec![0x03, 0x01, 0x02, 0x03].try_into().unwrap(),
+            coinbase_tx_input_sequence: u32::MAX,
+            coinbase_tx_value_remaining: 312_501_500,
+            coinbase_tx_outputs_count: 1,
+            coinbase_tx_outputs: outputs.try_into().unwrap(),
+            coinbase_tx_locktime: 0,
+            merkle_path: Seq0255::new(merkle_path).unwrap(),
+        }
+    }
+
+    #[test]
+    fn evaluates_policy() {
+        let denied = transaction(Txid::from_byte_array([1; 32]), p2wpkh(1), true);
+        let child = transaction(denied.compute_txid(), p2wpkh(2), true);
+        let denied_script = transaction(Txid::from_byte_array([2; 32]), p2wpkh(9), false);
+        let low_fee = transaction(Txid::from_byte_array([3; 32]), p2wpkh(3), false);
+        let unknown_fee = transaction(Txid::from_byte_array([4; 32]), p2wpkh(4), false);
+        let kept = transaction(Txid::from_byte_array([5; 32]), p2wpkh(5), true);
+        let over_max = transaction(Txid::from_byte_array([6; 32]), p2wpkh(6), true);
+        let policy = TxPolicy {
+            denied_txids: HashSet::from([denied.compute_txid()]),
+            denied_scripts: HashSet::from([p2wpkh(9)]),
+            max_transactions: Some(2),
+            min_fee_rate: Some(1.0),
+            ..Default::default()
+        };
+        let fees = HashMap::from([
+            (low_fee.compute_txid(), 10),
+            (kept.compute_txid(), 1_000),
+            (over_max.compute_txid(), 1_000),
+        ]);
+        let transactions = vec![
+            denied.clone(),
+            child.clone(),
+            denied_script.clone(),
+            low_fee.clone(),
+            unknown_fee,
+            kept,
+            over_max.clone(),
+        ];
+        let report = policy.evaluate(&transactions, &fees);
+        assert_eq!(
+            report.filtered,
+            vec![
+                (denied.compute_txid(), FilterReason::DeniedTxid),
+                (child.compute_txid(), FilterReason::FilteredParent),
+                (denied_script.compute_txid(), FilterReason::DeniedScript),
+                (low_fee.compute_txid(), FilterReason::LowFeeRate),
+                (over_max.compute_txid(), FilterReason::MaxTransactions),
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuilds_filtered_template() {
+        let kept = transaction(Txid::from_byte_array([1; 32]), p2wpkh(1), false);
+        let denied = transaction(Txid::from_byte_array([2; 32]), p2wpkh(2), true);
+        let kept_segwit = transaction(Txid::from_byte_array([3; 32]), p2wpkh(3), true);
+        let transactions = vec![kept.clone(), denied.clone(), kept_segwit.clone()];
+        let template = template(&transactions);
+        let policy = TxPolicy {
+            denied_txids: HashSet::from([denied.compute_txid()]),
+            ..Default::default()
+        };
+        let report = policy.evaluate(&transactions, &HashMap::new());
+        assert!(filter_template(&template, &transactions, &report, &HashMap::new()).is_err());
+
+        let fees = HashMap::from([(denied.compute_txid(), 500)]);
+        let filtered = filter_template(&template, &transactions, &report, &fees).unwrap();
+        assert_eq!(filtered.coinbase_tx_value_remaining, 312_501_000);
+
+        // Coinbase as built from the filtered template
+        let mut outputs_data = &filtered.coinbase_tx_outputs.to_vec()[..];
+        let coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(vec![0x03, 0x01, 0x02, 0x03]),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[[0u8; 32]]),
+            }],
+            output: vec![
+                TxOut {
+                    value: Amount::from_sat(filtered.coinbase_tx_value_remaining),
+                    script_pubkey: p2wpkh(7),
+                },
+                TxOut::consensus_decode(&mut outputs_data).unwrap(),
+            ],
+        };
+        let block = Block {
+            header: bitcoin::block::Header {
+                version: bitcoin::block::Version::TWO,
+                prev_blockhash: bitcoin::BlockHash::all_zeros(),
+                merkle_root: bitcoin::TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: bitcoin::CompactTarget::from_consensus(0),
+                nonce: 0,
+            },
+            txdata: vec![coinbase.clone(), kept, kept_segwit],
+        };
+        assert!(block.check_witness_commitment());
+        let merkle_root = filtered.merkle_path.clone().into_static().0.iter().fold(
+            coinbase.compute_txid().to_byte_array(),
+            |hash, sibling| {
+                let mut engine = sha256d::Hash::engine();
+                engine.input(&hash);
+                engine.input(&sibling.to_vec());
+                sha256d::Hash::from_engine(engine).to_byte_array()
+            },
+        );
+        assert_eq!(
+            merkle_root,
+            block.compute_merkle_root().unwrap().to_byte_array()
+        );
+    }
+
+    #[test]
+    fn detects_inscription_envelope() {
+        let mut tapscript = vec![0x20; 33];
+        tapscript.extend_from_slice(&[0xac, 0x00, 0x63, 0x03, b'o', b'r', b'd', 0x01, 0x01]);
+        assert!(contains_inscription_envelope(&tapscript));
+        assert!(!contains_inscription_envelope(&[
+            0x00, 0x63, 0x03, b'o', b'r'
+        ]));
+    }
+}
@@ -1,11 +1,15 @@
 mod task_manager;
 use crate::{
-    proxy_state::{DownstreamType, JdState, ProxyState},
+    proxy_state::{DownstreamType, ProxyState},
     shared::utils::AbortOnDrop,
 };
 use tokio::time::{timeout, Duration};
 
-use super::{job_declarator::JobDeclarator, mining_upstream::Upstream as UpstreamMiningNode};
+use super::{
+    block_submitter::{self, SubmissionResult},
+    job_declarator::JobDeclarator,
+    mining_upstream::Upstream as UpstreamMiningNode,
+};
 use crate::jd_client::error::Error as JdClientError;
 use roles_logic_sv2::{
     channel_logic::channel_factory::{OnNewShare, PoolChannelFactory, Share},
@@ -23,11 +27,11 @@ use tokio::{
     sync::mpsc::{Receiver as TReceiver, Sender as TSender},
     task,
 };
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 
 use codec_sv2::{StandardEitherFrame, StandardSv2Frame};
 
-use bitcoin::{consensus::Decodable, TxOut};
+use bitcoin::{consensus::Decodable, BlockHash, TxOut};
 
 pub type Message = MiningDeviceMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
@@ -42,7 +46,7 @@ pub struct DownstreamMiningNode {
     sender: TSender<Mining<'static>>,
     pub status: DownstreamMiningNodeStatus,
     pub prev_job_id: Option<u32>,
-    solution_sender: TSender<SubmitSolution<'static>>,
+    solution_sender: TSender<(SubmitSolution<'static>, Option<BlockHash>)>,
     withhold: bool,
     miner_coinbase_output: Vec<TxOut>,
     // used to retreive the job id of the share that we send upstream
@@ -118,7 +122,7 @@ impl DownstreamMiningNode {
     pub fn new(
         sender: TSender<Mining<'static>>,
         upstream: Option<Arc<Mutex<UpstreamMiningNode>>>,
-        solution_sender: TSender<SubmitSolution<'static>>,
+        solution_sender: TSender<(SubmitSolution<'static>, Option<BlockHash>)>,
         withhold: bool,
         miner_coinbase_output: Vec<TxOut>,
         jd: Option<Arc<Mutex<JobDeclarator>>>,
@@ -142,6 +146,17 @@ impl DownstreamMiningNode {
         }
     }
 
+    /// Links the node to a template provider connection: the solutions found on the declared
+    /// jobs are sent to `solution_sender` and the blocks are assembled with `jd`.
+    pub fn set_template_provider(
+        &mut self,
+        solution_sender: TSender<(SubmitSolution<'static>, Option<BlockHash>)>,
+        jd: Option<Arc<Mutex<JobDeclarator>>>,
+    ) {
+        self.solution_sender = solution_sender;
+        self.jd = jd;
+    }
+
     /// Strat listen for downstream mining node. Return as soon as one downstream connect.
     pub async fn start(
         self_mutex: Arc<Mutex<Self>>,
@@ -167,7 +182,10 @@ impl DownstreamMiningNode {
             while let Some(message) = receiver.recv().await {
                 if let Err(e) = DownstreamMiningNode::next(&self_mutex, message).await {
                     error!("Jd error can not receive message from downstream: {e:?}");
-                    ProxyState::update_downstream_state(DownstreamType::JdClientMiningDownstream);
+                    ProxyState::update_downstream_state(
+                        DownstreamType::JdClientMiningDownstream,
+                        &format!("Jd error can not receive message from downstream: {e:?}"),
+                    );
                 };
             }
         });
@@ -193,6 +211,7 @@ impl DownstreamMiningNode {
                             error!("Jd can not get upstream");
                             ProxyState::update_downstream_state(
                                 DownstreamType::JdClientMiningDownstream,
+                                "Jd can not get upstream",
                             );
                             return;
                         }
@@ -201,6 +220,7 @@ impl DownstreamMiningNode {
                         error!("Jd can not get upstream: {e}");
                         ProxyState::update_downstream_state(
                             DownstreamType::JdClientMiningDownstream,
+                            &format!("Jd can not get upstream: {e}"),
                         );
                         return;
                     }
@@ -249,7 +269,10 @@ impl DownstreamMiningNode {
                     Some(incoming) => incoming,
                     None => {
                         error!("JDC dowstream try to releay an inexistent message");
-                        ProxyState::update_jd_state(JdState::Down);
+                        ProxyState::update_downstream_state(
+                            DownstreamType::JdClientMiningDownstream,
+                            "JDC dowstream try to releay an inexistent message",
+                        );
                         return Err(JdClientError::Unrecoverable);
                     }
                 };
@@ -292,6 +315,7 @@ impl DownstreamMiningNode {
                         error!("Upstream is None Here");
                         ProxyState::update_downstream_state(
                             DownstreamType::JdClientMiningDownstream,
+                            "Upstream is None Here",
                         );
                     }
                 });
@@ -316,6 +340,7 @@ impl DownstreamMiningNode {
                         error!("Jd Unexpected message: {e:?}");
                         ProxyState::update_downstream_state(
                             DownstreamType::JdClientMiningDownstream,
+                            &format!("Jd Unexpected message: {e:?}"),
                         );
                     }
                 }
@@ -455,6 +480,10 @@ impl DownstreamMiningNode {
         Self::send(self_mutex, message)
             .await
             .map_err(|_| Error::DownstreamDown)?; // Caller will restart proxy
+                                                  // Miners work on the declared jobs from now on
+        if super::IS_ON_POOL_JOBS.swap(false, std::sync::atomic::Ordering::AcqRel) {
+            info!("Switched from the pool jobs to the declared jobs");
+        }
         Ok(())
     }
 }
@@ -584,6 +613,14 @@ impl
         &mut self,
         m: SubmitSharesExtended,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
+        // Shares on the pool jobs are checked by the pool
+        if super::IS_ON_POOL_JOBS.load(std::sync::atomic::Ordering::Acquire) {
+            return Ok(SendTo::RelaySameMessageToRemote(
+                self.status
+                    .get_upstream()
+                    .ok_or(Error::NoUpstreamsConnected)?,
+            ));
+        }
         match self
             .status
             .get_channel()
@@ -618,16 +655,30 @@ impl
             )) => {
                 match share {
                     Share::Extended(share) => {
+                        info!("Share meets bitcoin target, block found");
+                        // The block is assembled first so that every submission is linked to
+                        // its hash
+                        let block = match &self.jd {
+                            Some(jd) => {
+                                JobDeclarator::assemble_block(jd, template_id, &share, &coinbase)
+                                    .unwrap_or_else(|e| {
+                                        error!("Failed to assemble block: {e:?}");
+                                        None
+                                    })
+                            }
+                            None => None,
+                        };
+                        let block_hash = block.as_ref().map(|block| block.block_hash());
                         let solution_sender = self.solution_sender.clone();
                         let solution = SubmitSolution {
                             template_id,
                             version: share.version,
                             header_timestamp: share.ntime,
                             header_nonce: share.nonce,
-                            coinbase_tx: coinbase.try_into()?,
+                            coinbase_tx: coinbase.clone().try_into()?,
                         };
                         tokio::spawn(async move {
-                            if solution_sender.send(solution).await.is_err() {
+                            if solution_sender.send((solution, block_hash)).await.is_err() {
                                 error!("Downstream channel closed, couldn't send solution");
                             }
                         });
@@ -640,11 +691,36 @@ impl
                                 // fastly
                                 tokio::task::spawn(async move {
                                     if let Some(jd) = jd {
-                                        if let Err(e) = JobDeclarator::on_solution(&jd, share).await
-                                        {
-                                            error!("Jd Error on solution: {e:?}");
-                                            // Set the proxy state to internal inconsistency
-                                            ProxyState::update_inconsistency(Some(1));
+                                        match JobDeclarator::on_solution(&jd, share.clone()).await {
+                                            Ok(()) => block_submitter::report(
+                                                Some(template_id),
+                                                block_hash,
+                                                "jds",
+                                                SubmissionResult::Sent,
+                                            ),
+                                            Err(e) => {
+                                                error!("Jd Error on solution: {e:?}");
+                                                block_submitter::report(
+                                                    Some(template_id),
+                                                    block_hash,
+                                                    "jds",
+                                                    SubmissionResult::Failed {
+                                                        error: format!("{e:?}"),
+                                                    },
+                                                );
+                                                // Set the proxy state to internal inconsistency
+                                                ProxyState::update_inconsistency(
+                                                    Some(1),
+                                                    &format!("Jd Error on solution: {e:?}"),
+                                                );
+                                            }
+                                        }
+                                        if let Some(block) = block {
+                                            block_submitter::submit_to_bitcoind(
+                                                Some(template_id),
+                                                &block,
+                                            )
+                                            .await;
                                         }
                                     }
                                 });
@@ -1,5 +1,5 @@
-use crate::jd_client::IS_CUSTOM_JOB_SET;
-use crate::proxy_state::{DownstreamType, ProxyState, TpState, UpstreamType};
+use crate::jd_client::{job_declarator::audit, IS_CUSTOM_JOB_SET, IS_ON_POOL_JOBS};
+use crate::proxy_state::{DownstreamType, ProxyState, UpstreamType};
 use crate::{jd_client::error::Error, jd_client::error::ProxyResult, shared::utils::AbortOnDrop};
 
 use crate::jd_client::mining_downstream::DownstreamMiningNode as Downstream;
@@ -11,7 +11,9 @@ use roles_logic_sv2::{
     common_properties::{IsMiningUpstream, IsUpstream},
     handlers::mining::{ParseUpstreamMiningMessages, SendTo},
     job_declaration_sv2::DeclareMiningJob,
-    mining_sv2::{ExtendedExtranonce, Extranonce, SetCustomMiningJob},
+    mining_sv2::{
+        ExtendedExtranonce, Extranonce, NewExtendedMiningJob, SetCustomMiningJob, SetNewPrevHash,
+    },
     parsers::Mining,
     routing_logic::{MiningRoutingLogic, NoRouting},
     selectors::NullDownstreamMiningSelector,
@@ -21,7 +23,7 @@ use roles_logic_sv2::{
 use std::{collections::HashMap, sync::Arc};
 use tokio::sync::mpsc::{Receiver as TReceiver, Sender as TSender};
 use tokio::task;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info};
 
 use std::collections::VecDeque;
 
@@ -109,6 +111,11 @@ pub struct Upstream {
     channel_factory: Option<PoolChannelFactory>,
     template_to_job_id: TemplateToJobId,
     req_ids: Id,
+    /// Last jobs sent by the pool, miners are switched to them when the declared jobs are not
+    /// available
+    last_pool_job: Option<NewExtendedMiningJob<'static>>,
+    future_pool_job: Option<NewExtendedMiningJob<'static>>,
+    last_pool_prev_hash: Option<SetNewPrevHash<'static>>,
 }
 
 impl Upstream {
@@ -142,6 +149,9 @@ impl Upstream {
             channel_factory: None,
             template_to_job_id: TemplateToJobId::new(),
             req_ids: Id::new(),
+            last_pool_job: None,
+            future_pool_job: None,
+            last_pool_prev_hash: None,
         })))
     }
 
@@ -196,6 +206,7 @@ impl Upstream {
             merkle_path,
             extranonce_size: 0,
         };
+        audit::record_custom_job_set(template_id, &to_send.prev_hash.to_vec());
         let message = Mining::SetCustomMiningJob(to_send);
         self_
             .safe_lock(|s| {
@@ -231,8 +242,10 @@ impl Upstream {
                         Some(msg) => msg,
                         None => {
                             error!("Upstream down");
-                            // Update the proxy state to reflect the Tp is down
-                            ProxyState::update_tp_state(TpState::Down);
+                            ProxyState::update_upstream_state(
+                                UpstreamType::JDCMiningUpstream,
+                                "Upstream down",
+                            );
                             break;
                         }
                     };
@@ -258,6 +271,7 @@ impl Upstream {
                                 // Update global proxy downstream state
                                 ProxyState::update_downstream_state(
                                     DownstreamType::JdClientMiningDownstream,
+                                    "Failed to send message downstream",
                                 );
                                 break;
                             };
@@ -266,7 +280,10 @@ impl Upstream {
                         Ok(_) => unreachable!(),
                         Err(e) => {
                             error!("{e:?}");
-                            ProxyState::update_upstream_state(UpstreamType::JDCMiningUpstream);
+                            ProxyState::update_upstream_state(
+                                UpstreamType::JDCMiningUpstream,
+                                &format!("{e:?}"),
+                            );
                             break;
                         }
                     }
@@ -298,6 +315,19 @@ impl Upstream {
             .map_err(|_| Error::JdClientUpstreamMutexCorrupted)?
     }
 
+    /// Messages that make the miners work on the last pool job: the job is sent as a future job
+    /// and activated by the last pool prev hash. None if the pool did not send a job yet.
+    pub fn last_pool_job_messages(&self) -> Option<Vec<Mining<'static>>> {
+        let mut job = self.last_pool_job.clone()?;
+        job.min_ntime = binary_sv2::Sv2Option::new(None);
+        let mut prev_hash = self.last_pool_prev_hash.clone()?;
+        prev_hash.job_id = job.job_id;
+        Some(vec![
+            Mining::NewExtendedMiningJob(job),
+            Mining::SetNewPrevHash(prev_hash),
+        ])
+    }
+
     pub async fn get_job_id(self_: &Arc<Mutex<Self>>, template_id: u64) -> Result<u32, Error> {
         loop {
             if let Some(id) = self_
@@ -548,23 +578,58 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
     /// Handles the SV2 `NewExtendedMiningJob` message which is used (along with the SV2
     /// `SetNewPrevHash` message) to later create a SV1 `mining.notify` for the Downstream
     /// role.
+    ///
+    /// The pool jobs are relayed only while the declared jobs are not available, otherwise they
+    /// are just kept so that miners can be switched to them.
     fn handle_new_extended_mining_job(
         &mut self,
-        _: roles_logic_sv2::mining_sv2::NewExtendedMiningJob,
+        m: roles_logic_sv2::mining_sv2::NewExtendedMiningJob,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        warn!("Extended job received from upstream, proxy ignore it, and use the one declared by JOB DECLARATOR");
-        Ok(SendTo::None(None))
+        let m = m.into_static();
+        if m.is_future() {
+            self.future_pool_job = Some(m);
+        } else {
+            self.last_pool_job = Some(m);
+        }
+        if IS_ON_POOL_JOBS.load(std::sync::atomic::Ordering::Acquire) {
+            let downstream = self
+                .downstream
+                .clone()
+                .ok_or(RolesLogicError::DownstreamDown)?;
+            Ok(SendTo::RelaySameMessageToRemote(downstream))
+        } else {
+            debug!("Extended job received from upstream, proxy keeps it and uses the one declared by JOB DECLARATOR");
+            Ok(SendTo::None(None))
+        }
     }
 
     /// Handles the SV2 `SetNewPrevHash` message which is used (along with the SV2
     /// `NewExtendedMiningJob` message) to later create a SV1 `mining.notify` for the Downstream
     /// role.
+    ///
+    /// Like the pool jobs, the pool prev hash is relayed only while the declared jobs are not
+    /// available.
     fn handle_set_new_prev_hash(
         &mut self,
-        _m: roles_logic_sv2::mining_sv2::SetNewPrevHash,
+        m: roles_logic_sv2::mining_sv2::SetNewPrevHash,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        warn!("SNPH received from upstream, proxy ignores it, and uses the one declared by JOB DECLARATOR");
-        Ok(SendTo::None(None))
+        let m = m.into_static();
+        if let Some(job) = self.future_pool_job.take() {
+            if job.job_id == m.job_id {
+                self.last_pool_job = Some(job);
+            }
+        }
+        self.last_pool_prev_hash = Some(m);
+        if IS_ON_POOL_JOBS.load(std::sync::atomic::Ordering::Acquire) {
+            let downstream = self
+                .downstream
+                .clone()
+                .ok_or(RolesLogicError::DownstreamDown)?;
+            Ok(SendTo::RelaySameMessageToRemote(downstream))
+        } else {
+            debug!("SNPH received from upstream, proxy keeps it and uses the one declared by JOB DECLARATOR");
+            Ok(SendTo::None(None))
+        }
     }
 
     /// Handles the SV2 `SetCustomMiningJobSuccess` message.
@@ -575,6 +640,7 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         if let Some(template_id) = self.template_to_job_id.take_template_id(m.request_id) {
             self.template_to_job_id
                 .register_job_id(template_id, m.job_id);
+            audit::record_pool_job_id(template_id, m.job_id);
             info!(
                 "Set custom mining job success {}, for template {}",
                 m.job_id, template_id
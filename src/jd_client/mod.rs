@@ -1,5 +1,6 @@
 #![allow(special_module_name)]
 
+pub mod block_submitter;
 mod error;
 pub mod job_declarator;
 pub mod mining_downstream;
@@ -13,7 +14,31 @@ use mining_downstream::DownstreamMiningNode;
 use std::sync::atomic::AtomicBool;
 use task_manager::TaskManager;
 use template_receiver::TemplateRx;
-use tracing::{error, info};
+use tracing::{debug, error, warn};
+
+use crate::{
+    config::Configuration,
+    events::{self, EventKind},
+};
+
+/// Max time to wait for a template provider to accept the TCP connection
+const TP_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+/// Bounds of the time spent on pool jobs after the job declarations are rejected
+const JD_FALLBACK_MIN_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+const JD_FALLBACK_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+lazy_static! {
+    // Grows while JD keeps being disabled shortly after being enabled again
+    static ref JD_FALLBACK_BACKOFF: Mutex<Backoff> =
+        Mutex::new(Backoff::new(JD_FALLBACK_MIN_DELAY, JD_FALLBACK_MAX_DELAY));
+    // When JD was last enabled. It is in the future while JD is disabled after repeated job
+    // declaration rejections, and it is not reset when the TP or the JDS reconnect.
+    static ref JD_ENABLED_SINCE: Mutex<Option<tokio::time::Instant>> = Mutex::new(None);
+    // Tried after the other template providers on the next connection
+    static ref LAST_FAILED_TP: Mutex<Option<String>> = Mutex::new(None);
+    // Template providers without a pinned authority key that were already connected
+    static ref TRUSTED_TPS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
 
 /// Is used by the template receiver and the downstream. When a NewTemplate is received the context
 /// that is running the template receiver set this value to false and then the message is sent to
@@ -41,16 +66,33 @@ pub static IS_NEW_TEMPLATE_HANDLED: AtomicBool = AtomicBool::new(true);
 pub static IS_CUSTOM_JOB_SET: AtomicBool = AtomicBool::new(true);
 pub static IS_NEW_PHASH_ARRIVED: AtomicBool = AtomicBool::new(false);
 
-use crate::proxy_state::{DownstreamType, ProxyState, TpState};
+/// True while the miners work on the pool jobs, because the template provider or the JDS is not
+/// available. The pool jobs are then relayed to the translator and the shares are relayed to the
+/// pool as they are.
+pub static IS_ON_POOL_JOBS: AtomicBool = AtomicBool::new(true);
+
+/// Set by the control API to drop the current template provider and JDS connections, the
+/// supervisor then connects them again.
+static RECONNECT_TEMPLATE_PROVIDER: AtomicBool = AtomicBool::new(false);
+
+use crate::proxy_state::{DownstreamType, JdState, ProxyState, TpState, UpstreamType};
 use roles_logic_sv2::{parsers::Mining, utils::Mutex};
 use std::{
+    collections::HashSet,
     net::{IpAddr, SocketAddr},
     str::FromStr,
     sync::Arc,
 };
 
-use crate::shared::utils::AbortOnDrop;
+use crate::shared::{
+    supervisor::{self, Backoff},
+    utils::AbortOnDrop,
+};
+use lazy_static::lazy_static;
 
+/// Starts the JDC between the translator and the share accounter. Miners work on the pool jobs
+/// until the template provider and the JDS are connected. Those are supervised on their own, so
+/// when one of them fails the miners are switched to the pool jobs in place while they reconnect.
 pub async fn start(
     receiver: tokio::sync::mpsc::Receiver<Mining<'static>>,
     sender: tokio::sync::mpsc::Sender<Mining<'static>>,
@@ -58,18 +100,16 @@ pub async fn start(
     up_sender: tokio::sync::mpsc::Sender<Mining<'static>>,
 ) -> Option<AbortOnDrop> {
     // This will not work when we implement support for multiple upstream
-    IS_CUSTOM_JOB_SET.store(true, std::sync::atomic::Ordering::Release);
-    IS_NEW_TEMPLATE_HANDLED.store(true, std::sync::atomic::Ordering::Release);
-    IS_NEW_PHASH_ARRIVED.store(false, std::sync::atomic::Ordering::Release);
-    initialize_jd(receiver, sender, up_receiver, up_sender).await
-}
-
-async fn initialize_jd(
-    receiver: tokio::sync::mpsc::Receiver<Mining<'static>>,
-    sender: tokio::sync::mpsc::Sender<Mining<'static>>,
-    up_receiver: tokio::sync::mpsc::Receiver<Mining<'static>>,
-    up_sender: tokio::sync::mpsc::Sender<Mining<'static>>,
-) -> Option<AbortOnDrop> {
+    IS_ON_POOL_JOBS.store(true, std::sync::atomic::Ordering::Release);
+    if JD_ENABLED_SINCE
+        .safe_lock(|since| {
+            since.get_or_insert_with(tokio::time::Instant::now);
+        })
+        .is_err()
+    {
+        error!("JD_ENABLED_SINCE mutex corrupt");
+        return None;
+    }
     let task_manager = TaskManager::initialize();
     let abortable = match task_manager.safe_lock(|t| t.get_aborter()) {
         Ok(abortable) => abortable?,
@@ -78,11 +118,9 @@ async fn initialize_jd(
             return None;
         }
     };
-    let test_only_do_not_send_solution_to_tp = false;
 
-    // When Downstream receive a share that meets bitcoin target it transformit in a
-    // SubmitSolution and send it to the TemplateReceiver
-    let (send_solution, recv_solution) = tokio::sync::mpsc::channel(10);
+    // Replaced by the solution channel of the template provider connection
+    let (send_solution, _) = tokio::sync::mpsc::channel(1);
 
     // Instantiate a new `Upstream` (SV2 Pool)
     let upstream = match mining_upstream::Upstream::new(crate::MIN_EXTRANONCE_SIZE, up_sender).await
@@ -95,72 +133,23 @@ async fn initialize_jd(
         }
     };
 
-    // Initialize JD part
-    let tp_address = match crate::TP_ADDRESS.safe_lock(|tp| tp.clone()) {
-        Ok(tp_address) => tp_address
-            .expect("Unreachable code, jdc is not instantiated when TP_ADDRESS not present"),
-        Err(e) => {
-            error!("TP_ADDRESS mutex corrupted: {e}");
-            drop(abortable);
-            return None;
-        }
-    };
-
-    let mut parts = tp_address.split(':');
-    let ip_tp = parts.next().expect("The passed value for TP address is not valid. Terminating.... TP_ADDRESS should be in this format `127.0.0.1:8442`").to_string();
-    let port_tp = parts.next().expect("The passed value for TP address is not valid. Terminating.... TP_ADDRESS should be in this format `127.0.0.1:8442`").parse::<u16>().expect("This operation should not fail because a valid port_tp should always be converted to U16");
-
-    let auth_pub_k: Secp256k1PublicKey = crate::AUTH_PUB_KEY.parse().expect("Invalid public key");
-    let address = match crate::ACTIVE_POOL_ADDRESS.safe_lock(|address| *address) {
-        Ok(Some(address)) => address,
-        Ok(None) => {
-            error!("Pool address is missing");
-            ProxyState::update_inconsistency(Some(1));
-            return None;
-        }
-        Err(e) => {
-            error!("Pool address mutex is poisoned: {e:?}");
-            ProxyState::update_inconsistency(Some(1));
-            return None;
-        }
-    };
-
-    let (jd, jd_abortable) =
-        match JobDeclarator::new(address, auth_pub_k.into_bytes(), upstream.clone(), true).await {
-            Ok(c) => c,
-            Err(e) => {
-                error!("Failed to intialize Jd: {e}");
-                drop(abortable);
-                return None;
-            }
-        };
-
-    if TaskManager::add_job_declarator_task(task_manager.clone(), jd_abortable)
-        .await
-        .is_err()
-    {
-        error!(
-            "Task manager failed while trying to add job declarator task{}",
-            error::Error::TaskManagerFailed
-        );
-        drop(abortable);
-        return None;
-    };
-
     let donwstream = Arc::new(Mutex::new(DownstreamMiningNode::new(
         sender,
         Some(upstream.clone()),
         send_solution,
         false,
         vec![],
-        Some(jd.clone()),
+        None,
     )));
     let downstream_abortable = match DownstreamMiningNode::start(donwstream.clone(), receiver).await
     {
         Ok(abortable) => abortable,
         Err(e) => {
             error!("Can not start downstream mining node: {e}");
-            ProxyState::update_downstream_state(DownstreamType::JdClientMiningDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::JdClientMiningDownstream,
+                &format!("Can not start downstream mining node: {e}"),
+            );
             return None;
         }
     };
@@ -205,72 +194,331 @@ async fn initialize_jd(
         drop(abortable); // drop all tasks initailzed upto this point
         return None;
     };
+
+    let template_provider_abortable = supervisor::supervise("template_provider", move || {
+        run_template_provider(upstream.clone(), donwstream.clone())
+    });
+    if TaskManager::add_template_provider_task(task_manager, template_provider_abortable)
+        .await
+        .is_err()
+    {
+        error!(
+            "Task manager failed while trying to add template provider task{}",
+            error::Error::TaskManagerFailed
+        );
+        drop(abortable);
+        return None;
+    };
+    Some(abortable)
+}
+
+/// Connects a template provider and the JDS, miners then work on the declared jobs until one of
+/// them fails and they are switched back to the pool jobs. Run by the supervisor, so the
+/// connection is retried as long as the proxy is connected to the pool.
+async fn run_template_provider(
+    upstream: Arc<Mutex<mining_upstream::Upstream>>,
+    downstream: Arc<Mutex<DownstreamMiningNode>>,
+) {
+    if let Some(until) = jd_disabled_until() {
+        tokio::time::sleep_until(until).await;
+    }
+    IS_CUSTOM_JOB_SET.store(true, std::sync::atomic::Ordering::Release);
+    IS_NEW_TEMPLATE_HANDLED.store(true, std::sync::atomic::Ordering::Release);
+    IS_NEW_PHASH_ARRIVED.store(false, std::sync::atomic::Ordering::Release);
+    // A requested reconnection is served by this connection
+    RECONNECT_TEMPLATE_PROVIDER.store(false, std::sync::atomic::Ordering::Release);
+
+    let connection = connect_template_provider(&upstream, &downstream).await;
+    if connection.is_some() {
+        ProxyState::update_tp_state(TpState::Up, "connected to the template provider");
+        ProxyState::update_jd_state(JdState::Up, "connected to the JDS");
+        while is_template_provider_up()
+            && !RECONNECT_TEMPLATE_PROVIDER.swap(false, std::sync::atomic::Ordering::AcqRel)
+        {
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+    }
+    drop(connection);
+
+    let reason = match jd_disabled_until() {
+        Some(until) => format!(
+            "repeated job declaration rejections, JD will be enabled again in {:?}",
+            until.saturating_duration_since(tokio::time::Instant::now())
+        ),
+        None => "the template provider or the JDS is not available".to_string(),
+    };
+    switch_to_pool_jobs(&upstream, &downstream, &reason).await;
+}
+
+/// Connects the JDS and the first reachable template provider by priority. Returns None,
+/// with the state of the failed component set to down, if one of them is not available.
+async fn connect_template_provider(
+    upstream: &Arc<Mutex<mining_upstream::Upstream>>,
+    downstream: &Arc<Mutex<DownstreamMiningNode>>,
+) -> Option<AbortOnDrop> {
+    let test_only_do_not_send_solution_to_tp = false;
+
+    let tp_address = match select_template_provider().await {
+        Some(tp_address) => tp_address,
+        None => {
+            eprintln!("No TP is reachable, the proxy is in not in JD mode");
+            ProxyState::update_tp_state(TpState::Down, "No TP is reachable");
+            return None;
+        }
+    };
+    if crate::TP_ADDRESS
+        .safe_lock(|tp| *tp = Some(tp_address.clone()))
+        .is_err()
+    {
+        error!("TP_ADDRESS mutex corrupt");
+        ProxyState::update_inconsistency(Some(1), "TP_ADDRESS mutex corrupt");
+        return None;
+    };
+
+    let mut parts = tp_address.split(':');
+    let ip_tp = parts.next().expect("The passed value for TP address is not valid. Terminating.... TP_ADDRESS should be in this format `127.0.0.1:8442`").to_string();
+    let port_tp = parts.next().expect("The passed value for TP address is not valid. Terminating.... TP_ADDRESS should be in this format `127.0.0.1:8442`").parse::<u16>().expect("This operation should not fail because a valid port_tp should always be converted to U16");
+
+    // The JDS can be served by a different endpoint and authority than the mining pool
+    let auth_pub_k: Secp256k1PublicKey = Configuration::jds_authority_public_key()
+        .unwrap_or_else(|| crate::AUTH_PUB_KEY.parse().expect("Invalid public key"));
+    let pool_address = match crate::ACTIVE_POOL_ADDRESS.safe_lock(|address| *address) {
+        Ok(Some(address)) => address,
+        Ok(None) => {
+            error!("Pool address is missing");
+            ProxyState::update_inconsistency(Some(1), "Pool address is missing");
+            return None;
+        }
+        Err(e) => {
+            error!("Pool address mutex is poisoned: {e:?}");
+            ProxyState::update_inconsistency(
+                Some(1),
+                &format!("Pool address mutex is poisoned: {e:?}"),
+            );
+            return None;
+        }
+    };
+    let address = Configuration::jds_address().unwrap_or(pool_address);
+
+    let (jd, mut abortable) =
+        match JobDeclarator::new(address, auth_pub_k.into_bytes(), upstream.clone(), true).await {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to intialize Jd: {e}");
+                ProxyState::update_jd_state(JdState::Down, &format!("Failed to intialize Jd: {e}"));
+                return None;
+            }
+        };
+
+    // When Downstream receive a share that meets bitcoin target it transformit in a
+    // SubmitSolution and send it to the TemplateReceiver
+    let (send_solution, recv_solution) = tokio::sync::mpsc::channel(10);
+    if downstream
+        .safe_lock(|d| d.set_template_provider(send_solution, Some(jd.clone())))
+        .is_err()
+    {
+        error!("Downstream mutex failed");
+        ProxyState::update_downstream_state(
+            DownstreamType::JdClientMiningDownstream,
+            "Downstream mutex failed",
+        );
+        return None;
+    }
+
     let ip = IpAddr::from_str(ip_tp.as_str())
         .expect("Infallable Operation: Failed tp can always be converted into IpAddr");
+    let tp_authority_public_key = Configuration::tp_authority_public_key(&tp_address);
     let tp_abortable = match TemplateRx::connect(
         SocketAddr::new(ip, port_tp),
         recv_solution,
-        Some(jd.clone()),
-        donwstream.clone(),
+        Some(jd),
+        downstream.clone(),
         vec![],
-        None,
+        tp_authority_public_key,
         test_only_do_not_send_solution_to_tp,
     )
     .await
     {
         Ok(abortable) => abortable,
-        Err(_) => {
-            info!("Dropping jd abortable");
-            eprintln!("TP is unreachable, the proxy is in not in JD mode");
-            drop(abortable);
-            // Temporaily set TP_ADDRESS to None so that proxy can restart without it.
-            // that means we will start mining without jd
-            if crate::TP_ADDRESS.safe_lock(|tp| *tp = None).is_err() {
-                error!("TP_ADDRESS mutex corrupt");
-                return None;
-            };
-            tokio::spawn(retry_connection(tp_address));
+        Err(e) => {
+            // The other configured TPs, if any, are tried first on the next connection
+            fail_over_template_provider(&tp_address);
+            ProxyState::update_tp_state(
+                TpState::Down,
+                &format!("Failed to connect to TP {}: {}", tp_address, e),
+            );
             return None;
         }
     };
-
-    if TaskManager::add_template_receiver_task(task_manager, tp_abortable)
-        .await
-        .is_err()
-    {
-        error!(
-            "Task manager failed while trying to add template receiver task{}",
-            error::Error::TaskManagerFailed
-        );
-        drop(abortable);
-        return None;
-    };
+    abortable.merge(tp_abortable);
+    if tp_authority_public_key.is_none() {
+        trust_on_first_use(&tp_address);
+    }
+    // The TPs are tried in priority order again on the next connection
+    if LAST_FAILED_TP.safe_lock(|tp| *tp = None).is_err() {
+        error!("LAST_FAILED_TP mutex corrupt");
+        ProxyState::update_inconsistency(Some(1), "LAST_FAILED_TP mutex corrupt");
+    }
     Some(abortable)
 }
 
-// Used when tp is down or connection was unsuccessful to retry connection.
-async fn retry_connection(address: String) {
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
-    loop {
-        info!("TP Retrying connection....");
-        interval.tick().await;
-        if tokio::net::TcpStream::connect(address.clone())
+/// Reconnects the template provider and the JDS, starting from the first TP by priority.
+pub fn reconnect_template_provider() {
+    if LAST_FAILED_TP.safe_lock(|tp| *tp = None).is_err() {
+        error!("LAST_FAILED_TP mutex corrupt");
+        ProxyState::update_inconsistency(Some(1), "LAST_FAILED_TP mutex corrupt");
+    }
+    RECONNECT_TEMPLATE_PROVIDER.store(true, std::sync::atomic::Ordering::Release);
+}
+
+fn is_template_provider_up() -> bool {
+    ProxyState::get_state().is_ok_and(|state| state.tp == TpState::Up && state.jd == JdState::Up)
+}
+
+/// Makes the miners work on the last pool job, in place of the declared jobs. The shares are then
+/// relayed to the pool as they are, until the template provider sends a new prev hash again.
+async fn switch_to_pool_jobs(
+    upstream: &Arc<Mutex<mining_upstream::Upstream>>,
+    downstream: &Arc<Mutex<DownstreamMiningNode>>,
+    reason: &str,
+) {
+    if downstream.safe_lock(|d| d.jd = None).is_err() {
+        error!("Downstream mutex failed");
+        ProxyState::update_downstream_state(
+            DownstreamType::JdClientMiningDownstream,
+            "Downstream mutex failed",
+        );
+        return;
+    }
+    crate::jobs::clear_template();
+    if IS_ON_POOL_JOBS.swap(true, std::sync::atomic::Ordering::AcqRel) {
+        return;
+    }
+    warn!("Mining on pool jobs: {}", reason);
+    events::publish(EventKind::JdFallback {
+        reason: reason.to_string(),
+    });
+    let messages = match upstream.safe_lock(|u| u.last_pool_job_messages()) {
+        Ok(messages) => messages.unwrap_or_default(),
+        Err(e) => {
+            error!("Upstream mutex failed: {e}");
+            ProxyState::update_upstream_state(
+                UpstreamType::JDCMiningUpstream,
+                &format!("Upstream mutex failed: {e}"),
+            );
+            return;
+        }
+    };
+    for message in messages {
+        if DownstreamMiningNode::send(downstream, message)
             .await
-            .is_ok()
+            .is_err()
         {
-            info!("Successfully reconnected to TP: Restarting Proxy...");
-            if crate::TP_ADDRESS
-                .safe_lock(|tp| *tp = Some(address))
-                .is_err()
-            {
-                error!("TP_ADDRESS Mutex failed");
-                std::process::exit(1);
-            };
-            // This force the proxy to restart. If we use Up the proxy just ignore it.
-            // So updating it to Down and setting the TP_ADDRESS to Some(address) will make the
-            // proxy restart with TP, the the TpState will be set to Up.
-            ProxyState::update_tp_state(TpState::Down);
-            break;
+            error!("Failed to send the pool job downstream");
+            ProxyState::update_downstream_state(
+                DownstreamType::JdClientMiningDownstream,
+                "Failed to send the pool job downstream",
+            );
+            return;
         }
     }
 }
+
+/// When JD is enabled again, if it is disabled
+fn jd_disabled_until() -> Option<tokio::time::Instant> {
+    JD_ENABLED_SINCE
+        .safe_lock(|since| *since)
+        .unwrap_or(None)
+        .filter(|since| *since > tokio::time::Instant::now())
+}
+
+/// Configured template providers by priority, the last one that failed is tried last.
+fn template_providers() -> Vec<String> {
+    let failed = LAST_FAILED_TP.safe_lock(|tp| tp.clone()).unwrap_or(None);
+    let (mut addresses, failed): (Vec<_>, Vec<_>) = Configuration::tp_addresses()
+        .into_iter()
+        .partition(|address| Some(address) != failed.as_ref());
+    addresses.extend(failed);
+    addresses
+}
+
+async fn is_reachable(address: &str) -> bool {
+    matches!(
+        tokio::time::timeout(TP_CONNECT_TIMEOUT, tokio::net::TcpStream::connect(address)).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Returns the first reachable template provider in priority order.
+async fn select_template_provider() -> Option<String> {
+    for address in template_providers() {
+        if is_reachable(&address).await {
+            return Some(address);
+        }
+        warn!("TP {} is unreachable", address);
+    }
+    None
+}
+
+/// Warns the first time that a template provider without a pinned authority key is trusted, the
+/// handshake does not expose the key of the TP so it can only be pinned from the TP config.
+fn trust_on_first_use(tp_address: &str) {
+    match TRUSTED_TPS.safe_lock(|trusted| trusted.insert(tp_address.to_string())) {
+        Ok(true) => warn!(
+            "Trusting TP {} on first use, its identity is not verified and its key can not be \
+            logged. Pin its authority key from the TP config with \
+            --tp-authority-public-key={}=<key>",
+            tp_address, tp_address
+        ),
+        Ok(false) => debug!("TP {} has no pinned authority key", tp_address),
+        Err(e) => {
+            error!("TRUSTED_TPS mutex corrupt: {e}");
+            ProxyState::update_inconsistency(Some(1), &format!("TRUSTED_TPS mutex corrupt: {e}"));
+        }
+    }
+}
+
+/// Makes `failed` the last template provider tried on the next connection.
+fn fail_over_template_provider(failed: &str) {
+    if Configuration::tp_addresses().len() > 1 {
+        warn!("TP {} failed, failing over to the other TPs", failed);
+    }
+    if LAST_FAILED_TP
+        .safe_lock(|tp| *tp = Some(failed.to_string()))
+        .is_err()
+    {
+        error!("LAST_FAILED_TP mutex corrupt");
+        ProxyState::update_inconsistency(Some(1), "LAST_FAILED_TP mutex corrupt");
+    }
+}
+
+/// Disables JD after repeated job declaration rejections: miners are switched to the pool jobs
+/// and JD is enabled again after a backoff. When JD worked for long enough since it was last
+/// enabled the backoff starts over.
+pub(crate) fn fall_back_to_pool_jobs() {
+    let now = tokio::time::Instant::now();
+    let result = JD_ENABLED_SINCE.safe_lock(|since| {
+        let jd_uptime = now.saturating_duration_since(since.unwrap_or(now));
+        let delay = JD_FALLBACK_BACKOFF
+            .safe_lock(|backoff| backoff.next_delay(jd_uptime))
+            .unwrap_or(JD_FALLBACK_MAX_DELAY);
+        *since = Some(now + delay);
+        delay
+    });
+    let delay = match result {
+        Ok(delay) => delay,
+        Err(_) => {
+            error!("JD_ENABLED_SINCE mutex corrupt");
+            ProxyState::update_inconsistency(Some(1), "JD_ENABLED_SINCE mutex corrupt");
+            return;
+        }
+    };
+    warn!("JD will be enabled again in {:?}", delay);
+    ProxyState::update_jd_state(
+        JdState::Down,
+        &format!(
+            "repeated job declaration rejections, JD will be enabled again in {:?}",
+            delay
+        ),
+    );
+}
@@ -8,8 +8,7 @@ use tracing::debug;
 #[derive(Debug)]
 #[allow(dead_code)]
 enum Task {
-    JobDeclarator(AbortOnDrop),
-    TemplateReceiver(AbortOnDrop),
+    TemplateProvider(AbortOnDrop),
     MiningUpstream(AbortOnDrop),
     MiningDowntream(AbortOnDrop),
 }
@@ -44,23 +43,13 @@ impl TaskManager {
         self.abort.take()
     }
 
-    pub async fn add_job_declarator_task(
+    pub async fn add_template_provider_task(
         self_: Arc<Mutex<Self>>,
         abortable: AbortOnDrop,
     ) -> Result<(), ()> {
         let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
         send_task
-            .send(Task::JobDeclarator(abortable))
-            .await
-            .map_err(|_| ())
-    }
-    pub async fn add_template_receiver_task(
-        self_: Arc<Mutex<Self>>,
-        abortable: AbortOnDrop,
-    ) -> Result<(), ()> {
-        let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
-        send_task
-            .send(Task::TemplateReceiver(abortable))
+            .send(Task::TemplateProvider(abortable))
             .await
             .map_err(|_| ())
     }
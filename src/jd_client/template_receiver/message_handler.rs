@@ -8,6 +8,7 @@ use roles_logic_sv2::{
 
 impl ParseServerTemplateDistributionMessages for TemplateRx {
     fn handle_new_template(&mut self, m: NewTemplate) -> Result<SendTo, Error> {
+        self.last_new_template = tokio::time::Instant::now();
         let new_template = m.into_static();
         let new_template = TemplateDistribution::NewTemplate(new_template);
         Ok(SendTo::None(Some(new_template)))
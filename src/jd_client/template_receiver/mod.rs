@@ -2,11 +2,16 @@ mod task_manager;
 use crate::proxy_state::{DownstreamType, JdState, TpState};
 use crate::shared::utils::AbortOnDrop;
 use crate::{
-    jd_client::mining_downstream::DownstreamMiningNode as Downstream, proxy_state::ProxyState,
+    config::Configuration, jd_client::mining_downstream::DownstreamMiningNode as Downstream,
+    proxy_state::ProxyState,
 };
 
-use super::{error::Error, job_declarator::JobDeclarator};
-use bitcoin::{consensus::Encodable, TxOut};
+use super::{
+    block_submitter::{self, SubmissionResult},
+    error::Error,
+    job_declarator::{tx_policy, JobDeclarator},
+};
+use bitcoin::{consensus::Encodable, BlockHash, TxOut};
 use codec_sv2::{HandshakeRole, Initiator, StandardEitherFrame, StandardSv2Frame};
 use demand_sv2_connection::noise_connection_tokio::Connection;
 use key_utils::Secp256k1PublicKey;
@@ -15,19 +20,26 @@ use roles_logic_sv2::{
     job_declaration_sv2::AllocateMiningJobTokenSuccess,
     parsers::{PoolMessages, TemplateDistribution},
     template_distribution_sv2::{
-        CoinbaseOutputDataSize, NewTemplate, RequestTransactionData, SubmitSolution,
+        CoinbaseOutputDataSize, NewTemplate, RequestTransactionData, RequestTransactionDataSuccess,
+        SubmitSolution,
     },
     utils::Mutex,
 };
 use setup_connection::SetupConnectionHandler;
-use std::{convert::TryInto, net::SocketAddr, sync::Arc};
+use std::{collections::VecDeque, convert::TryInto, net::SocketAddr, sync::Arc};
 use task_manager::TaskManager;
-use tokio::sync::mpsc::{Receiver as TReceiver, Sender as TSender};
+use tokio::{
+    sync::mpsc::{Receiver as TReceiver, Sender as TSender},
+    time::{Duration, Instant},
+};
 use tracing::{error, info, warn};
 
 mod message_handler;
 mod setup_connection;
 
+/// How often the age of the last template is checked
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 pub type SendTo = SendTo_<roles_logic_sv2::parsers::TemplateDistribution<'static>, ()>;
 pub type Message = PoolMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
@@ -40,6 +52,8 @@ pub struct TemplateRx {
     jd: Option<Arc<Mutex<super::job_declarator::JobDeclarator>>>,
     down: Arc<Mutex<Downstream>>,
     new_template_message: Option<NewTemplate<'static>>,
+    // Used to detect a TP that is connected but stopped sending templates
+    last_new_template: Instant,
     miner_coinbase_output: Vec<u8>,
     test_only_do_not_send_solution_to_tp: bool,
 }
@@ -48,7 +62,7 @@ impl TemplateRx {
     #[allow(clippy::too_many_arguments)]
     pub async fn connect(
         address: SocketAddr,
-        solution_receiver: TReceiver<SubmitSolution<'static>>,
+        solution_receiver: TReceiver<(SubmitSolution<'static>, Option<BlockHash>)>,
         jd: Option<Arc<Mutex<super::job_declarator::JobDeclarator>>>,
         down: Arc<Mutex<Downstream>>,
         miner_coinbase_outputs: Vec<TxOut>,
@@ -63,6 +77,7 @@ impl TemplateRx {
             .await
             .map_err(Error::Io)?;
 
+        let verify_tp_key = authority_public_key.is_some();
         let initiator = match authority_public_key {
             Some(pub_key) => Initiator::from_raw_k(pub_key.into_bytes()),
             None => Initiator::without_pk(),
@@ -82,6 +97,13 @@ impl TemplateRx {
                     (receiver, sender, abortable, aborthandle)
                 }
                 Err(_) => {
+                    if verify_tp_key {
+                        error!(
+                            "Noise handshake with TP {} failed, check that the TP authority \
+                            public key is correct",
+                            address
+                        );
+                    }
                     error!("Impossible to connect to TP, wait a few seconds and retry");
                     return Err(Error::Unrecoverable);
                 }
@@ -100,6 +122,7 @@ impl TemplateRx {
             jd,
             down,
             new_template_message: None,
+            last_new_template: Instant::now(),
             miner_coinbase_output: encoded_outputs,
             test_only_do_not_send_solution_to_tp,
         }));
@@ -115,6 +138,10 @@ impl TemplateRx {
         TaskManager::add_on_new_solution(task_manager.clone(), on_new_solution_task.into())
             .await
             .map_err(|_| Error::TemplateRxTaskManagerFailed)?;
+        let stale_watchdog_task = tokio::task::spawn(Self::stale_watchdog(self_mutex.clone()));
+        TaskManager::add_stale_watchdog(task_manager.clone(), stale_watchdog_task.into())
+            .await
+            .map_err(|_| Error::TemplateRxTaskManagerFailed)?;
         let main_task = match Self::start_templates(self_mutex, receiver).await {
             Ok(main_task) => main_task,
             Err(e) => return Err(e),
@@ -126,22 +153,56 @@ impl TemplateRx {
         Ok(abortable)
     }
 
-    pub async fn send(self_: &Arc<Mutex<Self>>, sv2_frame: StdFrame) {
+    /// Marks the TP as down when it does not send a `NewTemplate` for longer than the configured
+    /// timeout. The TP is moved after the others, so the next one by priority is connected.
+    async fn stale_watchdog(self_mutex: Arc<Mutex<Self>>) {
+        loop {
+            tokio::time::sleep(STALE_CHECK_INTERVAL).await;
+            let last_new_template = match self_mutex.safe_lock(|t| t.last_new_template) {
+                Ok(last_new_template) => last_new_template,
+                Err(e) => {
+                    error!("TemplateRx mutex poisoned: {e}");
+                    ProxyState::update_tp_state(
+                        TpState::Down,
+                        &format!("TemplateRx mutex poisoned: {e}"),
+                    );
+                    break;
+                }
+            };
+            let timeout = Configuration::tp_stale_timeout();
+            if let Some(silent) = stale_for(last_new_template, Instant::now(), timeout) {
+                error!("No template received from TP in {:?}, TP is stale", silent);
+                if let Ok(Some(address)) = crate::TP_ADDRESS.safe_lock(|tp| tp.clone()) {
+                    super::fail_over_template_provider(&address);
+                }
+                ProxyState::update_tp_state(
+                    TpState::Down,
+                    &format!("No template received from TP in {:?}", silent),
+                );
+                break;
+            }
+        }
+    }
+
+    /// Sends a frame to the TP, returns false if the TP connection is down.
+    pub async fn send(self_: &Arc<Mutex<Self>>, sv2_frame: StdFrame) -> bool {
         let either_frame = sv2_frame.into();
         let sender_to_tp = match self_.safe_lock(|self_| self_.sender.clone()) {
             Ok(sender_to_tp) => sender_to_tp,
             Err(e) => {
                 // Update global tp state to down
                 error!("{e}");
-                ProxyState::update_tp_state(TpState::Down);
-                return;
+                ProxyState::update_tp_state(TpState::Down, &e.to_string());
+                return false;
             }
         };
         if sender_to_tp.send(either_frame).await.is_err() {
             error!("Failed to send msg to tp");
             // Update global tp state to down
-            ProxyState::update_tp_state(TpState::Down);
+            ProxyState::update_tp_state(TpState::Down, "Failed to send msg to tp");
+            return false;
         }
+        true
     }
 
     pub async fn send_max_coinbase_size(self_mutex: &Arc<Mutex<Self>>, size: u32) {
@@ -166,7 +227,88 @@ impl TemplateRx {
         );
         let frame: StdFrame =  tx_data_request.try_into().expect("Internal error: this operation can not fail because PoolMessages::TemplateDistribution can always be converted into StdFrame");
 
-        Self::send(self_mutex, frame).await
+        Self::send(self_mutex, frame).await;
+    }
+
+    /// Requests the transactions of the template to the TP. With a tx policy the answer is
+    /// awaited here so that the template is filtered before miners get its job: the answer is
+    /// then queued with the filtered transactions, after the frames received meanwhile, and the
+    /// filtered template is returned. Returns None when the TP does not have the template anymore.
+    async fn request_tx_data(
+        self_mutex: &Arc<Mutex<Self>>,
+        receiver: &mut TReceiver<EitherFrame>,
+        pending_frames: &mut VecDeque<EitherFrame>,
+        new_template: NewTemplate<'static>,
+    ) -> Option<NewTemplate<'static>> {
+        Self::send_tx_data_request(self_mutex, new_template.clone()).await;
+        let Some(policy) = tx_policy::current() else {
+            return Some(new_template);
+        };
+        let template_id = new_template.template_id;
+        loop {
+            let mut frame: StdFrame = match receiver.recv().await.map(|frame| frame.try_into()) {
+                Some(Ok(frame)) => frame,
+                Some(Err(_)) => {
+                    error!("Failed to covert TP message to StdFrame");
+                    continue;
+                }
+                None => {
+                    error!("Failed to receive msg");
+                    ProxyState::update_tp_state(TpState::Down, "Failed to receive msg");
+                    return None;
+                }
+            };
+            let Some(message_type) = frame.get_header().map(|header| header.msg_type()) else {
+                pending_frames.push_back(frame.into());
+                continue;
+            };
+            let answer = match TemplateDistribution::try_from((message_type, frame.payload())) {
+                Ok(TemplateDistribution::RequestTransactionDataSuccess(m))
+                    if m.template_id == template_id =>
+                {
+                    Some(Ok((
+                        m.transaction_list.into_static(),
+                        m.excess_data.into_static(),
+                    )))
+                }
+                Ok(TemplateDistribution::RequestTransactionDataError(m))
+                    if m.template_id == template_id =>
+                {
+                    Some(Err(
+                        String::from_utf8_lossy(m.error_code.as_ref()).to_string()
+                    ))
+                }
+                _ => None,
+            };
+            match answer {
+                Some(Ok((transaction_list, excess_data))) => {
+                    let (new_template, transaction_list) =
+                        tx_policy::apply(&policy, new_template, transaction_list).await;
+                    let success: StdFrame = PoolMessages::TemplateDistribution(
+                        TemplateDistribution::RequestTransactionDataSuccess(
+                            RequestTransactionDataSuccess {
+                                template_id,
+                                excess_data,
+                                transaction_list,
+                            },
+                        ),
+                    )
+                    .try_into()
+                    .expect("Internal error: this operation can not fail because PoolMessages::TemplateDistribution can always be converted into StdFrame");
+                    pending_frames.push_back(success.into());
+                    return Some(new_template);
+                }
+                Some(Err(error_code)) => {
+                    warn!(
+                        "Template {} is no longer valid for the TP ({}), continuing work on the \
+                        updated template",
+                        template_id, error_code
+                    );
+                    return None;
+                }
+                None => pending_frames.push_back(frame.into()),
+            }
+        }
     }
 
     async fn get_last_token(
@@ -205,6 +347,8 @@ impl TemplateRx {
             .map_err(|_| Error::JdClientDownstreamMutexCorrupted)?;
         let mut coinbase_output_max_additional_size_sent = false;
         let mut last_token = None;
+        // Frames received while the transactions of a template are awaited
+        let mut pending_frames = VecDeque::new();
         let miner_coinbase_output = self_mutex
             .safe_lock(|s| s.miner_coinbase_output.clone())
             .map_err(|_| Error::TemplateRxMutexCorrupted)?;
@@ -219,7 +363,10 @@ impl TemplateRx {
                             Ok(jd) => jd,
                             Err(_) => {
                                 error!("Job declarator mutex poisoned!");
-                                ProxyState::update_jd_state(JdState::Down);
+                                ProxyState::update_jd_state(
+                                    JdState::Down,
+                                    "Job declarator mutex poisoned!",
+                                );
                                 break;
                             }
                         };
@@ -241,7 +388,11 @@ impl TemplateRx {
                         .await;
                     }
 
-                    match receiver.recv().await {
+                    let received = match pending_frames.pop_front() {
+                        Some(frame) => Some(frame),
+                        None => receiver.recv().await,
+                    };
+                    match received {
                         Some(received) => {
                             let frame: Result<StdFrame, _> = received.try_into();
                             if let Ok(mut frame) = frame {
@@ -250,7 +401,10 @@ impl TemplateRx {
                                     None => {
                                         error!("Msg header not found");
                                         // Update global tp state to down
-                                        ProxyState::update_tp_state(TpState::Down);
+                                        ProxyState::update_tp_state(
+                                            TpState::Down,
+                                            "Msg header not found",
+                                        );
                                         break;
                                     }
                                 };
@@ -268,6 +422,7 @@ impl TemplateRx {
                                             // Send the new template along with the token to the JD so that JD can
                                             // declare the mining job
                                             Some(TemplateDistribution::NewTemplate(m)) => {
+                                                crate::jobs::new_template(&m);
                                                 let new_phash = super::IS_NEW_PHASH_ARRIVED
                                                     .load(std::sync::atomic::Ordering::Acquire);
                                                 let last_is_future = match self_mutex
@@ -280,7 +435,12 @@ impl TemplateRx {
                                                     Err(e) => {
                                                         // Update global tp state to down
                                                         error!("TemplateRx mutex poisoned: {e}");
-                                                        ProxyState::update_tp_state(TpState::Down);
+                                                        ProxyState::update_tp_state(
+                                                            TpState::Down,
+                                                            &format!(
+                                                                "TemplateRx mutex poisoned: {e}"
+                                                            ),
+                                                        );
                                                         break;
                                                     }
                                                 };
@@ -310,6 +470,17 @@ impl TemplateRx {
                                                     println!(
                                                         "wait_for_last_template_to_be_completed"
                                                     );
+                                                    let m = match Self::request_tx_data(
+                                                        &self_mutex,
+                                                        &mut receiver,
+                                                        &mut pending_frames,
+                                                        m,
+                                                    )
+                                                    .await
+                                                    {
+                                                        Some(m) => m,
+                                                        None => continue,
+                                                    };
                                                     if new_phash {
                                                         super::IS_NEW_PHASH_ARRIVED.store(
                                                             false,
@@ -322,11 +493,6 @@ impl TemplateRx {
                                                         false,
                                                         std::sync::atomic::Ordering::Release,
                                                     );
-                                                    Self::send_tx_data_request(
-                                                        &self_mutex,
-                                                        m.clone(),
-                                                    )
-                                                    .await;
                                                     if self_mutex
                                                         .safe_lock(|t| {
                                                             t.new_template_message = Some(m.clone())
@@ -335,7 +501,10 @@ impl TemplateRx {
                                                     {
                                                         error!("TemplateRx Mutex is corrupt");
                                                         // Update global tp state to down
-                                                        ProxyState::update_tp_state(TpState::Down);
+                                                        ProxyState::update_tp_state(
+                                                            TpState::Down,
+                                                            "TemplateRx Mutex is corrupt",
+                                                        );
                                                         break;
                                                     };
 
@@ -357,6 +526,7 @@ impl TemplateRx {
                                                         // Update global downstream state to down
                                                         ProxyState::update_downstream_state(
                                                             DownstreamType::JdClientMiningDownstream,
+                                                            &format!("{e:?}"),
                                                         );
                                                     };
                                                 } else if go_to_next_template {
@@ -372,6 +542,17 @@ impl TemplateRx {
                                                     //   any global
                                                     continue;
                                                 } else if discard_last_and_use_this {
+                                                    let m = match Self::request_tx_data(
+                                                        &self_mutex,
+                                                        &mut receiver,
+                                                        &mut pending_frames,
+                                                        m,
+                                                    )
+                                                    .await
+                                                    {
+                                                        Some(m) => m,
+                                                        None => continue,
+                                                    };
                                                     if new_phash {
                                                         super::IS_NEW_PHASH_ARRIVED.store(
                                                             false,
@@ -393,11 +574,6 @@ impl TemplateRx {
                                                         false,
                                                         std::sync::atomic::Ordering::Release,
                                                     );
-                                                    Self::send_tx_data_request(
-                                                        &self_mutex,
-                                                        m.clone(),
-                                                    )
-                                                    .await;
                                                     if self_mutex
                                                         .safe_lock(|t| {
                                                             t.new_template_message = Some(m.clone())
@@ -406,7 +582,10 @@ impl TemplateRx {
                                                     {
                                                         error!("TemplateRx Mutex is corrupt");
                                                         // Update global tp state to down
-                                                        ProxyState::update_tp_state(TpState::Down);
+                                                        ProxyState::update_tp_state(
+                                                            TpState::Down,
+                                                            "TemplateRx Mutex is corrupt",
+                                                        );
                                                         break;
                                                     };
 
@@ -428,6 +607,7 @@ impl TemplateRx {
                                                         // Update global downstream state to down
                                                         ProxyState::update_downstream_state(
                                                             DownstreamType::JdClientMiningDownstream,
+                                                            &format!("{e:?}"),
                                                         );
                                                     };
                                                 } else {
@@ -462,7 +642,7 @@ impl TemplateRx {
                                                 m.clone(),
                                             ).await {
                                                 error!("{e:?}");
-                                                ProxyState::update_jd_state(JdState::Down); break;
+                                                ProxyState::update_jd_state(JdState::Down, &format!("{e:?}")); break;
                                             };
                                                 }
                                                 if let Err(e) =
@@ -470,7 +650,10 @@ impl TemplateRx {
                                                 {
                                                     error!("SetNewPrevHash Error: {e:?}");
                                                     // Update global tp state to down
-                                                    ProxyState::update_tp_state(TpState::Down);
+                                                    ProxyState::update_tp_state(
+                                                        TpState::Down,
+                                                        &format!("SetNewPrevHash Error: {e:?}"),
+                                                    );
                                                     break;
                                                 };
                                             }
@@ -518,11 +701,14 @@ impl TemplateRx {
                                                             )
                                                             .await {
                                                                 error!("{e:?}");
-                                                                ProxyState::update_downstream_state(DownstreamType::JdClientMiningDownstream);
+                                                                ProxyState::update_downstream_state(DownstreamType::JdClientMiningDownstream, &format!("{e:?}"));
                                                             };
                                                         }
                                                     } else {
-                                                        ProxyState::update_tp_state(TpState::Down)
+                                                        ProxyState::update_tp_state(
+                                                            TpState::Down,
+                                                            "RequestTransactionDataSuccess received without a template",
+                                                        )
                                                     };
                                                 });
                                                 erase_last_token_rx.await.unwrap();
@@ -569,13 +755,16 @@ impl TemplateRx {
                             } else {
                                 error!("Failed to covert TP message to StdFrame");
                                 // Update global tp state to down
-                                ProxyState::update_tp_state(TpState::Down);
+                                ProxyState::update_tp_state(
+                                    TpState::Down,
+                                    "Failed to covert TP message to StdFrame",
+                                );
                             }
                         }
 
                         None => {
                             error!("Failed to receive msg");
-                            ProxyState::update_tp_state(TpState::Down);
+                            ProxyState::update_tp_state(TpState::Down, "Failed to receive msg");
                             break;
                         }
                     };
@@ -585,27 +774,68 @@ impl TemplateRx {
         Ok(main_task.into())
     }
 
-    async fn on_new_solution(self_: Arc<Mutex<Self>>, mut rx: TReceiver<SubmitSolution<'static>>) {
-        while let Some(solution) = rx.recv().await {
+    async fn on_new_solution(
+        self_: Arc<Mutex<Self>>,
+        mut rx: TReceiver<(SubmitSolution<'static>, Option<BlockHash>)>,
+    ) {
+        while let Some((solution, block_hash)) = rx.recv().await {
             let test_only = match self_.safe_lock(|s| s.test_only_do_not_send_solution_to_tp) {
                 Ok(test_only) => test_only,
                 Err(e) => {
                     error!("{e:?}");
                     // TemplateRx mutex poisoned
                     // Update global tp state to down
-                    ProxyState::update_tp_state(TpState::Down);
+                    ProxyState::update_tp_state(TpState::Down, &format!("{e:?}"));
                     return;
                 }
             };
 
             if !test_only {
+                let template_id = solution.template_id;
                 let sv2_frame: StdFrame = PoolMessages::TemplateDistribution(
                     TemplateDistribution::SubmitSolution(solution),
                 )
                 .try_into()
                 .expect("Internal error: this operation can not fail because PoolMessages::TemplateDistribution can always be converted into StdFrame");
-                Self::send(&self_, sv2_frame).await;
+                let result = if Self::send(&self_, sv2_frame).await {
+                    SubmissionResult::Sent
+                } else {
+                    SubmissionResult::Failed {
+                        error: "TP connection down".to_string(),
+                    }
+                };
+                block_submitter::report(Some(template_id), block_hash, "tp", result);
             }
         }
     }
 }
+
+/// Returns for how long the TP has been silent, if it is longer than `timeout`.
+fn stale_for(last_new_template: Instant, now: Instant, timeout: Duration) -> Option<Duration> {
+    let silent = now.saturating_duration_since(last_new_template);
+    (silent > timeout).then_some(silent)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn a_silent_tp_is_stale() {
+        let timeout = Duration::from_secs(120);
+        let last_new_template = Instant::now();
+        assert_eq!(
+            stale_for(last_new_template, last_new_template, timeout),
+            None
+        );
+        assert_eq!(
+            stale_for(last_new_template, last_new_template + timeout, timeout),
+            None
+        );
+        let silent = timeout + Duration::from_secs(1);
+        assert_eq!(
+            stale_for(last_new_template, last_new_template + silent, timeout),
+            Some(silent)
+        );
+    }
+}
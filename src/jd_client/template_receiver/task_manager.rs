@@ -10,6 +10,7 @@ use tracing::warn;
 enum Task {
     OnNewSolution(AbortOnDrop),
     MainTask(AbortOnDrop),
+    StaleWatchdog(AbortOnDrop),
 }
 
 #[derive(Debug)]
@@ -52,6 +53,16 @@ impl TaskManager {
             .await
             .map_err(|_| ())
     }
+    pub async fn add_stale_watchdog(
+        self_: Arc<Mutex<Self>>,
+        abortable: AbortOnDrop,
+    ) -> Result<(), ()> {
+        let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
+        send_task
+            .send(Task::StaleWatchdog(abortable))
+            .await
+            .map_err(|_| ())
+    }
     pub async fn add_main_task(self_: Arc<Mutex<Self>>, abortable: AbortOnDrop) -> Result<(), ()> {
         let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
         send_task
@@ -0,0 +1,120 @@
+//! Job the miners are currently working on.
+//!
+//! The last job and prev hash sent by the upstream, and the last template received from the TP
+//! when JD is enabled, are kept for `/api/jobs/current` so that the dashboard can show them as
+//! soon as it is opened instead of waiting for the next `new_job` event.
+
+use lazy_static::lazy_static;
+use roles_logic_sv2::{template_distribution_sv2::NewTemplate, utils::Mutex};
+use serde::Serialize;
+use tracing::error;
+
+use crate::{
+    blocks::block_height,
+    events::{self, EventKind},
+};
+
+lazy_static! {
+    static ref CURRENT_JOB: Mutex<CurrentJob> = Mutex::new(CurrentJob::default());
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CurrentJob {
+    pub job: Option<Job>,
+    pub prev_hash: Option<PrevHash>,
+    /// None when JD is not enabled
+    pub template: Option<Template>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+    pub job_id: u32,
+    pub future_job: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PrevHash {
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+    pub job_id: u32,
+    pub prev_hash: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Template {
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+    pub template_id: u64,
+    pub future_template: bool,
+    pub height: Option<u64>,
+    pub version: u32,
+    /// Value available to the coinbase outputs, in sats
+    pub coinbase_tx_value_remaining: u64,
+    pub coinbase_tx_outputs_count: u32,
+    pub merkle_path_length: usize,
+}
+
+/// Records a job sent by the upstream and publishes a `NewJob` event
+pub fn new_job(job_id: u32, future_job: bool) {
+    update(|current| {
+        current.job = Some(Job {
+            timestamp: now(),
+            job_id,
+            future_job,
+        })
+    });
+    events::publish(EventKind::NewJob { job_id, future_job });
+}
+
+/// Records a prev hash sent by the upstream and publishes a `NewPrevHash` event
+pub fn new_prev_hash(job_id: u32, prev_hash: String) {
+    update(|current| {
+        current.prev_hash = Some(PrevHash {
+            timestamp: now(),
+            job_id,
+            prev_hash: prev_hash.clone(),
+        })
+    });
+    events::publish(EventKind::NewPrevHash { job_id, prev_hash });
+}
+
+/// Records a template received from the TP
+pub fn new_template(template: &NewTemplate) {
+    let recorded = Template {
+        timestamp: now(),
+        template_id: template.template_id,
+        future_template: template.future_template,
+        height: block_height(&template.coinbase_prefix.to_vec()),
+        version: template.version,
+        coinbase_tx_value_remaining: template.coinbase_tx_value_remaining,
+        coinbase_tx_outputs_count: template.coinbase_tx_outputs_count,
+        merkle_path_length: template.merkle_path.0.len(),
+    };
+    update(|current| current.template = Some(recorded));
+}
+
+/// The template is not current anymore when the proxy stops declaring its own jobs
+pub fn clear_template() {
+    update(|current| current.template = None);
+}
+
+pub fn get_current_job() -> Result<CurrentJob, ()> {
+    CURRENT_JOB
+        .safe_lock(|current| current.clone())
+        .map_err(|_| error!("Current job Mutex Corrupted"))
+}
+
+fn update(f: impl FnOnce(&mut CurrentJob)) {
+    if CURRENT_JOB.safe_lock(f).is_err() {
+        error!("Current job Mutex Corrupted");
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
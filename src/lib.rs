@@ -6,29 +6,44 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+use crate::api::ControlCommand;
 use crate::auto_update::check_update_proxy;
-use crate::shared::utils::AbortOnDrop;
+use crate::shared::{
+    supervisor::{self, Backoff},
+    utils::AbortOnDrop,
+};
 use config::Configuration;
 use key_utils::Secp256k1PublicKey;
 use lazy_static::lazy_static;
-use proxy_state::{PoolState, ProxyState, TpState, TranslatorState};
-use std::sync::OnceLock;
+use proxy_state::{DownstreamType, PoolState, ProxyState, TranslatorState};
+use std::sync::{Once, OnceLock};
 use std::{net::SocketAddr, time::Duration};
-use tokio::sync::mpsc::channel;
+use tokio::sync::{
+    mpsc::{channel, Receiver},
+    watch,
+};
 use tracing::{error, info, warn};
 
 mod api;
 mod auto_update;
+mod blocks;
 mod config;
+mod curtailment;
+mod events;
+mod fallback;
 mod ingress;
 pub mod jd_client;
+mod jobs;
 mod minin_pool_connection;
 mod monitor;
+mod notifier;
+mod outbound;
 mod proxy_state;
 mod router;
 mod share_accounter;
 mod shared;
 mod translator;
+mod upstreams;
 
 const TRANSLATOR_BUFFER_SIZE: usize = 32;
 const MIN_EXTRANONCE_SIZE: u16 = 6;
@@ -44,6 +59,8 @@ const STAGING_URL: &str = "https://staging-user-dashboard-server.dmnd.work";
 const LOCAL_URL: &str = "http://localhost:8787";
 const TESTNET3_URL: &str = "https://testnet3-user-dashboard-server.dmnd.work";
 const PRODUCTION_URL: &str = "https://production-user-dashboard-server.dmnd.work";
+/// Name of the pool connection task, the proxy is reinitialized when it finishes
+const POOL_CONNECTION: &str = "pool_connection";
 
 lazy_static! {
     static ref TP_ADDRESS: roles_logic_sv2::utils::Mutex<Option<String>> =
@@ -71,56 +88,100 @@ lazy_static! {
 
 static LOG_GUARD: OnceLock<tracing_appender::non_blocking::WorkerGuard> = OnceLock::new();
 
-pub async fn start() {
-    let log_level = Configuration::loglevel();
-    let noise_connection_log_level = Configuration::nc_loglevel();
-
-    let enable_file_logging = Configuration::enable_file_logging();
-
-    //Disable noise_connection error (for now) because:
-    // 1. It produce logs that are not very user friendly and also bloat the logs
-    // 2. The errors resulting from noise_connection are handled. E.g if unrecoverable error from
-    //    noise connection occurs during Pool connection: We either retry connecting immediatley or
-    //    we update Proxy state to Pool Down
-
-    let console_layer =
-        tracing_subscriber::fmt::layer().with_filter(tracing_subscriber::EnvFilter::new(format!(
-            "{},demand_sv2_connection::noise_connection_tokio={}",
-            log_level, noise_connection_log_level
-        )));
-
-    if enable_file_logging {
-        let file_appender = tracing_appender::rolling::daily("logs", "dmnd-client.log");
-        let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
-        LOG_GUARD.set(guard).unwrap_or(());
-        let file_layer = tracing_subscriber::fmt::layer()
-            .with_writer(non_blocking)
-            .with_ansi(false) // file logs should not contain color codes
-            .with_filter(tracing_subscriber::EnvFilter::new(format!(
+static INIT_LOGGING: Once = Once::new();
+
+fn init_logging() {
+    INIT_LOGGING.call_once(|| {
+        let log_level = Configuration::loglevel();
+        let noise_connection_log_level = Configuration::nc_loglevel();
+
+        let enable_file_logging = Configuration::enable_file_logging();
+
+        //Disable noise_connection error (for now) because:
+        // 1. It produce logs that are not very user friendly and also bloat the logs
+        // 2. The errors resulting from noise_connection are handled. E.g if unrecoverable error
+        //    from noise connection occurs during Pool connection: We either retry connecting
+        //    immediatley or we update Proxy state to Pool Down
+
+        let console_layer = tracing_subscriber::fmt::layer().with_filter(
+            tracing_subscriber::EnvFilter::new(format!(
                 "{},demand_sv2_connection::noise_connection_tokio={}",
                 log_level, noise_connection_log_level
-            )));
-        tracing_subscriber::registry()
-            .with(console_layer)
-            .with(file_layer)
-            // .with(remote_layer)
-            .init();
-    } else {
-        tracing_subscriber::registry()
-            .with(console_layer)
-            // .with(remote_layer)
-            .init();
+            )),
+        );
+
+        if enable_file_logging {
+            let file_appender = tracing_appender::rolling::daily("logs", "dmnd-client.log");
+            let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+            LOG_GUARD.set(guard).unwrap_or(());
+            let file_layer = tracing_subscriber::fmt::layer()
+                .with_writer(non_blocking)
+                .with_ansi(false) // file logs should not contain color codes
+                .with_filter(tracing_subscriber::EnvFilter::new(format!(
+                    "{},demand_sv2_connection::noise_connection_tokio={}",
+                    log_level, noise_connection_log_level
+                )));
+            tracing_subscriber::registry()
+                .with(console_layer)
+                .with(file_layer)
+                // .with(remote_layer)
+                .init();
+        } else {
+            tracing_subscriber::registry()
+                .with(console_layer)
+                // .with(remote_layer)
+                .init();
+        }
+    });
+}
+
+/// Replaces the running binary with the latest release when auto update is enabled. The updater
+/// reads and sets environment variables, so it must run before the tokio runtime is started: only
+/// then no other thread reads the environment meanwhile.
+pub fn update() {
+    init_logging();
+    auto_update::read_previous_version();
+    if Configuration::auto_update() {
+        check_update_proxy();
+    }
+}
+
+/// Resolves when the proxy is asked to stop, with ctrl-c or SIGTERM.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = sigterm.recv() => (),
+                }
+                return;
+            }
+            Err(e) => warn!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Failed to listen for ctrl-c: {}", e);
+        std::future::pending::<()>().await;
     }
+}
+
+/// Writes what is only kept in memory. Called once the runtime is stopped, so that nothing is
+/// recorded meanwhile.
+pub fn shutdown() {
+    info!("Shutting down");
+    jd_client::job_declarator::audit::flush();
+}
+
+pub async fn start() {
+    init_logging();
 
     Configuration::token().expect("TOKEN is not set");
 
-    //`self_update` performs synchronous I/O so spawn_blocking is needed
-    if Configuration::auto_update() {
-        if let Err(e) = tokio::task::spawn_blocking(check_update_proxy).await {
-            error!("An error occured while trying to update Proxy; {:?}", e);
-            ProxyState::update_inconsistency(Some(1));
-        };
-    }
+    let _notifier = supervisor::supervise("notifier", notifier::run);
+    let _curtailment = supervisor::supervise("curtailment", curtailment::run);
 
     if Configuration::staging() {
         info!("Package is running in staging mode");
@@ -132,18 +193,29 @@ pub async fn start() {
         info!("Package is running in testnet3 mode");
     }
 
-    let auth_pub_k: Secp256k1PublicKey = AUTH_PUB_KEY.parse().expect("Invalid public key");
-
-    let pool_addresses = Configuration::pool_address()
-        .await
-        .filter(|p| !p.is_empty())
-        .unwrap_or_else(|| match Configuration::environment().as_str() {
-            "staging" => panic!("Staging pool address is missing"),
-            "testnet3" => panic!("Testnet3 pool address is missing"),
-            "local" => panic!("Local pool address is missing"),
-            "production" => panic!("Pool address is missing"),
-            _ => unreachable!(),
-        });
+    let (pool_addresses, auth_pub_k) = match Configuration::parent_address() {
+        // A cascaded dmnd-client mines through its parent only
+        Some(parent) => {
+            info!("Using the parent dmnd-client at {} as pool", parent);
+            let auth_pub_k = Configuration::parent_authority_public_key()
+                .expect("Checked when the configuration is loaded");
+            (vec![parent], auth_pub_k)
+        }
+        None => {
+            let auth_pub_k: Secp256k1PublicKey = AUTH_PUB_KEY.parse().expect("Invalid public key");
+            let pool_addresses = Configuration::pool_address()
+                .await
+                .filter(|p| !p.is_empty())
+                .unwrap_or_else(|| match Configuration::environment().as_str() {
+                    "staging" => panic!("Staging pool address is missing"),
+                    "testnet3" => panic!("Testnet3 pool address is missing"),
+                    "local" => panic!("Local pool address is missing"),
+                    "production" => panic!("Pool address is missing"),
+                    _ => unreachable!(),
+                });
+            (pool_addresses, auth_pub_k)
+        }
+    };
 
     let mut router = router::Router::new(pool_addresses, auth_pub_k, None, None);
     let epsilon = Duration::from_millis(30_000);
@@ -165,25 +237,65 @@ async fn initialize_proxy(
     epsilon: Duration,
     signature: String,
 ) {
+    // Created once so that the workers history survives the proxy restarts
+    let stats_sender = api::stats::StatsSender::new();
+    // Delays the full restarts when the proxy keeps failing right after starting
+    let mut restart_backoff = Backoff::default();
+    // The API server is started once and keeps serving while the proxy restarts
+    let (control_sender, mut control_receiver) = channel(10);
+    let (router_sender, router_receiver) = watch::channel(router.clone());
+    let api_stats_sender = stats_sender.clone();
+    let _api_server = supervisor::supervise("api_server", move || {
+        api::start(
+            router_receiver.clone(),
+            api_stats_sender.clone(),
+            control_sender.clone(),
+        )
+    });
     loop {
-        let stats_sender = api::stats::StatsSender::new();
-        let (send_to_pool, recv_from_pool, pool_connection_abortable) =
-            match router.connect_pool(pool_addr).await {
-                Ok(connection) => connection,
-                Err(_) => {
-                    error!("No upstream available. Retrying in 5 seconds...");
-                    warn!(
-                        "Please make sure the your token {} is correct",
-                        Configuration::token().expect("Token is not set")
-                    );
-                    let secs = 5;
-                    tokio::time::sleep(Duration::from_secs(secs)).await;
-                    continue;
-                }
-            };
+        stats_sender.reset_connections();
+        let started = tokio::time::Instant::now();
+        let connection = match Configuration::fallback_pool_url() {
+            // Without a fallback pool the proxy waits for the SV2 pool as long as needed
+            None => router.connect_pool(pool_addr).await,
+            Some(_) => tokio::time::timeout(
+                fallback::SV2_CONNECT_TIMEOUT,
+                router.connect_pool(pool_addr),
+            )
+            .await
+            .unwrap_or(Err(minin_pool_connection::errors::Error::Timeout)),
+        };
+        router_sender.send_replace(router.clone());
+        let (send_to_pool, recv_from_pool, pool_connection_abortable) = match connection {
+            Ok(connection) => connection,
+            Err(_) if Configuration::fallback_pool_url().is_some() => {
+                pool_addr =
+                    fallback::run(router, stats_sender.clone(), &mut control_receiver).await;
+                continue;
+            }
+            Err(_) => {
+                error!("No upstream available. Retrying in 5 seconds...");
+                warn!(
+                    "Please make sure the your token {} is correct",
+                    Configuration::token().expect("Token is not set")
+                );
+                let secs = 5;
+                tokio::time::sleep(Duration::from_secs(secs)).await;
+                continue;
+            }
+        };
 
         let (downs_sv1_tx, downs_sv1_rx) = channel(10);
+        // The ingress only hands new connections to the translator, so it can be restarted alone
         let sv1_ingress_abortable = ingress::sv1_ingress::start_listen_for_downstream(downs_sv1_tx);
+        // Part of the miners go to the split pool or to the other accounts when they are configured
+        let (downs_sv1_rx, upstreams_abortables) = upstreams::start(
+            router,
+            downs_sv1_rx,
+            stats_sender.clone(),
+            signature.clone(),
+        )
+        .await;
 
         let (translator_up_tx, mut translator_up_rx) = channel(10);
         let translator_abortable = match translator::start(
@@ -191,6 +303,7 @@ async fn initialize_proxy(
             translator_up_tx,
             stats_sender.clone(),
             signature.clone(),
+            upstreams::MAIN_POOL,
         )
         .await
         {
@@ -198,8 +311,10 @@ async fn initialize_proxy(
             Err(e) => {
                 error!("Impossible to initialize translator: {e}");
                 // Impossible to start the proxy so we restart proxy
-                ProxyState::update_translator_state(TranslatorState::Down);
-                ProxyState::update_tp_state(TpState::Down);
+                ProxyState::update_translator_state(
+                    TranslatorState::Down,
+                    &format!("Impossible to initialize translator: {e}"),
+                );
                 return;
             }
         };
@@ -222,6 +337,8 @@ async fn initialize_proxy(
         };
 
         if let Some(_tp_addr) = tp {
+            // The TP and the JD are restarted on their own by the JDC, miners work on the pool jobs
+            // in the meantime
             jdc_abortable = jd_client::start(
                 jdc_from_translator_receiver,
                 jdc_to_translator_sender,
@@ -230,7 +347,10 @@ async fn initialize_proxy(
             )
             .await;
             if jdc_abortable.is_none() {
-                ProxyState::update_tp_state(TpState::Down);
+                ProxyState::update_downstream_state(
+                    DownstreamType::JdClientMiningDownstream,
+                    "JD client failed to start",
+                );
             };
             share_accounter_abortable = match share_accounter::start(
                 from_jdc_to_share_accounter_recv,
@@ -267,7 +387,7 @@ async fn initialize_proxy(
 
         // Collecting all abort handles
         let mut abort_handles = vec![
-            (pool_connection_abortable, "pool_connection".to_string()),
+            (pool_connection_abortable, POOL_CONNECTION.to_string()),
             (sv1_ingress_abortable, "sv1_ingress".to_string()),
             (translator_abortable, "translator".to_string()),
             (share_accounter_abortable, "share_accounter".to_string()),
@@ -275,16 +395,25 @@ async fn initialize_proxy(
         if let Some(jdc_handle) = jdc_abortable {
             abort_handles.push((jdc_handle, "jdc".to_string()));
         }
-        let server_handle = tokio::spawn(api::start(router.clone(), stats_sender));
-        abort_handles.push((server_handle.into(), "api_server".to_string()));
-        match monitor(router, abort_handles, epsilon).await {
+        abort_handles.extend(upstreams_abortables);
+        let (reconnect, after_failure, reason) =
+            monitor(router, abort_handles, epsilon, &mut control_receiver).await;
+        // Requested restarts and upstream switches are not delayed
+        if after_failure {
+            let delay = restart_backoff.next_delay(started.elapsed());
+            info!("Reinitializing proxy in {:?}", delay);
+            tokio::time::sleep(delay).await;
+        }
+        match reconnect {
             Reconnect::NewUpstream(new_pool_addr) => {
-                ProxyState::update_proxy_state_up();
+                ProxyState::record_reconnect(true, &reason);
+                ProxyState::update_proxy_state_up(&reason);
                 pool_addr = Some(new_pool_addr);
                 continue;
             }
             Reconnect::NoUpstream => {
-                ProxyState::update_proxy_state_up();
+                ProxyState::record_reconnect(false, &reason);
+                ProxyState::update_proxy_state_up(&reason);
                 pool_addr = None;
                 continue;
             }
@@ -292,13 +421,50 @@ async fn initialize_proxy(
     }
 }
 
+/// Returns how to reconnect once a component failed, or a reinitialization was requested, and
+/// whether it follows a failure.
 async fn monitor(
     router: &mut Router,
-    abort_handles: Vec<(AbortOnDrop, std::string::String)>,
+    mut abort_handles: Vec<(AbortOnDrop, std::string::String)>,
     epsilon: Duration,
-) -> Reconnect {
+    control_receiver: &mut Receiver<ControlCommand>,
+) -> (Reconnect, bool, String) {
     let mut should_check_upstreams_latency = 0;
     loop {
+        // Commands from the control API
+        if let Ok(command) = control_receiver.try_recv() {
+            info!("Received control command {:?}", command);
+            let reason = format!("{:?} requested from the control API", command);
+            let reconnect = match command {
+                ControlCommand::SwitchPool(Some(pool_addr)) => {
+                    Some(Reconnect::NewUpstream(pool_addr))
+                }
+                ControlCommand::SwitchPool(None) => Some(Reconnect::NoUpstream),
+                // The JDC runs when there is a TP, only the TP and the JDS are reconnected and
+                // miners work on the pool jobs in the meantime
+                ControlCommand::ReconnectTp if is_tp_set() => {
+                    jd_client::reconnect_template_provider();
+                    None
+                }
+                ControlCommand::ReconnectTp => {
+                    reset_tp_address();
+                    Some(reconnect_to_current_pool(router))
+                }
+                ControlCommand::ReloadConfig => {
+                    reset_tp_address();
+                    Some(reconnect_to_current_pool(router))
+                }
+                ControlCommand::Restart => Some(reconnect_to_current_pool(router)),
+            };
+            if let Some(reconnect) = reconnect {
+                info!("Reinitializing proxy...");
+                drop(abort_handles);
+                // Needs a little to time to drop
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+                return (reconnect, false, reason);
+            }
+        }
+
         if Configuration::monitor() {
             // Check if a better upstream exist every 100 seconds
             if should_check_upstreams_latency == 10 * 100 {
@@ -309,51 +475,64 @@ async fn monitor(
 
                     // Needs a little to time to drop
                     tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
-                    return Reconnect::NewUpstream(new_upstream);
+                    let reason = format!("faster upstream {} detected", new_upstream);
+                    return (Reconnect::NewUpstream(new_upstream), false, reason);
                 }
             }
             should_check_upstreams_latency += 1;
         }
 
-        // Monitor finished tasks
+        // The other subsystems are restarted on their own, the whole proxy is reinitialized only
+        // when the pool channel is gone
         if let Some((_handle, name)) = abort_handles
             .iter()
-            .find(|(handle, _name)| handle.is_finished())
+            .find(|(handle, name)| name == POOL_CONNECTION && handle.is_finished())
         {
             error!("Task {:?} finished, Closing connection", name);
-            for (handle, _name) in abort_handles {
-                drop(handle);
-            }
-
-            // Check if the proxy state is down, and if so, reinitialize the proxy.
-            let is_proxy_down = ProxyState::is_proxy_down();
-            if is_proxy_down.0 {
-                error!(
-                    "Status: {:?}. Reinitializing proxy...",
-                    is_proxy_down.1.unwrap_or("Proxy".to_string())
-                );
-                return Reconnect::NoUpstream;
-            } else {
-                return Reconnect::NoUpstream;
-            }
+            let reason = format!("task {} finished", name);
+            drop(abort_handles);
+            return (Reconnect::NoUpstream, true, reason);
         }
+        abort_handles.retain(|(handle, name)| {
+            let finished = handle.is_finished();
+            if finished {
+                warn!("Task {:?} finished", name);
+            }
+            !finished
+        });
 
-        // Check if the proxy state is down, and if so, reinitialize the proxy.
-        let is_proxy_down = ProxyState::is_proxy_down();
-        if is_proxy_down.0 {
-            error!(
-                "{:?} is DOWN. Reinitializing proxy...",
-                is_proxy_down.1.unwrap_or("Proxy".to_string())
-            );
+        if ProxyState::is_pool_down() {
+            error!("Pool is DOWN. Reinitializing proxy...");
             drop(abort_handles); // Drop all abort handles
             tokio::time::sleep(tokio::time::Duration::from_millis(10)).await; // Needs a little to time to drop
-            return Reconnect::NoUpstream;
+            return (Reconnect::NoUpstream, true, "pool is down".to_string());
         }
 
         tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
     }
 }
 
+fn reconnect_to_current_pool(router: &Router) -> Reconnect {
+    match router.current_pool {
+        Some(pool_addr) => Reconnect::NewUpstream(pool_addr),
+        None => Reconnect::NoUpstream,
+    }
+}
+
+fn is_tp_set() -> bool {
+    TP_ADDRESS.safe_lock(|tp| tp.is_some()).unwrap_or(false)
+}
+
+fn reset_tp_address() {
+    if TP_ADDRESS
+        .safe_lock(|tp| *tp = Configuration::tp_address())
+        .is_err()
+    {
+        error!("TP_ADDRESS Mutex Corrupted");
+        ProxyState::update_inconsistency(Some(1), "TP_ADDRESS Mutex Corrupted");
+    }
+}
+
 pub enum Reconnect {
     NewUpstream(std::net::SocketAddr), // Reconnecting with a new upstream
     NoUpstream,                        // Reconnecting without upstream
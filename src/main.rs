@@ -1,5 +1,14 @@
 #![allow(unused_crate_dependencies)] // To avoid warnings about unused dependencies in this binary crate since the dependencies are used in the library crate.
-#[tokio::main]
-async fn main() {
-    dmnd_client::start().await;
+fn main() {
+    // The updater runs before the runtime is started, see `dmnd_client::update`
+    dmnd_client::update();
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start the tokio runtime");
+    runtime.block_on(async {
+        tokio::select! {
+            _ = dmnd_client::start() => (),
+            _ = dmnd_client::shutdown_signal() => (),
+        }
+    });
+    runtime.shutdown_timeout(std::time::Duration::from_secs(1));
+    dmnd_client::shutdown();
 }
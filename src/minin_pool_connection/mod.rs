@@ -14,7 +14,6 @@ use roles_logic_sv2::{
     common_messages_sv2::{Protocol, SetupConnection, SetupConnectionSuccess},
     parsers::CommonMessages,
 };
-use tokio::net::TcpStream;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tracing::{error, info};
 
@@ -43,7 +42,7 @@ pub async fn connect_pool(
     Error,
 > {
     let socket = loop {
-        match TcpStream::connect(address).await {
+        match crate::outbound::connect(address).await {
             Ok(socket) => {
                 info!("Socket initialized with Pool at {}", address);
                 break socket;
@@ -116,7 +115,7 @@ pub fn relay_up(
                 let either_frame: EitherFrame = std_frame.into();
                 if send.send(either_frame).await.is_err() {
                     error!("Mining upstream failed");
-                    ProxyState::update_pool_state(PoolState::Down);
+                    ProxyState::update_pool_state(PoolState::Down, "Mining upstream failed");
                     break;
                 };
             } else {
@@ -147,7 +146,10 @@ pub fn relay_down(
                             error!("Internal Mining downstream not available");
 
                             // Update Proxy state to reflect Internal inconsistency
-                            ProxyState::update_inconsistency(Some(1));
+                            ProxyState::update_inconsistency(
+                                Some(1),
+                                "Internal Mining downstream not available",
+                            );
                         }
                     } else {
                         error!("Mining Upstream send non Mining message. Disconnecting");
@@ -163,7 +165,7 @@ pub fn relay_down(
             }
         }
         error!("Failed to receive msg from Pool");
-        ProxyState::update_pool_state(PoolState::Down);
+        ProxyState::update_pool_state(PoolState::Down, "Failed to receive msg from Pool");
     });
     task.into()
 }
@@ -213,6 +215,16 @@ pub async fn mining_setup_connection(
 }
 
 pub fn get_mining_setup_connection_msg(work_selection: bool) -> SetupConnection<'static> {
+    let token = Configuration::token().expect("Checked at initialization");
+    get_mining_setup_connection_msg_for_token(work_selection, &token)
+}
+
+/// `SetupConnection` that identifies the miner with `token`, used for the pools that are not
+/// reached with the main token.
+pub fn get_mining_setup_connection_msg_for_token(
+    work_selection: bool,
+    token: &str,
+) -> SetupConnection<'static> {
     let endpoint_host = "0.0.0.0".to_string().into_bytes().try_into().expect("Internal error: this operation can not fail because the string 0.0.0.0 can always be converted into Inner");
     let vendor = String::new().try_into().expect("Internal error: this operation can not fail because an empty string can always be converted into Inner");
     let hardware_version = String::new().try_into().expect("Internal error: this operation can not fail because an empty string can always be converted into Inner");
@@ -221,7 +233,6 @@ pub fn get_mining_setup_connection_msg(work_selection: bool) -> SetupConnection<
         false => 0b0000_0000_0000_0000_0000_0000_0000_0100,
         true => 0b0000_0000_0000_0000_0000_0000_0000_0110,
     };
-    let token = Configuration::token().expect("Checked at initialization");
     let device_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
     let device_id = format!("{}::POOLED::{}", device_id, token)
         .to_string()
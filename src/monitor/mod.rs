@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use reqwest::Url;
 use serde_json::json;
 use tracing::{debug, error};
@@ -6,7 +8,7 @@ use crate::{
     config::Configuration,
     monitor::{shares::ShareInfo, worker_activity::WorkerActivity},
     shared::error::Error,
-    LOCAL_URL, PRODUCTION_URL, STAGING_URL, TESTNET3_URL,
+    upstreams, LOCAL_URL, PRODUCTION_URL, STAGING_URL, TESTNET3_URL,
 };
 
 pub mod shares;
@@ -40,37 +42,43 @@ fn worker_activity_server_endpoint() -> String {
 
 impl MonitorAPI {
     pub fn new(url: String) -> Self {
-        let client = reqwest::Client::new();
+        let client = crate::outbound::http_client();
         MonitorAPI {
             url: url.parse().expect("Invalid URL"),
             client,
         }
     }
 
-    /// Sends a batch of shares to the monitoring server.
-    async fn send_shares(&self, shares: Vec<ShareInfo>) -> Result<(), Error> {
-        let token = crate::config::Configuration::token().expect("Token is not set");
-
-        debug!("Sending batch of {} shares to API", shares.len());
-        let response = self
-            .client
-            .post(self.url.clone())
-            .json(&json!({ "shares": shares, "token": token }))
-            .send()
-            .await?;
+    /// Sends a batch of shares to the monitoring server, under the token of the account each share
+    /// was mined for. Returns the shares of the accounts whose batch could not be sent.
+    async fn send_shares(&self, shares: Vec<ShareInfo>) -> Vec<ShareInfo> {
+        let mut shares_by_token: HashMap<String, Vec<ShareInfo>> = HashMap::new();
+        for share in shares {
+            let token = upstreams::token_of(share.upstream_index()).expect("Token is not set");
+            shares_by_token.entry(token).or_default().push(share);
+        }
 
-        match response.error_for_status() {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                error!("Failed to send shares: {}", err);
-                Err(err.into())
+        let mut failed = Vec::new();
+        for (token, shares) in shares_by_token {
+            debug!("Sending batch of {} shares to API", shares.len());
+            let response = self
+                .client
+                .post(self.url.clone())
+                .json(&json!({ "shares": shares, "token": token }))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(err) = response {
+                error!("Failed to send {} shares: {}", shares.len(), err);
+                failed.extend(shares);
             }
         }
+        failed
     }
 
     /// Sends a worker activity log to the monitoring server.
     pub async fn send_worker_activity(&self, activity: WorkerActivity) -> Result<(), Error> {
-        let token = crate::config::Configuration::token().expect("Token is not set");
+        let token = upstreams::token_of(activity.upstream_index()).expect("Token is not set");
         debug!("Sending worker activity to API: {:?}", activity);
         let response = self
             .client
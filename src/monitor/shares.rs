@@ -3,6 +3,7 @@ use std::sync::Arc;
 use tracing::{error, info, warn};
 
 use crate::{
+    events::EventKind,
     monitor::{shares_server_endpoint, MonitorAPI},
     proxy_state::{DownstreamType, ProxyState},
 };
@@ -15,6 +16,9 @@ pub struct ShareInfo {
     // if None, the share was accepted
     rejection_reason: Option<RejectionReason>,
     timestamp: u64,
+    // Translator index of the upstream the share was mined on, it gives the account of the share
+    #[serde(skip)]
+    upstream_index: u32,
 }
 
 impl ShareInfo {
@@ -33,6 +37,37 @@ impl ShareInfo {
                 .duration_since(std::time::UNIX_EPOCH)
                 .expect("Time went backwards")
                 .as_secs(),
+            upstream_index: crate::upstreams::MAIN_POOL,
+        }
+    }
+
+    /// Sets the translator index of the upstream the share was mined on
+    pub fn on_upstream(mut self, upstream_index: u32) -> Self {
+        self.upstream_index = upstream_index;
+        self
+    }
+
+    pub fn upstream_index(&self) -> u32 {
+        self.upstream_index
+    }
+
+    /// Converts the share in the event published on the events stream. Shares that do not meet
+    /// the latest difficulty are accepted for the miner, and counted as accepted in its stats, they
+    /// are only not sent upstream.
+    pub fn as_event(&self, connection_id: u32) -> EventKind {
+        match &self.rejection_reason {
+            None | Some(RejectionReason::DifficultyMismatch) => EventKind::ShareAccepted {
+                connection_id,
+                worker_name: self.worker_name.clone(),
+                job_id: self.job_id,
+                difficulty: self.difficulty.unwrap_or_default(),
+            },
+            Some(reason) => EventKind::ShareRejected {
+                connection_id,
+                worker_name: self.worker_name.clone(),
+                job_id: self.job_id,
+                reason: reason.clone(),
+            },
         }
     }
 }
@@ -57,30 +92,38 @@ impl SharesMonitor {
             })
             .unwrap_or_else(|e| {
                 error!("Failed to lock pending shares: {:?}", e);
-                ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                ProxyState::update_downstream_state(
+                    DownstreamType::TranslatorDownstream,
+                    &format!("Failed to lock pending shares: {:?}", e),
+                );
             });
     }
 
-    /// Retrieves the list of pending shares.
-    fn get_next_shares(&self) -> Vec<ShareInfo> {
-        self.shares
-            .safe_lock(|event| event.clone())
-            .unwrap_or_else(|e| {
-                error!("Failed to lock pending shares: {:?}", e);
-                ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
-                Vec::new()
-            })
+    /// Takes the pending shares, leaving the list empty.
+    fn take_next_shares(&self) -> Vec<ShareInfo> {
+        self.shares.safe_lock(std::mem::take).unwrap_or_else(|e| {
+            error!("Failed to lock pending shares: {:?}", e);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                &format!("Failed to lock pending shares: {:?}", e),
+            );
+            Vec::new()
+        })
     }
 
-    /// Clears the list of pending shares.
-    fn clear_next_shares(&self) {
+    /// Puts back shares that could not be sent before the shares received meanwhile.
+    fn requeue_shares(&self, mut shares: Vec<ShareInfo>) {
         self.shares
             .safe_lock(|event| {
-                event.clear();
+                shares.append(event);
+                *event = shares;
             })
             .unwrap_or_else(|e| {
                 error!("Failed to lock pending shares: {:?}", e);
-                ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                ProxyState::update_downstream_state(
+                    DownstreamType::TranslatorDownstream,
+                    &format!("Failed to lock pending shares: {:?}", e),
+                );
             });
     }
 
@@ -91,19 +134,15 @@ impl SharesMonitor {
         interval.tick().await;
         loop {
             interval.tick().await;
-            let shares_to_send = self.get_next_shares();
+            let shares_to_send = self.take_next_shares();
             if !shares_to_send.is_empty() {
-                match api.send_shares(shares_to_send.clone()).await {
-                    Ok(_) => {
-                        info!(
-                            "Saved {} shares to the monitoring server",
-                            shares_to_send.len()
-                        );
-                        self.clear_next_shares();
-                    }
-                    Err(err) => {
-                        warn!("Failed to send shares, this does not affect mining but may cause issues with monitoring: {:?}", err);
-                    }
+                let count = shares_to_send.len();
+                let failed = api.send_shares(shares_to_send).await;
+                if failed.is_empty() {
+                    info!("Saved {} shares to the monitoring server", count);
+                } else {
+                    warn!("Failed to send {} of {} shares, this does not affect mining but may cause issues with monitoring. They are sent again with the next batch", failed.len(), count);
+                    self.requeue_shares(failed);
                 }
             } else {
                 warn!("No pending shares to send. If this happens frequently, check your miner.");
@@ -130,3 +169,48 @@ impl std::fmt::Display for RejectionReason {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requeues_failed_shares_first() {
+        let monitor = SharesMonitor::new();
+        monitor.insert_share(ShareInfo::new("a".to_string(), None, 1, None));
+        monitor.insert_share(ShareInfo::new("b".to_string(), None, 2, None));
+        let taken = monitor.take_next_shares();
+        assert_eq!(taken.len(), 2);
+        assert!(monitor.take_next_shares().is_empty());
+
+        monitor.insert_share(ShareInfo::new("c".to_string(), None, 3, None));
+        monitor.requeue_shares(taken.into_iter().skip(1).collect());
+        let names: Vec<String> = monitor
+            .take_next_shares()
+            .iter()
+            .map(|share| share.worker_name.clone())
+            .collect();
+        assert_eq!(names, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn difficulty_mismatches_are_published_as_accepted() {
+        let share = ShareInfo::new(
+            "a".to_string(),
+            Some(8.0),
+            1,
+            Some(RejectionReason::DifficultyMismatch),
+        );
+        assert!(matches!(
+            share.as_event(0),
+            EventKind::ShareAccepted { difficulty, .. } if difficulty == 8.0
+        ));
+        let share = ShareInfo::new(
+            "a".to_string(),
+            None,
+            1,
+            Some(RejectionReason::InvalidShare),
+        );
+        assert!(matches!(share.as_event(0), EventKind::ShareRejected { .. }));
+    }
+}
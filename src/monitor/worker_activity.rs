@@ -11,6 +11,9 @@ pub struct WorkerActivity {
     user_agent: String,
     worker_name: String,
     activity: WorkerActivityType,
+    // Translator index of the upstream the worker mines on, it gives the account of the worker
+    #[serde(skip)]
+    upstream_index: u32,
 }
 
 impl WorkerActivity {
@@ -19,9 +22,20 @@ impl WorkerActivity {
             user_agent,
             worker_name,
             activity,
+            upstream_index: crate::upstreams::MAIN_POOL,
         }
     }
 
+    /// Sets the translator index of the upstream the worker mines on
+    pub fn on_upstream(mut self, upstream_index: u32) -> Self {
+        self.upstream_index = upstream_index;
+        self
+    }
+
+    pub fn upstream_index(&self) -> u32 {
+        self.upstream_index
+    }
+
     pub fn monitor_api(&self) -> MonitorAPI {
         MonitorAPI::new(worker_activity_server_endpoint())
     }
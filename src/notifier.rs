@@ -0,0 +1,424 @@
+//! Operational alerts posted to webhooks.
+//!
+//! The notifier listens to the proxy events and posts an alert to every URL given with
+//! `--webhook-url` when a component goes down, the pool is switched, the proxy falls back to the
+//! pool jobs, a worker goes idle or degraded, the reject rate spikes, the proxy is updated or a
+//! block is found. Alerts are sent as plain JSON or formatted for Slack and Discord incoming
+//! webhooks.
+//!
+//! Every kind of alert is debounced, an alert about the same subject (e.g. the same component)
+//! is not sent again before the debounce time, and rate limited, at most a number of alerts of
+//! each kind are sent per hour. Suppressed alerts are counted in the next alert of that kind.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tokio::{sync::broadcast::error::RecvError, time::Instant};
+use tracing::{debug, info, warn};
+
+use crate::{
+    api::history::HealthState,
+    auto_update,
+    config::Configuration,
+    events::{self, Event, EventKind},
+};
+
+/// How often the reject rate is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Number of check intervals the reject rate is computed over
+const REJECT_RATE_BUCKETS: usize = 10;
+/// Min number of shares in the window for the reject rate to be meaningful
+const MIN_SHARES_FOR_REJECT_RATE: u64 = 20;
+const RATE_LIMIT_PERIOD: Duration = Duration::from_secs(60 * 60);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum AlertKind {
+    ComponentDown,
+    PoolSwitched,
+    JdFallback,
+    WorkerIdle,
+    WorkerDegraded,
+    RejectRateSpike,
+    AutoUpdate,
+    BlockFound,
+}
+
+impl AlertKind {
+    fn name(&self) -> &'static str {
+        match self {
+            AlertKind::ComponentDown => "component_down",
+            AlertKind::PoolSwitched => "pool_switched",
+            AlertKind::JdFallback => "jd_fallback",
+            AlertKind::WorkerIdle => "worker_idle",
+            AlertKind::WorkerDegraded => "worker_degraded",
+            AlertKind::RejectRateSpike => "reject_rate_spike",
+            AlertKind::AutoUpdate => "auto_update",
+            AlertKind::BlockFound => "block_found",
+        }
+    }
+
+    /// Min time between two alerts about the same subject and max alerts per hour
+    fn limits(&self) -> (Duration, usize) {
+        match self {
+            AlertKind::ComponentDown => (Duration::from_secs(5 * 60), 20),
+            AlertKind::PoolSwitched => (Duration::from_secs(5 * 60), 10),
+            AlertKind::JdFallback => (Duration::from_secs(15 * 60), 6),
+            AlertKind::WorkerIdle => (Duration::from_secs(60 * 60), 30),
+            AlertKind::WorkerDegraded => (Duration::from_secs(60 * 60), 30),
+            AlertKind::RejectRateSpike => (Duration::from_secs(15 * 60), 4),
+            AlertKind::AutoUpdate => (Duration::ZERO, 10),
+            // Every block is worth an alert
+            AlertKind::BlockFound => (Duration::ZERO, 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Alert {
+    kind: AlertKind,
+    /// What the alert is about, alerts are debounced by kind and subject
+    subject: String,
+    title: String,
+    message: String,
+    details: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WebhookFormat {
+    Json,
+    Slack,
+    Discord,
+}
+
+impl WebhookFormat {
+    fn for_url(url: &str) -> Self {
+        match Configuration::webhook_format().as_deref() {
+            Some("json") => return WebhookFormat::Json,
+            Some("slack") => return WebhookFormat::Slack,
+            Some("discord") => return WebhookFormat::Discord,
+            Some(format) => warn!(
+                "Unknown webhook format {}, guessing it from the url",
+                format
+            ),
+            None => (),
+        }
+        if url.contains("hooks.slack.com") {
+            WebhookFormat::Slack
+        } else if url.contains("discord.com/api/webhooks")
+            || url.contains("discordapp.com/api/webhooks")
+        {
+            WebhookFormat::Discord
+        } else {
+            WebhookFormat::Json
+        }
+    }
+
+    fn payload(&self, alert: &Alert, suppressed: usize) -> Value {
+        let mut message = alert.message.clone();
+        if suppressed > 0 {
+            message.push_str(&format!(
+                " ({} similar alerts suppressed in the last hour)",
+                suppressed
+            ));
+        }
+        match self {
+            WebhookFormat::Json => json!({
+                "alert": alert.kind.name(),
+                "title": alert.title,
+                "message": message,
+                "details": alert.details,
+                "suppressed": suppressed,
+            }),
+            WebhookFormat::Slack => json!({ "text": format!("*{}*\n{}", alert.title, message) }),
+            WebhookFormat::Discord => {
+                json!({ "content": format!("**{}**\n{}", alert.title, message) })
+            }
+        }
+    }
+}
+
+/// Debounce and rate limit state of the alerts
+#[derive(Debug, Default)]
+struct Limiter {
+    last_sent: HashMap<(AlertKind, String), Instant>,
+    sent: HashMap<AlertKind, VecDeque<Instant>>,
+    suppressed: HashMap<AlertKind, usize>,
+}
+
+impl Limiter {
+    /// Returns the number of alerts of the same kind suppressed since the last one sent if the
+    /// alert can be sent, None if it must be suppressed.
+    fn allow(&mut self, kind: AlertKind, subject: &str, now: Instant) -> Option<usize> {
+        let (debounce, max_per_period) = kind.limits();
+        let key = (kind, subject.to_string());
+        if let Some(last_sent) = self.last_sent.get(&key) {
+            if now.duration_since(*last_sent) < debounce {
+                return None;
+            }
+        }
+        let sent = self.sent.entry(kind).or_default();
+        while sent
+            .front()
+            .is_some_and(|sent_at| now.duration_since(*sent_at) >= RATE_LIMIT_PERIOD)
+        {
+            sent.pop_front();
+        }
+        if sent.len() >= max_per_period {
+            *self.suppressed.entry(kind).or_default() += 1;
+            return None;
+        }
+        sent.push_back(now);
+        self.last_sent.insert(key, now);
+        Some(self.suppressed.remove(&kind).unwrap_or(0))
+    }
+}
+
+/// Listens to the proxy events and sends the alerts. Runs until the events channel is closed.
+pub async fn run() {
+    let mut events = events::subscribe();
+    auto_update::publish_update();
+    auto_update::publish_failure();
+    let mut limiter = Limiter::default();
+    // Accepted and rejected shares for each check interval, newest last
+    let mut shares: VecDeque<(u64, u64)> = VecDeque::from([(0, 0)]);
+    let mut check = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        let alerts = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    count_share(&event, &mut shares);
+                    alert_for(&event).into_iter().collect()
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Notifier lagged behind, {} events skipped", skipped);
+                    Vec::new()
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = check.tick() => {
+                let alerts = reject_rate_spike(&shares).into_iter().collect();
+                if shares.len() == REJECT_RATE_BUCKETS {
+                    shares.pop_front();
+                }
+                shares.push_back((0, 0));
+                alerts
+            }
+        };
+        for alert in alerts {
+            match limiter.allow(alert.kind, &alert.subject, Instant::now()) {
+                Some(suppressed) => {
+                    tokio::spawn(send(alert, suppressed));
+                }
+                None => debug!("Alert {} suppressed: {}", alert.kind.name(), alert.title),
+            }
+        }
+    }
+}
+
+// Counts the shares of the current check interval for the reject rate
+fn count_share(event: &Event, shares: &mut VecDeque<(u64, u64)>) {
+    if let Some((accepted, rejected)) = shares.back_mut() {
+        match event.kind {
+            EventKind::ShareAccepted { .. } => *accepted += 1,
+            EventKind::ShareRejected { .. } => *rejected += 1,
+            _ => (),
+        }
+    }
+}
+
+fn alert_for(event: &Event) -> Option<Alert> {
+    let alert = match &event.kind {
+        EventKind::ComponentStateChanged {
+            component,
+            old_state,
+            new_state,
+        } if new_state.starts_with("Down") => Alert {
+            kind: AlertKind::ComponentDown,
+            subject: component.clone(),
+            title: format!("{} is down", component),
+            message: format!("{} went from {} to {}", component, old_state, new_state),
+            details: json!(event),
+        },
+        EventKind::PoolSwitched { from, to } => Alert {
+            kind: AlertKind::PoolSwitched,
+            subject: to.to_string(),
+            title: "Pool switched".to_string(),
+            message: match from {
+                Some(from) => format!("Switched from pool {} to {}", from, to),
+                None => format!("Connected to pool {}", to),
+            },
+            details: json!(event),
+        },
+        EventKind::JdFallback { reason } => Alert {
+            kind: AlertKind::JdFallback,
+            subject: String::new(),
+            title: "Job declaration disabled".to_string(),
+            message: format!("Mining on the pool jobs: {}", reason),
+            details: json!(event),
+        },
+        EventKind::FallbackPoolChanged {
+            active: true,
+            pool_url,
+        } => Alert {
+            kind: AlertKind::PoolSwitched,
+            subject: pool_url.clone(),
+            title: "Mining on the fallback pool".to_string(),
+            message: format!(
+                "No SV2 pool is reachable, the miners are relayed to {}",
+                pool_url
+            ),
+            details: json!(event),
+        },
+        EventKind::ProxyUpdated {
+            from_version,
+            to_version,
+        } => Alert {
+            kind: AlertKind::AutoUpdate,
+            subject: to_version.clone(),
+            title: "Proxy updated".to_string(),
+            message: format!("Updated from {} to {}", from_version, to_version),
+            details: json!(event),
+        },
+        EventKind::ProxyUpdateFailed { error } => Alert {
+            kind: AlertKind::AutoUpdate,
+            subject: "failed".to_string(),
+            title: "Proxy update failed".to_string(),
+            message: error.clone(),
+            details: json!(event),
+        },
+        EventKind::WorkerHealthChanged {
+            worker_name,
+            new_state: HealthState::Idle,
+            reasons,
+            ..
+        } => Alert {
+            kind: AlertKind::WorkerIdle,
+            subject: worker_name.clone(),
+            title: format!("{} is idle", worker_name),
+            message: format!("Worker {}: {}", worker_name, reasons.join(", ")),
+            details: json!(event),
+        },
+        EventKind::WorkerHealthChanged {
+            worker_name,
+            new_state: HealthState::Degraded,
+            reasons,
+            ..
+        } => Alert {
+            kind: AlertKind::WorkerDegraded,
+            subject: worker_name.clone(),
+            title: format!("{} is degraded", worker_name),
+            message: format!("Worker {}: {}", worker_name, reasons.join(", ")),
+            details: json!(event),
+        },
+        EventKind::BlockFound {
+            worker_name,
+            block_hash,
+            height,
+            ..
+        } => Alert {
+            kind: AlertKind::BlockFound,
+            subject: block_hash.clone(),
+            title: "Block found".to_string(),
+            message: format!(
+                "Block {} at height {} found by {}",
+                block_hash,
+                height.map_or("unknown".to_string(), |h| h.to_string()),
+                worker_name
+            ),
+            details: json!(event),
+        },
+        _ => return None,
+    };
+    Some(alert)
+}
+
+fn reject_rate_spike(shares: &VecDeque<(u64, u64)>) -> Option<Alert> {
+    let (accepted, rejected) = shares.iter().fold((0, 0), |(a, r), (accepted, rejected)| {
+        (a + accepted, r + rejected)
+    });
+    let total = accepted + rejected;
+    if total < MIN_SHARES_FOR_REJECT_RATE {
+        return None;
+    }
+    let reject_rate = rejected as f64 * 100.0 / total as f64;
+    let threshold = Configuration::reject_rate_alert();
+    if reject_rate < threshold {
+        return None;
+    }
+    let minutes = (CHECK_INTERVAL * shares.len() as u32).as_secs() / 60;
+    Some(Alert {
+        kind: AlertKind::RejectRateSpike,
+        subject: String::new(),
+        title: "Reject rate spike".to_string(),
+        message: format!(
+            "{:.1}% of the shares rejected in the last {} minutes ({} of {}), alert threshold is {}%",
+            reject_rate, minutes, rejected, total, threshold
+        ),
+        details: json!({
+            "reject_rate": reject_rate,
+            "accepted": accepted,
+            "rejected": rejected,
+        }),
+    })
+}
+
+async fn send(alert: Alert, suppressed: usize) {
+    let urls = Configuration::webhook_urls();
+    if urls.is_empty() {
+        return;
+    }
+    info!("Sending alert {}: {}", alert.kind.name(), alert.title);
+    let client = crate::outbound::http_client();
+    let requests = urls.iter().map(|url| {
+        let payload = WebhookFormat::for_url(url).payload(&alert, suppressed);
+        client
+            .post(url)
+            .json(&payload)
+            .timeout(WEBHOOK_TIMEOUT)
+            .send()
+    });
+    for result in futures::future::join_all(requests).await {
+        if let Err(e) = result.and_then(|response| response.error_for_status()) {
+            warn!("Failed to send alert {}: {}", alert.kind.name(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn alerts_are_debounced_and_rate_limited() {
+        let mut limiter = Limiter::default();
+        let start = Instant::now();
+        assert_eq!(
+            limiter.allow(AlertKind::ComponentDown, "Pool", start),
+            Some(0)
+        );
+        // Same subject within the debounce time
+        assert_eq!(limiter.allow(AlertKind::ComponentDown, "Pool", start), None);
+        assert_eq!(
+            limiter.allow(AlertKind::ComponentDown, "Tp", start),
+            Some(0)
+        );
+
+        let (_, max_per_hour) = AlertKind::PoolSwitched.limits();
+        for pool in 0..max_per_hour {
+            let pool = pool.to_string();
+            assert!(limiter
+                .allow(AlertKind::PoolSwitched, &pool, start)
+                .is_some());
+        }
+        assert_eq!(limiter.allow(AlertKind::PoolSwitched, "other", start), None);
+        let later = start + RATE_LIMIT_PERIOD;
+        assert_eq!(
+            limiter.allow(AlertKind::PoolSwitched, "other", later),
+            Some(1)
+        );
+    }
+}
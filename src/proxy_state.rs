@@ -1,12 +1,35 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+};
 
 use lazy_static::lazy_static;
 use roles_logic_sv2::utils::Mutex;
 use serde::{Deserialize, Serialize};
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::Configuration,
+    events::{self, EventKind},
+};
+
+/// Max number of transitions kept in memory
+const TRANSITIONS_HISTORY_SIZE: usize = 1000;
 
 lazy_static! {
     static ref PROXY_STATE: Arc<Mutex<ProxyState>> = Arc::new(Mutex::new(ProxyState::new()));
+    // Transition history of each component, it is never reset
+    static ref COMPONENTS_HEALTH: Mutex<HashMap<&'static str, ComponentHealth>> =
+        Mutex::new(HashMap::new());
+    static ref TRANSITIONS: Mutex<VecDeque<Transition>> =
+        Mutex::new(VecDeque::with_capacity(TRANSITIONS_HISTORY_SIZE));
+    static ref RECONNECTS: Mutex<ReconnectCounts> = Mutex::new(ReconnectCounts::default());
+    // Transitions are written to the history file by a dedicated thread so that no IO is done
+    // while the proxy state is locked
+    static ref HISTORY_WRITER: mpsc::Sender<(PathBuf, Transition)> = spawn_history_writer();
 }
 
 /// Main enum representing the overall state of the proxy
@@ -86,7 +109,7 @@ pub enum UpstreamType {
 }
 
 /// Represents global proxy state
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProxyState {
     pub pool: PoolState,
     pub tp: TpState,
@@ -96,6 +119,107 @@ pub struct ProxyState {
     pub inconsistency: Option<u32>,
     pub downstream: DownstreamState,
     pub upstream: UpstreamState,
+    // True once the translator received a prev hash, so miners can get a valid job
+    #[serde(default)]
+    pub jobs_available: bool,
+    // True while the miners are relayed to the SV1 fallback pool
+    #[serde(default)]
+    pub fallback: bool,
+}
+
+/// Transition history of a component
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ComponentHealth {
+    /// Unix time in seconds of the last state change
+    pub last_transition: Option<u64>,
+    pub last_error: Option<String>,
+    /// How many times the component came back up after being down
+    pub restarts: u32,
+}
+
+/// A change of state of a component
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    /// Unix time in seconds
+    pub timestamp: u64,
+    pub component: &'static str,
+    pub old_state: String,
+    pub new_state: String,
+    /// Why the state changed, or why the proxy was restarted when all components are reset
+    pub reason: String,
+    pub error: Option<String>,
+}
+
+/// How many times the proxy has been reinitialized, by kind of reconnection
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReconnectCounts {
+    pub new_upstream: u64,
+    pub no_upstream: u64,
+    /// Unix time in seconds of the last reconnection
+    pub last_reconnect: Option<u64>,
+    /// Why the proxy was last reinitialized
+    pub last_reason: Option<String>,
+}
+
+/// Health of a single component as reported by `/api/health/live` and `/api/health/ready`
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentStatus {
+    pub component: &'static str,
+    /// The state as in `/api/proxy/state`, e.g. `"Up"` or `{"Down": ["TranslatorDownstream"]}`
+    pub state: serde_json::Value,
+    #[serde(flatten)]
+    pub health: ComponentHealth,
+}
+
+/// Implemented by the state of each component so that transitions can be tracked generically.
+trait ComponentState: std::fmt::Debug + PartialEq {
+    /// Returns `None` if the component is up, or a description of the failure.
+    fn error(&self) -> Option<String>;
+}
+
+macro_rules! impl_up_down_state {
+    ($($state:ty),*) => {
+        $(impl ComponentState for $state {
+            fn error(&self) -> Option<String> {
+                match self {
+                    Self::Up => None,
+                    Self::Down => Some("Down".to_string()),
+                }
+            }
+        })*
+    };
+}
+
+impl_up_down_state!(
+    PoolState,
+    TpState,
+    TranslatorState,
+    JdState,
+    ShareAccounterState
+);
+
+impl ComponentState for DownstreamState {
+    fn error(&self) -> Option<String> {
+        match self {
+            Self::Up => None,
+            Self::Down(downstreams) => Some(format!("Down: {:?}", downstreams)),
+        }
+    }
+}
+
+impl ComponentState for UpstreamState {
+    fn error(&self) -> Option<String> {
+        match self {
+            Self::Up => None,
+            Self::Down(upstreams) => Some(format!("Down: {:?}", upstreams)),
+        }
+    }
+}
+
+impl ComponentState for Option<u32> {
+    fn error(&self) -> Option<String> {
+        self.map(|code| format!("Internal inconsistency {}", code))
+    }
 }
 
 impl ProxyState {
@@ -109,14 +233,21 @@ impl ProxyState {
             inconsistency: None,
             downstream: DownstreamState::Up,
             upstream: UpstreamState::Up,
+            jobs_available: false,
+            fallback: false,
         }
     }
 
-    pub fn update_pool_state(pool_state: PoolState) {
+    pub fn update_pool_state(pool_state: PoolState, reason: &str) {
         info!("Updating PoolState state to {:?}", pool_state);
         if PROXY_STATE
             .safe_lock(|state| {
+                publish_transition("pool", &state.pool, &pool_state, reason);
                 state.pool = pool_state;
+                // Without the pool the miners get no jobs until the proxy is reinitialized
+                if pool_state == PoolState::Down {
+                    state.jobs_available = false;
+                }
                 // // state.update_proxy_state();
             })
             .is_err()
@@ -126,10 +257,11 @@ impl ProxyState {
         }
     }
 
-    pub fn update_tp_state(tp_state: TpState) {
+    pub fn update_tp_state(tp_state: TpState, reason: &str) {
         info!("Updating TpState state to {:?}", tp_state);
         if PROXY_STATE
             .safe_lock(|state| {
+                publish_transition("tp", &state.tp, &tp_state, reason);
                 state.tp = tp_state;
             })
             .is_err()
@@ -139,10 +271,11 @@ impl ProxyState {
         }
     }
 
-    pub fn update_jd_state(jd_state: JdState) {
+    pub fn update_jd_state(jd_state: JdState, reason: &str) {
         info!("Updating JdState state to {:?}", jd_state);
         if PROXY_STATE
             .safe_lock(|state| {
+                publish_transition("jd", &state.jd, &jd_state, reason);
                 state.jd = jd_state;
             })
             .is_err()
@@ -152,10 +285,11 @@ impl ProxyState {
         }
     }
 
-    pub fn update_translator_state(translator_state: TranslatorState) {
+    pub fn update_translator_state(translator_state: TranslatorState, reason: &str) {
         info!("Updating Translator state to {:?}", translator_state);
         if PROXY_STATE
             .safe_lock(|state| {
+                publish_transition("translator", &state.translator, &translator_state, reason);
                 state.translator = translator_state;
             })
             .is_err()
@@ -165,13 +299,19 @@ impl ProxyState {
         }
     }
 
-    pub fn update_share_accounter_state(share_accounter_state: ShareAccounterState) {
+    pub fn update_share_accounter_state(share_accounter_state: ShareAccounterState, reason: &str) {
         info!(
             "Updating ShareAccounterState state to {:?}",
             share_accounter_state
         );
         if PROXY_STATE
             .safe_lock(|state| {
+                publish_transition(
+                    "share_accounter",
+                    &state.share_accounter,
+                    &share_accounter_state,
+                    reason,
+                );
                 state.share_accounter = share_accounter_state;
             })
             .is_err()
@@ -181,10 +321,11 @@ impl ProxyState {
         }
     }
 
-    pub fn update_inconsistency(code: Option<u32>) {
+    pub fn update_inconsistency(code: Option<u32>, reason: &str) {
         info!("Updating Internal Inconsistency state to {:?}", code);
         if PROXY_STATE
             .safe_lock(|state| {
+                publish_transition("inconsistency", &state.inconsistency, &code, reason);
                 state.inconsistency = code;
             })
             .is_err()
@@ -194,11 +335,13 @@ impl ProxyState {
         }
     }
 
-    pub fn update_downstream_state(downstream_type: DownstreamType) {
+    pub fn update_downstream_state(downstream_type: DownstreamType, reason: &str) {
         info!("Updating Downstream state to {:?}", downstream_type);
         if PROXY_STATE
             .safe_lock(|state| {
-                state.downstream = DownstreamState::Down(vec![downstream_type]);
+                let downstream = DownstreamState::Down(vec![downstream_type]);
+                publish_transition("downstream", &state.downstream, &downstream, reason);
+                state.downstream = downstream;
             })
             .is_err()
         {
@@ -207,11 +350,13 @@ impl ProxyState {
         }
     }
 
-    pub fn update_upstream_state(upstream_type: UpstreamType) {
+    pub fn update_upstream_state(upstream_type: UpstreamType, reason: &str) {
         info!("Updating Upstream state to {:?}", upstream_type);
         if PROXY_STATE
             .safe_lock(|state| {
-                state.upstream = UpstreamState::Down(vec![upstream_type]);
+                let upstream = UpstreamState::Down(vec![upstream_type]);
+                publish_transition("upstream", &state.upstream, &upstream, reason);
+                state.upstream = upstream;
             })
             .is_err()
         {
@@ -220,17 +365,35 @@ impl ProxyState {
         }
     }
 
-    pub fn update_proxy_state_up() {
+    /// Sets all the components up when the proxy is reinitialized, `reason` is why it was. The job
+    /// availability and the fallback are not component states and are kept.
+    pub fn update_proxy_state_up(reason: &str) {
         if PROXY_STATE
             .safe_lock(|state| {
-                state.pool = PoolState::Up;
-                state.jd = JdState::Up;
-                state.translator = TranslatorState::Up;
-                state.tp = TpState::Up;
-                state.share_accounter = ShareAccounterState::Up;
-                state.upstream = UpstreamState::Up;
-                state.downstream = DownstreamState::Up;
-                state.inconsistency = None;
+                let up = ProxyState::new();
+                publish_transition("pool", &state.pool, &up.pool, reason);
+                publish_transition("jd", &state.jd, &up.jd, reason);
+                publish_transition("translator", &state.translator, &up.translator, reason);
+                publish_transition("tp", &state.tp, &up.tp, reason);
+                publish_transition(
+                    "share_accounter",
+                    &state.share_accounter,
+                    &up.share_accounter,
+                    reason,
+                );
+                publish_transition("upstream", &state.upstream, &up.upstream, reason);
+                publish_transition("downstream", &state.downstream, &up.downstream, reason);
+                publish_transition(
+                    "inconsistency",
+                    &state.inconsistency,
+                    &up.inconsistency,
+                    reason,
+                );
+                // Jobs are available again once the new pool connection sends one
+                *state = ProxyState {
+                    fallback: state.fallback,
+                    ..up
+                };
             })
             .is_err()
         {
@@ -239,8 +402,121 @@ impl ProxyState {
         }
     }
 
+    /// Called when the translator has a job that miners can work on
+    pub fn update_jobs_available() {
+        if PROXY_STATE
+            .safe_lock(|state| state.jobs_available = true)
+            .is_err()
+        {
+            error!("Global Proxy Mutex Corrupted");
+            std::process::exit(1);
+        }
+    }
+
+    /// Called when the miners start or stop being relayed to the SV1 fallback pool
+    pub fn update_fallback(fallback: bool) {
+        if PROXY_STATE
+            .safe_lock(|state| state.fallback = fallback)
+            .is_err()
+        {
+            error!("Global Proxy Mutex Corrupted");
+            std::process::exit(1);
+        }
+    }
+
+    /// True while the miners are relayed to the SV1 fallback pool
+    pub fn is_fallback() -> bool {
+        Self::get_state().is_ok_and(|state| state.fallback)
+    }
+
+    /// Returns the status of every component, with its transition history
+    pub fn get_components_status() -> Result<Vec<ComponentStatus>, ()> {
+        let state = Self::get_state()?;
+        let health = COMPONENTS_HEALTH
+            .safe_lock(|health| health.clone())
+            .map_err(|_| error!("Components health Mutex Corrupted"))?;
+        let components = [
+            ("pool", serde_json::to_value(state.pool)),
+            ("tp", serde_json::to_value(state.tp)),
+            ("jd", serde_json::to_value(state.jd)),
+            ("translator", serde_json::to_value(state.translator)),
+            (
+                "share_accounter",
+                serde_json::to_value(state.share_accounter),
+            ),
+            ("upstream", serde_json::to_value(&state.upstream)),
+            ("downstream", serde_json::to_value(&state.downstream)),
+        ];
+        Ok(components
+            .into_iter()
+            .map(|(component, value)| ComponentStatus {
+                component,
+                state: value.unwrap_or_default(),
+                health: health.get(component).cloned().unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    /// Miners can get jobs only if the proxy is not down and the translator has a valid job, or
+    /// while they are relayed to the SV1 fallback pool
+    pub fn is_ready() -> bool {
+        let (jobs_available, fallback) = match Self::get_state() {
+            Ok(state) => (state.jobs_available, state.fallback),
+            Err(_) => return false,
+        };
+        fallback || (jobs_available && !Self::is_proxy_down().0)
+    }
+
+    /// Counts a reinitialization of the proxy, `new_upstream` is true when reconnecting to a
+    /// specific upstream
+    pub fn record_reconnect(new_upstream: bool, reason: &str) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        if RECONNECTS
+            .safe_lock(|reconnects| {
+                if new_upstream {
+                    reconnects.new_upstream += 1;
+                } else {
+                    reconnects.no_upstream += 1;
+                }
+                reconnects.last_reconnect = Some(now);
+                reconnects.last_reason = Some(reason.to_string());
+            })
+            .is_err()
+        {
+            error!("Reconnects Mutex Corrupted");
+        }
+    }
+
+    /// Returns the last state transitions, oldest first, and the reconnection counts
+    pub fn get_history() -> Result<(Vec<Transition>, ReconnectCounts), ()> {
+        let transitions = TRANSITIONS
+            .safe_lock(|transitions| transitions.iter().cloned().collect())
+            .map_err(|_| error!("Transitions history Mutex Corrupted"))?;
+        let reconnects = RECONNECTS
+            .safe_lock(|reconnects| reconnects.clone())
+            .map_err(|_| error!("Reconnects Mutex Corrupted"))?;
+        Ok((transitions, reconnects))
+    }
+
+    /// Returns a copy of the current state of all the components
+    pub fn get_state() -> Result<ProxyState, ()> {
+        PROXY_STATE
+            .safe_lock(|state| state.clone())
+            .map_err(|_| error!("Global Proxy Mutex Corrupted"))
+    }
+
+    /// The TP and the JD do not make the proxy down: they are restarted on their own while miners
+    /// work on the pool jobs
     pub fn is_proxy_down() -> (bool, Option<String>) {
-        let errors = Self::get_errors();
+        let errors = Self::get_errors().map(|errors| {
+            errors
+                .into_iter()
+                .filter(|e| !matches!(e, ProxyStates::Tp(_) | ProxyStates::Jd(_)))
+                .collect::<Vec<_>>()
+        });
         if errors.is_ok() && errors.as_ref().unwrap().is_empty() {
             (false, None)
         } else {
@@ -250,6 +526,11 @@ impl ProxyState {
         }
     }
 
+    /// The pool channel is lost, the other components are restarted on their own
+    pub fn is_pool_down() -> bool {
+        Self::get_state().is_ok_and(|state| state.pool == PoolState::Down)
+    }
+
     pub fn get_errors() -> Result<Vec<ProxyStates>, ()> {
         let mut errors = Vec::new();
         if PROXY_STATE
@@ -288,3 +569,101 @@ impl ProxyState {
         }
     }
 }
+
+/// Publishes a `ComponentStateChanged` event, updates the component health and records the
+/// transition in the history if the component state actually changed.
+fn publish_transition<S: ComponentState>(
+    component: &'static str,
+    old_state: &S,
+    new_state: &S,
+    reason: &str,
+) {
+    if old_state != new_state {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let error = new_state.error();
+        if COMPONENTS_HEALTH
+            .safe_lock(|health| {
+                let health = health.entry(component).or_default();
+                health.last_transition = Some(now);
+                match &error {
+                    Some(_) => health.last_error = Some(reason.to_string()),
+                    None if old_state.error().is_some() => health.restarts += 1,
+                    None => (),
+                }
+            })
+            .is_err()
+        {
+            error!("Components health Mutex Corrupted");
+        }
+        record_transition(Transition {
+            timestamp: now,
+            component,
+            old_state: format!("{:?}", old_state),
+            new_state: format!("{:?}", new_state),
+            reason: reason.to_string(),
+            error,
+        });
+        events::publish(EventKind::ComponentStateChanged {
+            component: component.to_string(),
+            old_state: format!("{:?}", old_state),
+            new_state: format!("{:?}", new_state),
+        });
+    }
+}
+
+fn record_transition(transition: Transition) {
+    if let Some(path) = Configuration::state_history_file() {
+        if HISTORY_WRITER.send((path, transition.clone())).is_err() {
+            warn!("State history writer is gone, transition not written");
+        }
+    }
+    if TRANSITIONS
+        .safe_lock(|transitions| {
+            if transitions.len() == TRANSITIONS_HISTORY_SIZE {
+                transitions.pop_front();
+            }
+            transitions.push_back(transition);
+        })
+        .is_err()
+    {
+        error!("Transitions history Mutex Corrupted");
+    }
+}
+
+fn spawn_history_writer() -> mpsc::Sender<(PathBuf, Transition)> {
+    let (sender, receiver) = mpsc::channel::<(PathBuf, Transition)>();
+    let spawned = std::thread::Builder::new()
+        .name("state-history".to_string())
+        .spawn(move || {
+            for (path, transition) in receiver {
+                append_to_file(&path, &transition);
+            }
+        });
+    if let Err(e) = spawned {
+        warn!("Failed to start the state history writer: {}", e);
+    }
+    sender
+}
+
+// Appends the transition as a JSON line, failures are only logged so that a read only disk
+// does not take down the proxy
+fn append_to_file(path: &Path, transition: &Transition) {
+    let line = match serde_json::to_string(transition) {
+        Ok(line) => line,
+        Err(e) => {
+            warn!("Failed to serialize transition: {}", e);
+            return;
+        }
+    };
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(e) = result {
+        warn!("Failed to write state history to {}: {}", path.display(), e);
+    }
+}
@@ -20,10 +20,17 @@ use tokio::{
 use tracing::{error, info};
 
 use crate::{
-    minin_pool_connection::{self, get_mining_setup_connection_msg, mining_setup_connection},
+    minin_pool_connection::{
+        self, get_mining_setup_connection_msg, get_mining_setup_connection_msg_for_token,
+        mining_setup_connection,
+    },
     shared::utils::AbortOnDrop,
 };
 
+/// How long the additional pool connections, see `Router::connect_pool_with_token`, have to be
+/// established before the proxy starts without them
+const EXTRA_POOL_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Router handles connection to Multiple upstreams.
 #[derive(Clone)]
 pub struct Router {
@@ -60,6 +67,11 @@ impl Router {
         }
     }
 
+    /// Returns true if `pool_addr` is one of the pools the router can connect to.
+    pub fn is_known_pool(&self, pool_addr: &SocketAddr) -> bool {
+        self.pool_addresses.contains(pool_addr)
+    }
+
     /// Internal function to select pool with the least latency.
     async fn select_pool(&self) -> Option<(SocketAddr, Duration)> {
         let mut best_pool = None;
@@ -100,6 +112,12 @@ impl Router {
         }
     }
 
+    /// Pool with the least latency among the ones that complete the setup of a connection, unlike
+    /// `select_pool_connect` it checks the pool even when there is only one.
+    pub async fn reachable_pool(&self) -> Option<SocketAddr> {
+        self.select_pool().await.map(|(pool, _)| pool)
+    }
+
     /// Select the best pool for monitoring
     async fn select_pool_monitor(&self, epsilon: Duration) -> Option<SocketAddr> {
         if let Some((best_pool, best_pool_latency)) = self.select_pool().await {
@@ -168,14 +186,22 @@ impl Router {
         .await
         {
             Ok((send_to_pool, recv_from_pool, pool_connection_abortable)) => {
-                crate::ACTIVE_POOL_ADDRESS
-                    .safe_lock(|pool_address| {
-                        *pool_address = Some(pool);
-                    })
+                let previous_pool = crate::ACTIVE_POOL_ADDRESS
+                    .safe_lock(|pool_address| pool_address.replace(pool))
                     .unwrap_or_else(|_| {
                         error!("Pool address Mutex corrupt");
-                        crate::proxy_state::ProxyState::update_inconsistency(Some(1));
+                        crate::proxy_state::ProxyState::update_inconsistency(
+                            Some(1),
+                            "Pool address Mutex corrupt",
+                        );
+                        None
                     });
+                if previous_pool != Some(pool) {
+                    crate::events::publish(crate::events::EventKind::PoolSwitched {
+                        from: previous_pool,
+                        to: pool,
+                    });
+                }
                 info!(
                     "Completed Handshake And SetupConnection with Pool at {:?}",
                     pool
@@ -188,6 +214,42 @@ impl Router {
         }
     }
 
+    /// Opens an additional connection to `pool` identified with `token`, used by the split pool
+    /// and the other DMND accounts. Unlike `connect_pool` it gives up after
+    /// `EXTRA_POOL_CONNECT_TIMEOUT` so that the proxy can start without it.
+    pub async fn connect_pool_with_token(
+        &self,
+        pool: SocketAddr,
+        authority_public_key: Option<Secp256k1PublicKey>,
+        token: &str,
+    ) -> Result<
+        (
+            tokio::sync::mpsc::Sender<PoolExtMessages<'static>>,
+            tokio::sync::mpsc::Receiver<PoolExtMessages<'static>>,
+            AbortOnDrop,
+        ),
+        minin_pool_connection::errors::Error,
+    > {
+        info!("Trying to open an additional connection to Pool {:?}", pool);
+        let connection = minin_pool_connection::connect_pool(
+            pool,
+            authority_public_key.unwrap_or(self.auth_pub_k),
+            Some(get_mining_setup_connection_msg_for_token(true, token)),
+            self.timer,
+        );
+        match tokio::time::timeout(EXTRA_POOL_CONNECT_TIMEOUT, connection).await {
+            Ok(Ok(connection)) => {
+                info!(
+                    "Completed Handshake And SetupConnection of the additional connection with Pool at {:?}",
+                    pool
+                );
+                Ok(connection)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(minin_pool_connection::errors::Error::Timeout),
+        }
+    }
+
     /// Returns the sum all the latencies for a given upstream
     async fn get_latency(&self, pool_address: SocketAddr) -> Result<Duration, ()> {
         let mut pool = PoolLatency::new(pool_address);
@@ -278,9 +340,10 @@ impl PoolLatency {
         timer: Option<Duration>,
         authority_public_key: Secp256k1PublicKey,
     ) -> Result<(), ()> {
-        // Set open_sv2_mining_connection latency
+        // Set open_sv2_mining_connection latency, through the outbound proxy if any since it only
+        // replies once connected to the pool
         let open_sv2_mining_connection_timer = Instant::now();
-        match TcpStream::connect(self.pool).await {
+        match crate::outbound::connect(self.pool).await {
             Ok(stream) => {
                 self.open_sv2_mining_connection = Some(open_sv2_mining_connection_timer.elapsed());
 
@@ -371,12 +434,16 @@ impl PoolLatency {
         &mut self,
         authority_public_key: Secp256k1PublicKey,
     ) -> Result<(), ()> {
-        let address = self.pool;
+        // The JDS can be served by a different endpoint and authority than the mining pool
+        let address = crate::config::Configuration::jds_address().unwrap_or(self.pool);
+        let authority_public_key = crate::config::Configuration::jds_authority_public_key()
+            .unwrap_or(authority_public_key);
 
         // Set open_sv2_jd_connection latency
         let open_sv2_jd_connection_timer = Instant::now();
 
-        match tokio::time::timeout(Duration::from_secs(2), TcpStream::connect(address)).await {
+        match tokio::time::timeout(Duration::from_secs(2), crate::outbound::connect(address)).await
+        {
             Ok(Ok(stream)) => {
                 if let Some(_tp_addr) = crate::config::Configuration::tp_address() {
                     let initiator = Initiator::from_raw_k(authority_public_key.into_bytes())
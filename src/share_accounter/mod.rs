@@ -13,7 +13,10 @@ use task_manager::TaskManager;
 
 use crate::{
     proxy_state::{ProxyState, ShareAccounterState},
-    shared::utils::AbortOnDrop,
+    shared::{
+        supervisor::{self, Exit},
+        utils::AbortOnDrop,
+    },
     PoolState,
 };
 
@@ -29,13 +32,25 @@ pub async fn start(
         .safe_lock(|t| t.get_aborter())
         .map_err(|_| Error::ShareAccounterTaskManagerMutexCorrupted)?
         .ok_or(Error::ShareAccounterTaskManagerError)?;
+    // The relays are restarted on their own, so they share the receivers across the restarts. A
+    // relay whose channels are closed is not restarted, the pool connection or the JDC/translator
+    // it relays to is gone.
+    let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+    let up_receiver = Arc::new(tokio::sync::Mutex::new(up_receiver));
 
-    let relay_up_task = relay_up(receiver, up_sender, shares_sent_up.clone());
+    let relay_up_task = {
+        let shares_sent_up = shares_sent_up.clone();
+        supervisor::supervise("share_accounter relay_up", move || {
+            relay_up(receiver.clone(), up_sender.clone(), shares_sent_up.clone())
+        })
+    };
     TaskManager::add_relay_up(task_manager.clone(), relay_up_task)
         .await
         .map_err(|_| Error::ShareAccounterTaskManagerError)?;
 
-    let relay_down_task = relay_down(up_receiver, sender, shares_sent_up.clone());
+    let relay_down_task = supervisor::supervise("share_accounter relay_down", move || {
+        relay_down(up_receiver.clone(), sender.clone(), shares_sent_up.clone())
+    });
     TaskManager::add_relay_down(task_manager.clone(), relay_down_task)
         .await
         .map_err(|_| Error::ShareAccounterTaskManagerError)?;
@@ -47,81 +62,96 @@ struct ShareSentUp {
     sequence_number: u32,
 }
 
-fn relay_up(
-    mut receiver: tokio::sync::mpsc::Receiver<Mining<'static>>,
+async fn relay_up(
+    receiver: Arc<tokio::sync::Mutex<tokio::sync::mpsc::Receiver<Mining<'static>>>>,
     up_sender: tokio::sync::mpsc::Sender<PoolExtMessages<'static>>,
     shares_sent_up: Arc<DashMap<u32, ShareSentUp>>,
-) -> AbortOnDrop {
-    let task = tokio::spawn(async move {
-        while let Some(msg) = receiver.recv().await {
-            if let Mining::SubmitSharesExtended(m) = &msg {
-                shares_sent_up.insert(
-                    m.job_id,
-                    ShareSentUp {
-                        channel_id: m.channel_id,
-                        sequence_number: m.sequence_number,
-                    },
-                );
-            };
-            let msg = PoolExtMessages::Mining(msg);
-            if up_sender.send(msg).await.is_err() {
-                break;
-            }
+) -> Exit {
+    let mut receiver = receiver.lock().await;
+    while let Some(msg) = receiver.recv().await {
+        if let Mining::SubmitSharesExtended(m) = &msg {
+            shares_sent_up.insert(
+                m.job_id,
+                ShareSentUp {
+                    channel_id: m.channel_id,
+                    sequence_number: m.sequence_number,
+                },
+            );
+        };
+        let msg = PoolExtMessages::Mining(msg);
+        if up_sender.send(msg).await.is_err() {
+            error!("Share accounter relay_up: pool channel closed");
+            return Exit::Stop;
         }
-    });
-    task.into()
+    }
+    error!("Share accounter relay_up: downstream channel closed");
+    Exit::Stop
 }
 
-fn relay_down(
-    mut up_receiver: tokio::sync::mpsc::Receiver<PoolExtMessages<'static>>,
+async fn relay_down(
+    up_receiver: Arc<tokio::sync::Mutex<tokio::sync::mpsc::Receiver<PoolExtMessages<'static>>>>,
     sender: tokio::sync::mpsc::Sender<Mining<'static>>,
     shares_sent_up: Arc<DashMap<u32, ShareSentUp>>,
-) -> AbortOnDrop {
-    let task = tokio::spawn(async move {
-        while let Some(msg) = up_receiver.recv().await {
-            match msg {
-                PoolExtMessages::ShareAccountingMessages(msg) => {
-                    if let ShareAccountingMessages::ShareOk(msg) = msg {
-                        let job_id_bytes = msg.ref_job_id.to_le_bytes();
-                        let job_id = u32::from_le_bytes(job_id_bytes[4..8].try_into().expect("Internal error: job_id_bytes[4..8] can always be convertible into a u32"));
-                        let share_sent_up = match shares_sent_up.remove(&job_id) {
-                            Some(shares) => shares.1,
-                            // job_id doesn't exist
-                            None => {
-                                error!("Pool sent invalid share success");
-                                // Set global pool state to Down
-                                ProxyState::update_pool_state(PoolState::Down);
-                                return;
-                            }
-                        };
-
-                        let success = Mining::SubmitSharesSuccess(SubmitSharesSuccess {
-                            channel_id: share_sent_up.channel_id,
-                            last_sequence_number: share_sent_up.sequence_number,
-                            new_submits_accepted_count: 1,
-                            new_shares_sum: 1,
-                        });
-                        if let Err(e) = sender.send(success).await {
-                            error!("{e:?}");
-                            ProxyState::update_share_accounter_state(ShareAccounterState::Down);
-                            break;
+) -> Exit {
+    let mut up_receiver = up_receiver.lock().await;
+    while let Some(msg) = up_receiver.recv().await {
+        match msg {
+            PoolExtMessages::ShareAccountingMessages(msg) => {
+                if let ShareAccountingMessages::ShareOk(msg) = msg {
+                    let job_id_bytes = msg.ref_job_id.to_le_bytes();
+                    let job_id = u32::from_le_bytes(job_id_bytes[4..8].try_into().expect(
+                        "Internal error: job_id_bytes[4..8] can always be convertible into a u32",
+                    ));
+                    let share_sent_up = match shares_sent_up.remove(&job_id) {
+                        Some(shares) => shares.1,
+                        // job_id doesn't exist
+                        None => {
+                            error!("Pool sent invalid share success");
+                            // Set global pool state to Down
+                            ProxyState::update_pool_state(
+                                PoolState::Down,
+                                "Pool sent invalid share success",
+                            );
+                            return Exit::Stop;
                         }
                     };
-                }
-                PoolExtMessages::Mining(msg) => {
-                    if let Err(e) = sender.send(msg).await {
-                        error!("{e}");
-                        ProxyState::update_share_accounter_state(ShareAccounterState::Down);
-                        break;
+
+                    let success = Mining::SubmitSharesSuccess(SubmitSharesSuccess {
+                        channel_id: share_sent_up.channel_id,
+                        last_sequence_number: share_sent_up.sequence_number,
+                        new_submits_accepted_count: 1,
+                        new_shares_sum: 1,
+                    });
+                    if let Err(e) = sender.send(success).await {
+                        error!("{e:?}");
+                        ProxyState::update_share_accounter_state(
+                            ShareAccounterState::Down,
+                            &format!("{e:?}"),
+                        );
+                        return Exit::Stop;
                     }
-                }
-                _ => {
-                    error!("Pool send unexpected message on mining connection");
-                    ProxyState::update_pool_state(PoolState::Down);
-                    break;
+                };
+            }
+            PoolExtMessages::Mining(msg) => {
+                if let Err(e) = sender.send(msg).await {
+                    error!("{e}");
+                    ProxyState::update_share_accounter_state(
+                        ShareAccounterState::Down,
+                        &e.to_string(),
+                    );
+                    return Exit::Stop;
                 }
             }
+            _ => {
+                error!("Pool send unexpected message on mining connection");
+                ProxyState::update_pool_state(
+                    PoolState::Down,
+                    "Pool send unexpected message on mining connection",
+                );
+                return Exit::Stop;
+            }
         }
-    });
-    task.into()
+    }
+    error!("Share accounter relay_down: pool channel closed");
+    Exit::Stop
 }
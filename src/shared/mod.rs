@@ -3,4 +3,5 @@
 //!
 
 pub mod error;
+pub mod supervisor;
 pub mod utils;
@@ -0,0 +1,108 @@
+//! Restarts long running subsystems (API server, miners ingress, template provider, share
+//! accounter) when they exit, so that a failure in one of them does not require reinitializing
+//! the whole proxy. Panics abort the process so only a clean exit is observed here.
+
+use std::{future::Future, time::Duration};
+
+use tokio::time::Instant;
+use tracing::{error, info};
+
+use super::utils::AbortOnDrop;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Exponential backoff between restarts. A subsystem that has been running for longer than the
+/// max delay is considered stable, so the next restart starts over from the min delay.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    /// Returns how long to wait before restarting a subsystem that ran for `uptime`.
+    pub fn next_delay(&mut self, uptime: Duration) -> Duration {
+        if uptime >= self.max {
+            self.current = self.min;
+        }
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(MIN_BACKOFF, MAX_BACKOFF)
+    }
+}
+
+/// How a supervised subsystem exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// The subsystem failed and is restarted after the backoff delay
+    Restart,
+    /// The subsystem can not run anymore, e.g. its channels are closed, and is not restarted
+    Stop,
+}
+
+/// Subsystems that do not tell how they exited are always restarted
+impl From<()> for Exit {
+    fn from(_: ()) -> Self {
+        Exit::Restart
+    }
+}
+
+/// Runs the subsystem created by `start` in its own task and restarts it with exponential
+/// backoff every time it exits, until it returns `Exit::Stop`. Dropping the returned handle stops
+/// the subsystem.
+pub fn supervise<F, Fut>(name: &'static str, mut start: F) -> AbortOnDrop
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: Into<Exit>,
+{
+    tokio::spawn(async move {
+        let mut backoff = Backoff::default();
+        loop {
+            let started = Instant::now();
+            let exit: Exit = start().await.into();
+            if exit == Exit::Stop {
+                info!("{} stopped", name);
+                return;
+            }
+            let delay = backoff.next_delay(started.elapsed());
+            error!("{} exited, restarting it in {:?}", name, delay);
+            tokio::time::sleep(delay).await;
+            info!("Restarting {}", name);
+        }
+    })
+    .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_and_resets_when_stable() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        let short = Duration::from_millis(10);
+        let delays: Vec<u64> = (0..5)
+            .map(|_| backoff.next_delay(short).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 8]);
+        assert_eq!(backoff.next_delay(Duration::from_secs(8)).as_secs(), 1);
+        assert_eq!(backoff.next_delay(short).as_secs(), 2);
+    }
+}
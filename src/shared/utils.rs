@@ -27,6 +27,11 @@ impl AbortOnDrop {
     pub fn add_task<T: Send + 'static>(&mut self, handle: JoinHandle<T>) {
         self.abort_handle.push(handle.abort_handle());
     }
+
+    /// Takes over the tasks of `other`, they are aborted when `self` is dropped
+    pub fn merge(&mut self, mut other: AbortOnDrop) {
+        self.abort_handle.append(&mut other.abort_handle);
+    }
 }
 
 impl core::ops::Drop for AbortOnDrop {
@@ -43,6 +48,14 @@ impl<T: Send + 'static> From<JoinHandle<T>> for AbortOnDrop {
     }
 }
 
+impl From<AbortHandle> for AbortOnDrop {
+    fn from(abort_handle: AbortHandle) -> Self {
+        Self {
+            abort_handle: vec![abort_handle],
+        }
+    }
+}
+
 /// Select a version rolling mask and min bit count based on the request from the miner.
 /// It copy the behavior from SRI translator
 pub fn sv1_rolling(configure: &sv1_api::client_to_server::Configure) -> (HexU32Be, HexU32Be) {
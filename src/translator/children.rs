@@ -0,0 +1,399 @@
+//! SV2 extended channels served to child dmnd-clients.
+//!
+//! With `--child-listen-address` other dmnd-clients can use this one as their pool, e.g. one per
+//! building of a site behind a regional one, so that the site has a single pool connection, a
+//! single job declaration and a single share accounting path. Each child opens extended channels
+//! on the channel factory of the `Bridge`, so it gets an extranonce prefix carved out of the pool
+//! channel like the SV1 miners do, and the jobs of the pool channel are relayed to it as they are.
+//! Shares of the children are checked against their channel and sent to the pool with the ones of
+//! the SV1 miners.
+//!
+//! The children are not authenticated, their shares are credited to the account of this proxy:
+//! `--child-allowed-ips` restricts the hosts that can connect.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use codec_sv2::HandshakeRole;
+use demand_share_accounting_ext::parser::PoolExtMessages;
+use demand_sv2_connection::noise_connection_tokio::Connection;
+use noise_sv2::Responder;
+use roles_logic_sv2::{
+    common_messages_sv2::SetupConnectionSuccess,
+    mining_sv2::{OpenMiningChannelError, SetTarget},
+    parsers::{CommonMessages, Mining},
+    utils::Mutex,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+};
+use tracing::{error, info, warn};
+
+use super::{proxy::Bridge, upstream::diff_management::UpstreamDifficultyConfig};
+use crate::{
+    api::stats::StatsSender,
+    config::Configuration,
+    minin_pool_connection::{EitherFrame, StdFrame},
+    shared::utils::AbortOnDrop,
+};
+
+/// Extranonce left to the children after the prefix of their channel: what a child needs for
+/// the extranonce1 it gives to its own miners, see `proxy_extranonce1_len`, plus the extranonce2
+/// of the miners. When the children are served the SV1 miners get the same extranonce2 size.
+pub const EXTRANONCE_SIZE: u16 = CHILD_EXTRANONCE1_SIZE + crate::MIN_EXTRANONCE2_SIZE;
+/// Enough for 16M connections of miners to a child before its extranonce1 wraps around
+const CHILD_EXTRANONCE1_SIZE: u16 = 3;
+const CERT_VALIDITY: Duration = Duration::from_secs(3600);
+const SETUP_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts to accept the children if `--child-listen-address` is set.
+pub fn start(
+    bridge: Arc<Mutex<Bridge>>,
+    diff_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    stats_sender: StatsSender,
+    connection_id_offset: u32,
+) -> Option<AbortOnDrop> {
+    let address = Configuration::child_listen_address()?;
+    let task = tokio::spawn(listen(
+        address,
+        bridge,
+        diff_config,
+        stats_sender,
+        connection_id_offset,
+    ));
+    Some(task.into())
+}
+
+async fn listen(
+    address: SocketAddr,
+    bridge: Arc<Mutex<Bridge>>,
+    diff_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    stats_sender: StatsSender,
+    connection_id_offset: u32,
+) {
+    let listener = match TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(
+                "Impossible to listen for child dmnd-clients on {}: {}",
+                address, e
+            );
+            return;
+        }
+    };
+    info!("Listening for child dmnd-clients on {}", address);
+    let allowed_ips = Configuration::child_allowed_ips();
+    if allowed_ips.is_empty() {
+        warn!(
+            "Any host that can reach {} can mine as a child dmnd-client, use --child-allowed-ips to restrict it",
+            address
+        );
+    }
+    // Children are aborted with the listener, when the translator restarts
+    let mut children: Vec<AbortOnDrop> = Vec::new();
+    while let Ok((stream, child)) = listener.accept().await {
+        // The shares of the children are credited to the account of this proxy
+        if !allowed_ips.is_empty() && !allowed_ips.contains(&child.ip()) {
+            warn!("Rejected child dmnd-client from {}: not allowed", child);
+            continue;
+        }
+        info!("Child dmnd-client connected from {}", child);
+        children.retain(|child| !child.is_finished());
+        let child = Child {
+            address: child,
+            bridge: bridge.clone(),
+            diff_config: diff_config.clone(),
+            stats_sender: stats_sender.clone(),
+            connection_id_offset,
+            channels: HashMap::new(),
+        };
+        children.push(tokio::spawn(child.serve(stream)).into());
+    }
+}
+
+/// Connection with a child dmnd-client
+struct Child {
+    address: SocketAddr,
+    bridge: Arc<Mutex<Bridge>>,
+    diff_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    stats_sender: StatsSender,
+    connection_id_offset: u32,
+    /// Nominal hashrate of each channel opened by the child
+    channels: HashMap<u32, f32>,
+}
+
+impl Child {
+    async fn serve(mut self, stream: TcpStream) {
+        let Some((public_key, secret_key)) = Configuration::authority_keys() else {
+            error!("Authority keys are missing, can not serve child dmnd-clients");
+            return;
+        };
+        let responder = match Responder::from_authority_kp(
+            &public_key.into_bytes(),
+            &secret_key.into_bytes(),
+            CERT_VALIDITY,
+        ) {
+            Ok(responder) => responder,
+            Err(e) => {
+                error!("Invalid authority keys: {:?}", e);
+                return;
+            }
+        };
+        let (mut receiver, sender, _, _) =
+            match Connection::new(stream, HandshakeRole::Responder(responder)).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Handshake with child {} failed: {:?}", self.address, e);
+                    return;
+                }
+            };
+        if let Err(e) = self.setup_connection(&mut receiver, &sender).await {
+            warn!(
+                "Failed to setup connection with child {}: {}",
+                self.address, e
+            );
+            return;
+        }
+
+        // Nothing is relayed before the first channel is opened
+        let mut jobs: Option<broadcast::Receiver<Mining<'static>>> = None;
+        loop {
+            tokio::select! {
+                frame = receiver.recv() => {
+                    let Some(frame) = frame else { break };
+                    let message = match parse(frame) {
+                        Ok(PoolExtMessages::Mining(message)) => message,
+                        _ => {
+                            warn!("Child {} sent an unexpected message", self.address);
+                            break;
+                        }
+                    };
+                    let responses = match self.on_message(message, &mut jobs).await {
+                        Ok(responses) => responses,
+                        Err(e) => {
+                            error!("Failed to handle message of child {}: {}", self.address, e);
+                            break;
+                        }
+                    };
+                    if send(&sender, responses).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(message) = recv_job(&mut jobs) => {
+                    // The jobs of the pool channel are valid for every channel of the factory
+                    let messages = self.channels.keys().map(|&channel_id| {
+                        let mut message = message.clone();
+                        match &mut message {
+                            Mining::NewExtendedMiningJob(job) => job.channel_id = channel_id,
+                            Mining::SetNewPrevHash(prev_hash) => prev_hash.channel_id = channel_id,
+                            _ => (),
+                        }
+                        message
+                    });
+                    if send(&sender, messages.collect()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        info!("Child dmnd-client {} disconnected", self.address);
+        self.close_channels();
+    }
+
+    async fn setup_connection(
+        &self,
+        receiver: &mut mpsc::Receiver<EitherFrame>,
+        sender: &mpsc::Sender<EitherFrame>,
+    ) -> Result<(), String> {
+        let frame = tokio::time::timeout(SETUP_CONNECTION_TIMEOUT, receiver.recv())
+            .await
+            .map_err(|_| "timeout".to_string())?
+            .ok_or("connection closed")?;
+        match parse(frame)? {
+            PoolExtMessages::Common(CommonMessages::SetupConnection(m)) => {
+                let success = SetupConnectionSuccess {
+                    used_version: m.min_version,
+                    flags: 0,
+                };
+                let message =
+                    PoolExtMessages::Common(CommonMessages::SetupConnectionSuccess(success));
+                send_frame(sender, message).await
+            }
+            _ => Err("expected SetupConnection".to_string()),
+        }
+    }
+
+    async fn on_message(
+        &mut self,
+        message: Mining<'static>,
+        jobs: &mut Option<broadcast::Receiver<Mining<'static>>>,
+    ) -> Result<Vec<Mining<'static>>, String> {
+        match message {
+            Mining::OpenExtendedMiningChannel(m) => {
+                let hash_rate = m.nominal_hash_rate;
+                let opened = self
+                    .bridge
+                    .safe_lock(|b| {
+                        b.on_new_sv2_channel(m.request_id, hash_rate, m.min_extranonce_size)
+                    })
+                    .map_err(|_| "Bridge Mutex Corrupted".to_string())?;
+                let (messages, receiver) = match opened {
+                    Ok(opened) => opened,
+                    Err(e) => {
+                        warn!("Failed to open a channel for child {}: {}", self.address, e);
+                        let error = OpenMiningChannelError {
+                            request_id: m.request_id,
+                            error_code: "max-target-out-of-range"
+                                .to_string()
+                                .try_into()
+                                .expect("Internal error: this operation can not fail because the error code is shorter than 255 bytes"),
+                        };
+                        return Ok(vec![Mining::OpenMiningChannelError(error)]);
+                    }
+                };
+                if let Some(Mining::OpenExtendedMiningChannelSuccess(success)) = messages.first() {
+                    let connection_id = success.channel_id + self.connection_id_offset;
+                    self.stats_sender.setup_stats(connection_id);
+                    self.stats_sender
+                        .update_device_name(connection_id, "dmnd-client".to_string());
+                    self.stats_sender
+                        .update_worker_name(connection_id, format!("child {}", self.address));
+                    self.update_hashrate(success.channel_id, hash_rate)?;
+                }
+                jobs.get_or_insert(receiver);
+                Ok(messages)
+            }
+            Mining::UpdateChannel(m) if self.channels.contains_key(&m.channel_id) => {
+                self.update_hashrate(m.channel_id, m.nominal_hash_rate)?;
+                let target = self
+                    .bridge
+                    .safe_lock(|b| b.on_update_sv2_channel(m.channel_id, m.nominal_hash_rate))
+                    .map_err(|_| "Bridge Mutex Corrupted".to_string())?;
+                match target {
+                    Ok(maximum_target) => Ok(vec![Mining::SetTarget(SetTarget {
+                        channel_id: m.channel_id,
+                        maximum_target,
+                    })]),
+                    // A curtailed child declares no hashrate, it keeps its target
+                    Err(_) => Ok(vec![]),
+                }
+            }
+            Mining::SubmitSharesExtended(m) if self.channels.contains_key(&m.channel_id) => {
+                let connection_id = m.channel_id + self.connection_id_offset;
+                let difficulty = self.difficulty(m.channel_id);
+                let worker_name = format!("child {}", self.address);
+                let response = Bridge::on_sv2_share(&self.bridge, m, worker_name, connection_id)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                match response {
+                    Mining::SubmitSharesSuccess(_) => self
+                        .stats_sender
+                        .update_accepted_shares(connection_id, difficulty),
+                    _ => self.stats_sender.update_rejected_shares(connection_id),
+                }
+                Ok(vec![response])
+            }
+            m => {
+                warn!("Ignoring message {:?} of child {}", m, self.address);
+                Ok(vec![])
+            }
+        }
+    }
+
+    /// Updates the nominal hashrate of the pool channel with the one of a channel of the child
+    fn update_hashrate(&mut self, channel_id: u32, hash_rate: f32) -> Result<(), String> {
+        let previous = self.channels.insert(channel_id, hash_rate).unwrap_or(0.0);
+        self.diff_config
+            .safe_lock(|c| {
+                c.channel_nominal_hashrate =
+                    (c.channel_nominal_hashrate + hash_rate - previous).max(0.0)
+            })
+            .map_err(|_| "Difficulty config Mutex Corrupted".to_string())?;
+        let connection_id = channel_id + self.connection_id_offset;
+        self.stats_sender.update_hashrate(connection_id, hash_rate);
+        self.stats_sender
+            .update_diff(connection_id, self.difficulty(channel_id));
+        Ok(())
+    }
+
+    /// Difficulty of the shares of a channel, derived from its nominal hashrate like its target
+    fn difficulty(&self, channel_id: u32) -> f32 {
+        let hash_rate = self.channels.get(&channel_id).copied().unwrap_or(0.0);
+        hash_rate / (*crate::SHARE_PER_MIN / 60.0 * 2f32.powi(32))
+    }
+
+    fn close_channels(&mut self) {
+        for (channel_id, hash_rate) in self.channels.drain() {
+            if self
+                .diff_config
+                .safe_lock(|c| {
+                    c.channel_nominal_hashrate -= f32::min(hash_rate, c.channel_nominal_hashrate)
+                })
+                .is_err()
+            {
+                error!("Difficulty config Mutex Corrupted");
+            }
+            self.stats_sender
+                .remove_stats(channel_id + self.connection_id_offset);
+        }
+    }
+}
+
+async fn recv_job(
+    jobs: &mut Option<broadcast::Receiver<Mining<'static>>>,
+) -> Result<Mining<'static>, broadcast::error::RecvError> {
+    match jobs {
+        Some(jobs) => jobs.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn parse(frame: EitherFrame) -> Result<PoolExtMessages<'static>, String> {
+    let mut frame: StdFrame = frame.try_into().map_err(|_| "invalid frame".to_string())?;
+    let header = frame.get_header().ok_or("frame without header")?;
+    let message: PoolExtMessages<'_> = (header.ext_type(), header.msg_type(), frame.payload())
+        .try_into()
+        .map_err(|e| format!("invalid message: {:?}", e))?;
+    Ok(message.into_static())
+}
+
+async fn send_frame(
+    sender: &mpsc::Sender<EitherFrame>,
+    message: PoolExtMessages<'static>,
+) -> Result<(), String> {
+    let frame: StdFrame = message
+        .try_into()
+        .map_err(|e| format!("invalid message: {:?}", e))?;
+    sender
+        .send(frame.into())
+        .await
+        .map_err(|_| "connection closed".to_string())
+}
+
+async fn send(
+    sender: &mpsc::Sender<EitherFrame>,
+    messages: Vec<Mining<'static>>,
+) -> Result<(), ()> {
+    for message in messages {
+        if let Err(e) = send_frame(sender, PoolExtMessages::Mining(message)).await {
+            warn!("Failed to send to child: {}", e);
+            return Err(());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn leaves_room_for_the_miners_of_the_children() {
+        // What the translator of a child, without signature, needs from its channel
+        let child_extranonce1 = crate::translator::upstream::upstream::proxy_extranonce1_len(
+            EXTRANONCE_SIZE as usize,
+            crate::MIN_EXTRANONCE2_SIZE as usize,
+        );
+        assert_eq!(child_extranonce1, CHILD_EXTRANONCE1_SIZE as usize);
+    }
+}
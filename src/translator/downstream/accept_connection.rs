@@ -105,7 +105,10 @@ pub async fn start_accept_connection(
                     }
                     Err(e) => {
                         error!("{e:?}");
-                        ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                        ProxyState::update_downstream_state(
+                            DownstreamType::TranslatorDownstream,
+                            &format!("{e:?}"),
+                        );
                         break;
                     }
                 }
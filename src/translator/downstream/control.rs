@@ -0,0 +1,123 @@
+use super::downstream::Downstream;
+use crate::translator::error::Error;
+use lazy_static::lazy_static;
+use roles_logic_sv2::utils::Mutex;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::mpsc::Sender;
+use tracing::error;
+
+struct DownstreamHandle {
+    downstream: Arc<Mutex<Downstream>>,
+    disconnect: Sender<()>,
+}
+
+lazy_static! {
+    // Connected downstreams by connection id, used by the control API to act on a single miner
+    static ref DOWNSTREAMS: Mutex<HashMap<u32, DownstreamHandle>> = Mutex::new(HashMap::new());
+}
+
+pub(super) fn register(
+    connection_id: u32,
+    downstream: Arc<Mutex<Downstream>>,
+    disconnect: Sender<()>,
+) {
+    if DOWNSTREAMS
+        .safe_lock(|d| {
+            d.insert(
+                connection_id,
+                DownstreamHandle {
+                    downstream,
+                    disconnect,
+                },
+            )
+        })
+        .is_err()
+    {
+        error!("Downstreams registry Mutex Poisoned");
+    }
+}
+
+/// Unregisters the downstream when dropped, so that it is unregistered also when its tasks are
+/// aborted.
+pub(super) struct Registration(pub(super) u32);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if DOWNSTREAMS.safe_lock(|d| d.remove(&self.0)).is_err() {
+            error!("Downstreams registry Mutex Poisoned");
+        }
+    }
+}
+
+/// Called when the translator starts, its downstreams of a previous run are gone.
+pub fn clear(connection_ids: std::ops::Range<u32>) {
+    if DOWNSTREAMS
+        .safe_lock(|d| d.retain(|id, _| !connection_ids.contains(id)))
+        .is_err()
+    {
+        error!("Downstreams registry Mutex Poisoned");
+    }
+}
+
+/// Closes the connection with the downstream. Returns false if there is no downstream with
+/// `connection_id`.
+pub fn disconnect(connection_id: u32) -> Result<bool, Error<'static>> {
+    let sender = DOWNSTREAMS
+        .safe_lock(|d| d.get(&connection_id).map(|h| h.disconnect.clone()))
+        .map_err(|_| Error::PoisonLock)?;
+    match sender {
+        Some(sender) => {
+            // If the channel is full a disconnection is already pending
+            let _ = sender.try_send(());
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Overrides the difficulty of the downstream, `None` gives it back to vardiff. Returns false if
+/// there is no downstream with `connection_id`.
+pub async fn set_difficulty(
+    connection_id: u32,
+    difficulty: Option<f32>,
+) -> Result<bool, Error<'static>> {
+    let downstream = DOWNSTREAMS
+        .safe_lock(|d| d.get(&connection_id).map(|h| h.downstream.clone()))
+        .map_err(|_| Error::PoisonLock)?;
+    match downstream {
+        Some(downstream) => {
+            Downstream::set_fixed_difficulty(&downstream, difficulty).await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Connection ids of the connected downstreams
+pub fn connection_ids() -> Vec<u32> {
+    DOWNSTREAMS
+        .safe_lock(|d| d.keys().copied().collect())
+        .unwrap_or_else(|_| {
+            error!("Downstreams registry Mutex Poisoned");
+            Vec::new()
+        })
+}
+
+/// Stops or resumes sending jobs to the downstream, see `Downstream::set_paused`. Returns false
+/// if there is no downstream with `connection_id`.
+pub async fn set_paused(
+    connection_id: u32,
+    paused: bool,
+    difficulty: Option<f32>,
+) -> Result<bool, Error<'static>> {
+    let downstream = DOWNSTREAMS
+        .safe_lock(|d| d.get(&connection_id).map(|h| h.downstream.clone()))
+        .map_err(|_| Error::PoisonLock)?;
+    match downstream {
+        Some(downstream) => {
+            Downstream::set_paused(&downstream, paused, difficulty).await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
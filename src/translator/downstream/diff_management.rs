@@ -1,4 +1,5 @@
 use super::{Downstream, DownstreamMessages, SetDownstreamTarget};
+use crate::events::{self, EventKind};
 use pid::Pid;
 use roles_logic_sv2::{self, utils::from_u128_to_u256};
 use sv1_api::{self, methods::server_to_client::SetDifficulty};
@@ -131,6 +132,90 @@ impl Downstream {
         Ok(())
     }
 
+    /// Pins the downstream difficulty to `difficulty` and sends it to the miner right away. With
+    /// `None` the difficulty goes back under the control of the PID controller.
+    pub async fn set_fixed_difficulty(
+        self_: &Arc<Mutex<Self>>,
+        difficulty: Option<f32>,
+    ) -> ProxyResult<'static, ()> {
+        let channel_id = self_.safe_lock(|d| {
+            d.difficulty_mgmt.fixed_difficulty = difficulty;
+            d.connection_id
+        })?;
+        if let Some(difficulty) = difficulty {
+            info!(
+                "Difficulty for downstream {} fixed to {}",
+                channel_id, difficulty
+            );
+            let new_estimation =
+                Self::estimate_hash_rate_from_difficulty(difficulty, *crate::SHARE_PER_MIN);
+            Self::update_self_with_new_hash_rate(self_, new_estimation, difficulty)?;
+            Self::update_diff_setting(self_, channel_id, difficulty.into()).await?;
+        } else {
+            info!(
+                "Difficulty for downstream {} is back to vardiff",
+                channel_id
+            );
+        }
+        Ok(())
+    }
+
+    /// Stops or resumes sending jobs to the downstream. When pausing with a `difficulty` it is sent
+    /// to the miner so that it stops submitting shares on the last job; on resume the vardiff
+    /// difficulty and the last job are sent again.
+    pub async fn set_paused(
+        self_: &Arc<Mutex<Self>>,
+        paused: bool,
+        difficulty: Option<f32>,
+    ) -> ProxyResult<'static, ()> {
+        let (changed, channel_id, current_diff) = self_.safe_lock(|d| {
+            let changed = d.paused != paused;
+            d.paused = paused;
+            (
+                changed,
+                d.connection_id,
+                d.difficulty_mgmt
+                    .current_difficulties
+                    .back()
+                    .copied()
+                    .unwrap_or(d.difficulty_mgmt.initial_difficulty),
+            )
+        })?;
+        if !changed {
+            return Ok(());
+        }
+        if paused {
+            info!("Downstream {} paused", channel_id);
+            if let Some(difficulty) = difficulty {
+                let (message, _) = diff_to_sv1_message(difficulty as f64)?;
+                Downstream::send_message_downstream(self_.clone(), message).await;
+            }
+        } else {
+            info!("Downstream {} resumed", channel_id);
+            Self::update_diff_setting(self_, channel_id, current_diff.into()).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the first job to the miner. A miner that connected while curtailed gets the
+    /// curtailment difficulty instead, the job is sent when it is resumed.
+    pub(super) async fn send_first_job(
+        self_: &Arc<Mutex<Self>>,
+        job: sv1_api::server_to_client::Notify<'static>,
+    ) -> ProxyResult<'static, ()> {
+        let (paused, connection_id) = self_.safe_lock(|d| (d.paused, d.connection_id))?;
+        let message = match paused {
+            true => {
+                info!("Downstream {} connected while curtailed", connection_id);
+                diff_to_sv1_message(crate::config::Configuration::curtailment_difficulty() as f64)?
+                    .0
+            }
+            false => job.into(),
+        };
+        Downstream::send_message_downstream(self_.clone(), message).await;
+        Ok(())
+    }
+
     /// Increments the number of shares since the last difficulty update.
     pub(super) fn save_share(self_: Arc<Mutex<Self>>) -> ProxyResult<'static, ()> {
         self_.safe_lock(|d| {
@@ -189,6 +274,11 @@ impl Downstream {
 
         self_.safe_lock(|d| d.last_call_to_update_hr = timestamp_millis)?;
 
+        // Difficulty has been pinned by the operator, PID controller is paused
+        if difficulty_mgmt.fixed_difficulty.is_some() {
+            return Ok(None);
+        }
+
         let realized_share_per_min = match difficulty_mgmt.share_count() {
             Some(value) => value,
             // we need at least 2 seconds of data
@@ -262,8 +352,8 @@ impl Downstream {
         new_estimation: f32,
         current_diff: f32,
     ) -> ProxyResult<'static, ()> {
-        let (upstream_difficulty_config, old_estimation, connection_id, stats_sender) = self_
-            .safe_lock(|d| {
+        let (upstream_difficulty_config, old_estimation, connection_id, stats_sender, worker_name) =
+            self_.safe_lock(|d| {
                 let old_estimation = d.difficulty_mgmt.estimated_downstream_hash_rate;
                 d.difficulty_mgmt.estimated_downstream_hash_rate = new_estimation;
                 d.difficulty_mgmt.reset();
@@ -274,10 +364,16 @@ impl Downstream {
                     old_estimation,
                     d.connection_id,
                     d.stats_sender.clone(),
+                    d.authorized_names.first().cloned(),
                 )
             })?;
         stats_sender.update_hashrate(connection_id, new_estimation);
         stats_sender.update_diff(connection_id, current_diff);
+        events::publish(EventKind::DifficultyChanged {
+            connection_id,
+            worker_name,
+            difficulty: current_diff,
+        });
         let hash_rate_delta = new_estimation - old_estimation;
         upstream_difficulty_config.safe_lock(|c| {
             if (c.channel_nominal_hashrate + hash_rate_delta) > 0.0 {
@@ -437,6 +533,7 @@ mod test {
             current_difficulties: diff,
             submits: VecDeque::new(),
             initial_difficulty: 10_000_000_000.0,
+            fixed_difficulty: None,
         };
         let upstream_config = UpstreamDifficultyConfig {
             channel_diff_update_interval: 60,
@@ -1,13 +1,19 @@
 use crate::{
     api::stats::StatsSender,
+    blocks,
     config::Configuration,
+    events::{self, EventKind},
+    jd_client::block_submitter::SubmissionResult,
     monitor::{
         shares::{RejectionReason, ShareInfo, SharesMonitor},
         worker_activity::{WorkerActivity, WorkerActivityType},
     },
     proxy_state::{DownstreamType, ProxyState},
     shared::utils::AbortOnDrop,
-    translator::{error::Error, utils::validate_share},
+    translator::{
+        error::Error,
+        utils::{validate_share, ValidShare},
+    },
 };
 
 use super::{
@@ -51,6 +57,8 @@ pub struct DownstreamDifficultyConfig {
     pub pid_controller: Pid<f32>,
     pub current_difficulties: VecDeque<f32>,
     pub initial_difficulty: f32,
+    /// Difficulty set by the operator through the control API, when set vardiff is disabled.
+    pub fixed_difficulty: Option<f32>,
 }
 
 impl DownstreamDifficultyConfig {
@@ -122,6 +130,8 @@ pub struct Downstream {
     pub first_job: Notify<'static>,
     pub share_monitor: SharesMonitor,
     pub user_agent: std::cell::RefCell<String>, // RefCell is used here because `handle_subscribe` and `handle_authorize` take &self not &mut self and we need to mutate user_agent
+    /// Set while the downstream is curtailed, no jobs are sent to it
+    pub(super) paused: bool,
 }
 
 impl Downstream {
@@ -184,6 +194,7 @@ impl Downstream {
             pid_controller: pid,
             current_difficulties,
             initial_difficulty,
+            fixed_difficulty: None,
         };
 
         let downstream = Arc::new(Mutex::new(Downstream {
@@ -203,18 +214,27 @@ impl Downstream {
             first_job: last_notify.expect("we have an assertion at the beginning of this function"),
             share_monitor: SharesMonitor::new(),
             user_agent: std::cell::RefCell::new(String::new()),
+            // Otherwise the first job would be sent before the curtailment pauses it
+            paused: crate::curtailment::is_paused(connection_id),
         }));
 
+        let (disconnect_tx, disconnect_rx) = channel(1);
+        super::control::register(connection_id, downstream.clone(), disconnect_tx);
+
         if let Err(e) = start_receive_downstream(
             task_manager.clone(),
             downstream.clone(),
             recv_from_down,
+            disconnect_rx,
             connection_id,
         )
         .await
         {
             error!("Failed to start receive downstream task: {e}");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                &format!("Failed to start receive downstream task: {e}"),
+            );
         };
 
         if let Err(e) = start_send_to_downstream(
@@ -227,7 +247,10 @@ impl Downstream {
         .await
         {
             error!("Failed to start send_to_downstream task {e}");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                &format!("Failed to start send_to_downstream task {e}"),
+            );
         };
 
         if let Err(e) = start_notify(
@@ -240,12 +263,18 @@ impl Downstream {
         .await
         {
             error!("Failed to start notify task: {e}");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                &format!("Failed to start notify task: {e}"),
+            );
         };
 
         if let Err(e) = Self::start_share_monitor(task_manager.clone(), downstream.clone()).await {
             error!("Failed to start share monitor task: {e}");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                &format!("Failed to start share monitor task: {e}"),
+            );
         }
     }
 
@@ -296,7 +325,10 @@ impl Downstream {
         .await
         {
             error!("Translator downstream failed to accept: {e}");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                &format!("Translator downstream failed to accept: {e}"),
+            );
             return Err(e);
         };
         Ok(abortable)
@@ -347,7 +379,10 @@ impl Downstream {
             Err(e) => {
                 // Poisoned mutex
                 error!("{e}");
-                ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                ProxyState::update_downstream_state(
+                    DownstreamType::TranslatorDownstream,
+                    &e.to_string(),
+                );
                 return;
             }
         };
@@ -362,15 +397,28 @@ impl Downstream {
             Err(e) => {
                 error!("{e}");
                 // Poisoned mutex
-                ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                ProxyState::update_downstream_state(
+                    DownstreamType::TranslatorDownstream,
+                    &e.to_string(),
+                );
                 return;
             }
         };
         if sender.send(msg).await.is_err() {
             error!("Translator downstream failed to send message");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                "Translator downstream failed to send message",
+            );
         }
     }
+    /// Saves the share for the monitoring server and publishes it on the events stream.
+    fn record_share(&self, share: ShareInfo) {
+        events::publish(share.as_event(self.connection_id));
+        let share = share.on_upstream(crate::translator::upstream_index(self.connection_id));
+        self.share_monitor.insert_share(share);
+    }
+
     #[cfg(test)]
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -406,6 +454,7 @@ impl Downstream {
             recent_jobs: RecentJobs::new(),
             share_monitor: SharesMonitor::new(),
             user_agent: std::cell::RefCell::new(String::new()),
+            paused: false,
         }
     }
 }
@@ -464,11 +513,19 @@ impl IsServer<'static> for Downstream {
     fn handle_authorize(&self, request: &client_to_server::Authorize) -> bool {
         if self.authorized_names.is_empty() {
             let user_agent = self.user_agent.borrow().clone();
+            events::publish(EventKind::MinerConnected {
+                connection_id: self.connection_id,
+                worker_name: request.name.clone(),
+                user_agent: user_agent.clone(),
+            });
+            self.stats_sender
+                .update_worker_name(self.connection_id, request.name.clone());
             let worker_activity = WorkerActivity::new(
                 user_agent,
                 request.name.clone(),
                 WorkerActivityType::Connected,
-            );
+            )
+            .on_upstream(crate::translator::upstream_index(self.connection_id));
 
             tokio::spawn(async move {
                 if let Err(e) = worker_activity
@@ -516,7 +573,7 @@ impl IsServer<'static> for Downstream {
                 // job_id_as_number.expect("checked above") as i64,
                 Some(RejectionReason::InvalidJobIdFormat),
             );
-            self.share_monitor.insert_share(share);
+            self.record_share(share);
 
             self.stats_sender.update_rejected_shares(self.connection_id);
             return false;
@@ -529,52 +586,77 @@ impl IsServer<'static> for Downstream {
         {
             request.job_id = job.job_id.clone();
             //check share is valid
-            if let Some(met_difficulty) = validate_share(
+            if let Some(ValidShare {
+                difficulty: met_difficulty,
+                block,
+            }) = validate_share(
                 &request,
                 &job,
                 &self.difficulty_mgmt.current_difficulties,
                 self.extranonce1.clone(),
                 self.version_rolling_mask.clone(),
             ) {
-                // Only forward upstream if the share meets the latest difficulty
-                if let Some(latest_difficulty) = self.difficulty_mgmt.current_difficulties.back() {
-                    if met_difficulty == *latest_difficulty {
-                        let to_send = SubmitShareWithChannelId {
-                            channel_id: self.connection_id,
-                            share: request.clone(),
-                            extranonce: self.extranonce1.clone(),
-                            extranonce2_len: self.extranonce2_len,
-                            version_rolling_mask: self.version_rolling_mask.clone(),
-                        };
-                        if let Err(e) = self
-                            .tx_sv1_bridge
-                            .try_send(DownstreamMessages::SubmitShares(to_send))
-                        {
-                            error!("Failed to start receive downstream task: {e:?}");
-                            self.stats_sender.update_rejected_shares(self.connection_id);
-                            // Return false because submit was not properly handled
-                            return false;
-                        }
-                        // Share is accepted here
-                        let share = ShareInfo::new(
-                            request.user_name.clone(),
-                            Some(met_difficulty),
-                            job_id,
-                            None,
-                        );
-                        self.share_monitor.insert_share(share);
-                    } else {
-                        // met_difficulty is not latest difficulty, so we mark it as rejected
-                        let share = ShareInfo::new(
+                let latest_difficulty = self.difficulty_mgmt.current_difficulties.back().copied();
+                // Only forward upstream if the share meets the latest difficulty, blocks are
+                // always forwarded
+                if block.is_some() || latest_difficulty == Some(met_difficulty) {
+                    let to_send = SubmitShareWithChannelId {
+                        channel_id: self.connection_id,
+                        share: request.clone(),
+                        extranonce: self.extranonce1.clone(),
+                        extranonce2_len: self.extranonce2_len,
+                        version_rolling_mask: self.version_rolling_mask.clone(),
+                        block_hash: block.as_ref().map(|block| block.block_hash),
+                    };
+                    let message = DownstreamMessages::SubmitShares(to_send);
+                    if let Some(block) = block {
+                        blocks::record_found(
                             request.user_name.clone(),
-                            None,
-                            job_id, // rejected because it was not sent upstream
-                            Some(RejectionReason::DifficultyMismatch),
+                            self.connection_id,
+                            block.block_hash,
+                            &block.coinbase,
                         );
-                        self.share_monitor.insert_share(share);
+                        // A full channel must not drop a block, so it is awaited
+                        let tx_sv1_bridge = self.tx_sv1_bridge.clone();
+                        tokio::spawn(async move {
+                            if tx_sv1_bridge.send(message).await.is_err() {
+                                error!("Failed to send block to the bridge");
+                                blocks::record_submission(
+                                    block.block_hash,
+                                    "upstream",
+                                    SubmissionResult::Failed {
+                                        error: "bridge channel closed".to_string(),
+                                    },
+                                );
+                            }
+                        });
+                    } else if let Err(e) = self.tx_sv1_bridge.try_send(message) {
+                        error!("Failed to start receive downstream task: {e:?}");
+                        self.stats_sender.update_rejected_shares(self.connection_id);
+                        // Return false because submit was not properly handled
+                        return false;
                     }
+                    // Share is accepted here
+                    let share = ShareInfo::new(
+                        request.user_name.clone(),
+                        Some(met_difficulty),
+                        job_id,
+                        None,
+                    );
+                    self.record_share(share);
+                } else if latest_difficulty.is_some() {
+                    // met_difficulty is not latest difficulty, so we mark it as rejected for the
+                    // monitor, it is still accepted for the miner
+                    let share = ShareInfo::new(
+                        request.user_name.clone(),
+                        Some(met_difficulty),
+                        job_id, // rejected because it was not sent upstream
+                        Some(RejectionReason::DifficultyMismatch),
+                    );
+                    self.record_share(share);
                 }
-                self.stats_sender.update_accepted_shares(self.connection_id);
+                self.stats_sender
+                    .update_accepted_shares(self.connection_id, met_difficulty);
                 info!(
                     "Share for Job {} and difficulty {} is accepted",
                     request.job_id, met_difficulty
@@ -587,7 +669,7 @@ impl IsServer<'static> for Downstream {
                     job_id,
                     Some(RejectionReason::InvalidShare),
                 );
-                self.share_monitor.insert_share(share);
+                self.record_share(share);
                 error!("Share rejected: Invalid share");
                 self.stats_sender.update_rejected_shares(self.connection_id);
                 false
@@ -599,12 +681,12 @@ impl IsServer<'static> for Downstream {
                 job_id,
                 Some(RejectionReason::JobIdNotFound),
             );
-            self.share_monitor.insert_share(event);
+            self.record_share(event);
             error!(
                 "Share rejected: can not find job with id {}",
                 request.job_id
             );
-            self.stats_sender.update_rejected_shares(self.connection_id);
+            self.stats_sender.update_stale_shares(self.connection_id);
             false
         }
     }
@@ -1,3 +1,4 @@
+use bitcoin::BlockHash;
 use roles_logic_sv2::mining_sv2::Target;
 use sv1_api::{client_to_server::Submit, utils::HexU32Be};
 pub mod diff_management;
@@ -5,6 +6,7 @@ pub mod diff_management;
 pub mod downstream;
 pub use downstream::Downstream;
 mod accept_connection;
+pub mod control;
 mod notify;
 mod receive_from_downstream;
 mod send_to_downstream;
@@ -36,6 +38,9 @@ pub struct SubmitShareWithChannelId {
     #[allow(dead_code)]
     extranonce2_len: usize,
     pub version_rolling_mask: Option<HexU32Be>,
+    // Set when the share meets the network target, block shares are never dropped by the
+    // upstream rate limit
+    pub block_hash: Option<BlockHash>,
 }
 
 /// message for notifying the bridge that a downstream target has updated
@@ -50,9 +50,8 @@ pub async fn start_notify(
                     if is_a {
                         if let Err(e) = Downstream::init_difficulty_management(&downstream).await {
                             error!("Failed to initailize difficulty managemant {e}")
-                        } else {
-                            let message: json_rpc::Message = job.into();
-                            Downstream::send_message_downstream(downstream.clone(), message).await;
+                        } else if let Err(e) = Downstream::send_first_job(&downstream, job).await {
+                            error!("Failed to send the first job: {e}");
                         }
                         break;
                     }
@@ -74,9 +73,8 @@ pub async fn start_notify(
                             .unwrap();
                         if let Err(e) = Downstream::init_difficulty_management(&downstream).await {
                             error!("Failed to initailize difficulty managemant {e}")
-                        } else {
-                            let message: json_rpc::Message = job.into();
-                            Downstream::send_message_downstream(downstream.clone(), message).await;
+                        } else if let Err(e) = Downstream::send_first_job(&downstream, job).await {
+                            error!("Failed to send the first job: {e}");
                         }
                     } else {
                         warn!(
@@ -97,23 +95,30 @@ pub async fn start_notify(
                     .safe_lock(|d| d.version_rolling_mask.clone())
                     .unwrap();
                 while let Ok(mut sv1_mining_notify_msg) = rx_sv1_notify.recv().await {
-                    if downstream
-                        .safe_lock(|d| {
-                            d.recent_jobs.add_job(&mut sv1_mining_notify_msg,mask.clone());
-                            debug!(
-                                "Downstream {}: Added job_id {} to recent_notifies. Current jobs: {:?}", 
-                                connection_id,
-                                sv1_mining_notify_msg.job_id,
-                                d.recent_jobs.current_jobs()
-                            );
-                            })
-                        .is_err()
-                    {
-                        error!("Translator Downstream Mutex Poisoned");
-                        ProxyState::update_downstream_state(
-                            DownstreamType::TranslatorDownstream,
+                    let paused = match downstream.safe_lock(|d| {
+                        d.recent_jobs
+                            .add_job(&mut sv1_mining_notify_msg, mask.clone());
+                        debug!(
+                            "Downstream {}: Added job_id {} to recent_notifies. Current jobs: {:?}",
+                            connection_id,
+                            sv1_mining_notify_msg.job_id,
+                            d.recent_jobs.current_jobs()
                         );
-                        break;
+                        d.paused
+                    }) {
+                        Ok(paused) => paused,
+                        Err(_) => {
+                            error!("Translator Downstream Mutex Poisoned");
+                            ProxyState::update_downstream_state(
+                                DownstreamType::TranslatorDownstream,
+                                "Translator Downstream Mutex Poisoned",
+                            );
+                            break;
+                        }
+                    };
+                    // The last job is sent when the downstream is resumed
+                    if paused {
+                        continue;
                     }
                     debug!(
                         "Sending Job {:?} to miner. Difficulty: {:?}",
@@ -155,6 +160,11 @@ async fn start_update(
 
             tokio::time::sleep(sleep_duration).await;
 
+            // Without jobs there are no shares, the estimated hashrate is kept for the resume
+            if downstream.safe_lock(|d| d.paused).unwrap_or(false) {
+                continue;
+            }
+
             // if hashrate has changed, update difficulty management, and send new
             // mining.set_difficulty
             if let Err(e) = Downstream::try_update_difficulty_settings(&downstream).await {
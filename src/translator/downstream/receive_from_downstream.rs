@@ -1,5 +1,6 @@
 use super::{downstream::Downstream, task_manager::TaskManager};
 use crate::{
+    events::{self, EventKind},
     monitor::worker_activity::{WorkerActivity, WorkerActivityType},
     proxy_state::ProxyState,
     translator::error::Error,
@@ -9,18 +10,30 @@ use std::sync::Arc;
 use sv1_api::{client_to_server::Submit, json_rpc};
 use tokio::sync::mpsc;
 use tokio::task;
-use tracing::{error, warn};
+use tracing::{error, info, warn};
 
 pub async fn start_receive_downstream(
     task_manager: Arc<Mutex<TaskManager>>,
     downstream: Arc<Mutex<Downstream>>,
     mut recv_from_down: mpsc::Receiver<String>,
+    mut disconnect: mpsc::Receiver<()>,
     connection_id: u32,
 ) -> Result<(), Error<'static>> {
     let handle = {
         let task_manager = task_manager.clone();
         task::spawn(async move {
-            while let Some(incoming) = recv_from_down.recv().await {
+            let registration = super::control::Registration(connection_id);
+            loop {
+                let incoming = tokio::select! {
+                    incoming = recv_from_down.recv() => match incoming {
+                        Some(incoming) => incoming,
+                        None => break,
+                    },
+                    Some(()) = disconnect.recv() => {
+                        info!("Downstream {} disconnected by operator", connection_id);
+                        break;
+                    }
+                };
                 let incoming: Result<json_rpc::Message, _> = serde_json::from_str(&incoming);
                 if let Ok(incoming) = incoming {
                     // if message is Submit Shares update difficulty management
@@ -47,9 +60,10 @@ pub async fn start_receive_downstream(
                             sv1_api::error::Error::InvalidJsonRpcMessageKind
                         ))
                     );
-                    return;
+                    break;
                 }
             }
+            drop(registration);
             if let Ok(stats_sender) = downstream.safe_lock(|d| d.stats_sender.clone()) {
                 stats_sender.remove_stats(connection_id);
             }
@@ -72,12 +86,20 @@ pub async fn start_receive_downstream(
                 })
                 .unwrap_or_else(|e| {
                     error!("Failed to lock downstream: {:?}", e);
-                    ProxyState::update_inconsistency(Some(1));
+                    ProxyState::update_inconsistency(
+                        Some(1),
+                        &format!("Failed to lock downstream: {:?}", e),
+                    );
                     ("unknown".to_string(), "unknown".to_string())
                 });
 
+            events::publish(EventKind::MinerDisconnected {
+                connection_id,
+                worker_name: worker_name.clone(),
+            });
             let worker_activity =
-                WorkerActivity::new(user_agent, worker_name, WorkerActivityType::Disconnected);
+                WorkerActivity::new(user_agent, worker_name, WorkerActivityType::Disconnected)
+                    .on_upstream(crate::translator::upstream_index(connection_id));
 
             worker_activity
                 .monitor_api()
@@ -97,7 +119,7 @@ pub async fn start_receive_downstream(
                 .unwrap();
             if send_kill_signal.send(connection_id).await.is_err() {
                 error!("Proxy can not abort downstreams tasks");
-                ProxyState::update_inconsistency(Some(1));
+                ProxyState::update_inconsistency(Some(1), "Proxy can not abort downstreams tasks");
             }
         })
     };
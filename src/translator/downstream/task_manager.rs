@@ -69,7 +69,7 @@ impl TaskManager {
                     .is_err()
                 {
                     tracing::error!("TasKManager Mutex Poisoned");
-                    ProxyState::update_inconsistency(Some(1));
+                    ProxyState::update_inconsistency(Some(1), "TasKManager Mutex Poisoned");
                 };
                 tracing::info!(
                     "Aborted all tasks for downstream connection ID {}",
@@ -1,3 +1,4 @@
+mod children;
 mod downstream;
 
 mod error;
@@ -18,6 +19,7 @@ use sv1_api::server_to_client;
 use tokio::sync::broadcast;
 
 use crate::{
+    config::Configuration,
     proxy_state::{ProxyState, TranslatorState},
     shared::utils::AbortOnDrop,
 };
@@ -27,6 +29,20 @@ use self::upstream::diff_management::UpstreamDifficultyConfig;
 mod task_manager;
 use task_manager::TaskManager;
 
+pub use downstream::control::{
+    connection_ids as downstream_connection_ids, disconnect as disconnect_downstream,
+    set_difficulty as set_downstream_difficulty, set_paused as set_downstream_paused,
+};
+
+/// Connection ids of the downstreams of a translator start at its index times this, so that they
+/// are unique when a translator runs for each upstream.
+pub const CONNECTION_ID_SPAN: u32 = 1 << 24;
+
+/// Index of the translator, and so of the upstream, the downstream with `connection_id` belongs to
+pub fn upstream_index(connection_id: u32) -> u32 {
+    connection_id / CONNECTION_ID_SPAN
+}
+
 pub async fn start(
     downstreams: TReceiver<(TSender<String>, TReceiver<String>, IpAddr)>,
     pool_connection: TSender<(
@@ -36,7 +52,11 @@ pub async fn start(
     )>,
     stats_sender: crate::api::stats::StatsSender,
     signature: String,
+    upstream_index: u32,
 ) -> Result<AbortOnDrop, Error<'static>> {
+    let connection_id_offset = upstream_index * CONNECTION_ID_SPAN;
+    // Downstreams of the previous run have been aborted
+    downstream::control::clear(connection_id_offset..connection_id_offset + CONNECTION_ID_SPAN);
     let task_manager = TaskManager::initialize(pool_connection.clone());
     let abortable = task_manager
         .safe_lock(|t| t.get_aborter())
@@ -94,11 +114,20 @@ pub async fn start(
     };
     let diff_config = Arc::new(Mutex::new(upstream_diff));
 
+    // Only the main translator serves the child proxies, its channels keep enough extranonce for
+    // the miners of a child
+    let serves_children = upstream_index == crate::upstreams::MAIN_POOL
+        && Configuration::child_listen_address().is_some();
+    let miner_extranonce2_size = match serves_children {
+        true => children::EXTRANONCE_SIZE,
+        false => crate::MIN_EXTRANONCE_SIZE - 1,
+    };
+
     // Instantiate a new `Upstream` (SV2 Pool)
     let upstream = upstream::Upstream::new(
         tx_sv2_set_new_prev_hash,
         tx_sv2_new_ext_mining_job,
-        crate::MIN_EXTRANONCE_SIZE - 1,
+        miner_extranonce2_size,
         tx_sv2_extranonce,
         target.clone(),
         diff_config.clone(),
@@ -121,7 +150,10 @@ pub async fn start(
                 Some((extended_extranonce, up_id)) => (extended_extranonce, up_id),
                 None => {
                     error!("Failed to receive from rx_sv2_extranonce");
-                    ProxyState::update_translator_state(TranslatorState::Down);
+                    ProxyState::update_translator_state(
+                        TranslatorState::Down,
+                        "Failed to receive from rx_sv2_extranonce",
+                    );
                     return;
                 }
             };
@@ -148,6 +180,7 @@ pub async fn start(
                 extended_extranonce,
                 target,
                 up_id,
+                connection_id_offset,
             ) {
                 Ok(b) => b,
                 Err(e) => {
@@ -171,6 +204,16 @@ pub async fn start(
                 }
             };
 
+            let children_aborter = match serves_children {
+                true => children::start(
+                    b.clone(),
+                    diff_config.clone(),
+                    stats_sender.clone(),
+                    connection_id_offset,
+                ),
+                false => None,
+            };
+
             let downstream_aborter = match downstream::Downstream::accept_connections(
                 tx_sv1_bridge,
                 tx_sv1_notify,
@@ -201,6 +244,16 @@ pub async fn start(
                 .is_err()
             {
                 error!("{}", Error::TranslatorTaskManagerFailed);
+                return;
+            }
+
+            if let Some(children_aborter) = children_aborter {
+                if TaskManager::add_children_listener(task_manager.clone(), children_aborter)
+                    .await
+                    .is_err()
+                {
+                    error!("{}", Error::TranslatorTaskManagerFailed);
+                }
             }
         })
     };
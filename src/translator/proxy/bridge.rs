@@ -1,17 +1,22 @@
-use bitcoin::hex::DisplayHex;
+use binary_sv2::U256;
+use bitcoin::{hex::DisplayHex, BlockHash};
 use tokio::task::JoinHandle;
 
 use roles_logic_sv2::{
     channel_logic::channel_factory::{ExtendedChannelKind, ProxyExtendedChannelFactory, Share},
     mining_sv2::{
-        ExtendedExtranonce, NewExtendedMiningJob, SetNewPrevHash, SubmitSharesExtended, Target,
+        ExtendedExtranonce, NewExtendedMiningJob, SetNewPrevHash, SubmitSharesError,
+        SubmitSharesExtended, SubmitSharesSuccess, Target,
     },
     parsers::Mining,
     utils::{GroupId, Mutex},
 };
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
 };
 use sv1_api::{client_to_server::Submit, server_to_client, utils::HexU32Be};
 use tokio::sync::broadcast;
@@ -24,9 +29,12 @@ use super::{
     task_manager::TaskManager,
 };
 use crate::{
+    blocks,
+    jd_client::block_submitter::SubmissionResult,
+    jobs,
     proxy_state::{ProxyState, TranslatorState, UpstreamType},
     shared::utils::AbortOnDrop,
-    translator::utils::allow_submit_share,
+    translator::utils::{allow_submit_share, header_hash},
 };
 use lazy_static::lazy_static;
 use roles_logic_sv2::{channel_logic::channel_factory::OnNewShare, Error as RolesLogicError};
@@ -36,6 +44,9 @@ lazy_static! {
     static ref SUBMIT_FAIL_COUNTER: AtomicU32 = AtomicU32::new(0);
 }
 
+/// Number of jobs of the current prev hash kept for the shares of the child proxies
+const CURRENT_JOBS_HISTORY: usize = 8;
+
 /// Bridge between the SV2 `Upstream` and SV1 `Downstream` responsible for the following messaging
 /// translation:
 /// 1. SV1 `mining.submit` -> SV2 `SubmitSharesExtended`
@@ -63,6 +74,14 @@ pub struct Bridge {
     future_jobs: Vec<NewExtendedMiningJob<'static>>,
     last_p_hash: Option<SetNewPrevHash<'static>>,
     target: Arc<Mutex<Vec<u8>>>,
+    /// Added to the channel ids given to the `Downstream`s, so that connection ids are unique
+    /// when several translators run side by side.
+    connection_id_offset: u32,
+    /// Jobs and prev hashes relayed to the child proxies, see `children`.
+    tx_sv2_jobs: broadcast::Sender<Mining<'static>>,
+    /// Last jobs of the current prev hash, used for the hash of the blocks found by the child
+    /// proxies. The last one is sent to them when they open a channel.
+    current_jobs: VecDeque<NewExtendedMiningJob<'static>>,
 }
 
 impl Bridge {
@@ -84,6 +103,7 @@ impl Bridge {
         extranonces: ExtendedExtranonce,
         target: Arc<Mutex<Vec<u8>>>,
         channel_id: u32,
+        connection_id_offset: u32,
     ) -> Result<Arc<Mutex<Self>>, Error<'static>> {
         info!("Creating new bridge for channel_id {}:", channel_id);
         let ids = Arc::new(Mutex::new(GroupId::new()));
@@ -107,9 +127,205 @@ impl Bridge {
             future_jobs: vec![],
             last_p_hash: None,
             target,
+            connection_id_offset,
+            tx_sv2_jobs: broadcast::channel(crate::TRANSLATOR_BUFFER_SIZE).0,
+            current_jobs: VecDeque::with_capacity(CURRENT_JOBS_HISTORY),
         })))
     }
 
+    /// Opens an extended channel for a child proxy. Returns the messages to send to it, the
+    /// `OpenExtendedMiningChannelSuccess` followed by the current job if any, and the receiver
+    /// of the next jobs and prev hashes.
+    #[allow(clippy::result_large_err)]
+    pub fn on_new_sv2_channel(
+        &mut self,
+        request_id: u32,
+        hash_rate: f32,
+        min_extranonce_size: u16,
+    ) -> ProxyResult<'static, (Vec<Mining<'static>>, broadcast::Receiver<Mining<'static>>)> {
+        let messages = self
+            .channel_factory
+            .new_extended_channel(request_id, hash_rate, min_extranonce_size)
+            .map_err(Error::RolesSv2Logic)?;
+        let success = messages
+            .into_iter()
+            .find_map(|m| match m {
+                Mining::OpenExtendedMiningChannelSuccess(success) => Some(success.into_static()),
+                _ => None,
+            })
+            .ok_or(Error::ImpossibleToOpenChannnel)?;
+        let channel_id = success.channel_id;
+        info!(
+            "New extended channel opened for a child with id {}",
+            channel_id
+        );
+        let mut messages = vec![Mining::OpenExtendedMiningChannelSuccess(success)];
+        // The current job is sent as a future job activated by the prev hash, as on a new
+        // connection to a pool
+        if let (Some(job), Some(prev_hash)) = (self.current_jobs.back(), &self.last_p_hash) {
+            let mut job = job.clone();
+            job.channel_id = channel_id;
+            job.min_ntime = binary_sv2::Sv2Option::new(None);
+            let mut prev_hash = prev_hash.clone();
+            prev_hash.channel_id = channel_id;
+            prev_hash.job_id = job.job_id;
+            messages.push(Mining::NewExtendedMiningJob(job));
+            messages.push(Mining::SetNewPrevHash(prev_hash));
+        }
+        Ok((messages, self.tx_sv2_jobs.subscribe()))
+    }
+
+    /// Updates the target of the channel of a child proxy from its nominal hashrate, returns the
+    /// new target.
+    #[allow(clippy::result_large_err)]
+    pub fn on_update_sv2_channel(
+        &mut self,
+        channel_id: u32,
+        hash_rate: f32,
+    ) -> ProxyResult<'static, U256<'static>> {
+        let target = roles_logic_sv2::utils::hash_rate_to_target(
+            hash_rate.into(),
+            (*crate::SHARE_PER_MIN).into(),
+        )
+        .map_err(Error::RolesSv2Logic)?;
+        self.channel_factory
+            .update_target_for_channel(channel_id, target.clone().into());
+        Ok(target)
+    }
+
+    /// Checks a share of a child proxy against its channel and sends it to the `Upstream` when
+    /// it meets the upstream target. Blocks are recorded and always sent, like the ones of the
+    /// SV1 miners. Returns the response for the child.
+    pub async fn on_sv2_share(
+        self_: &Arc<Mutex<Self>>,
+        share: SubmitSharesExtended<'static>,
+        worker_name: String,
+        connection_id: u32,
+    ) -> ProxyResult<'static, Mining<'static>> {
+        let (channel_id, sequence_number) = (share.channel_id, share.sequence_number);
+        let (tx_sv2_submit_shares_ext, target_mutex) = self_
+            .safe_lock(|s| (s.tx_sv2_submit_shares_ext.clone(), s.target.clone()))
+            .map_err(|_| Error::BridgeMutexPoisoned)?;
+        let upstream_target: [u8; 32] = target_mutex
+            .safe_lock(|t| t.clone())
+            .map_err(|_| Error::BridgeMutexPoisoned)?
+            .try_into()
+            .expect("Internal error: this operation can not fail because the Vec<U8> can always be converted into Inner");
+        let mut upstream_target: Target = upstream_target.into();
+        let res = self_
+            .safe_lock(|s| {
+                s.channel_factory.set_target(&mut upstream_target);
+                s.channel_factory.on_submit_shares_extended(share)
+            })
+            .map_err(|_| Error::BridgeMutexPoisoned)?;
+        let error_code = match res {
+            Ok(OnNewShare::SendErrorDownstream(e)) => return Ok(Mining::SubmitSharesError(e)),
+            Ok(OnNewShare::ShareMeetBitcoinTarget((Share::Extended(share), _, coinbase, _))) => {
+                info!(
+                    "Share from child channel {} meets bitcoin target, block found",
+                    channel_id
+                );
+                let block_hash = self_
+                    .safe_lock(|s| s.child_block_hash(&share, &coinbase))
+                    .map_err(|_| Error::BridgeMutexPoisoned)?;
+                match block_hash {
+                    Some(block_hash) => {
+                        blocks::record_found(worker_name, connection_id, block_hash, &coinbase)
+                    }
+                    None => error!(
+                        "Job {} of the block of child channel {} not found",
+                        share.job_id, channel_id
+                    ),
+                }
+                // Blocks are not subject to the rate limit
+                let sent = tx_sv2_submit_shares_ext.send(share).await;
+                if let Some(block_hash) = block_hash {
+                    let result = match sent {
+                        Ok(()) => SubmissionResult::Sent,
+                        Err(_) => SubmissionResult::Failed {
+                            error: "upstream channel closed".to_string(),
+                        },
+                    };
+                    blocks::record_submission(block_hash, "upstream", result);
+                }
+                sent.map_err(|_| Error::AsyncChannelError)?;
+                None
+            }
+            Ok(OnNewShare::SendSubmitShareUpstream((Share::Extended(share), _))) => {
+                if allow_submit_share()? {
+                    info!(
+                        "Share from child channel {} meets upstream target",
+                        channel_id
+                    );
+                    tx_sv2_submit_shares_ext
+                        .send(share)
+                        .await
+                        .map_err(|_| Error::AsyncChannelError)?;
+                } else {
+                    warn!("Share will not be sent upstream: Exceeded 70 shares/min limit");
+                }
+                None
+            }
+            Ok(OnNewShare::ShareMeetDownstreamTarget) => None,
+            // We are in an extended channel, shares are extended and not relayed to a group
+            Ok(_) => unreachable!(),
+            Err(RolesLogicError::ShareDoNotMatchAnyJob) | Err(RolesLogicError::NoValidJob) => {
+                Some("invalid-job-id")
+            }
+            Err(e) => {
+                warn!("Invalid share from child channel {}: {}", channel_id, e);
+                Some("invalid-share")
+            }
+        };
+        Ok(match error_code {
+            None => Mining::SubmitSharesSuccess(SubmitSharesSuccess {
+                channel_id,
+                last_sequence_number: sequence_number,
+                new_submits_accepted_count: 1,
+                new_shares_sum: 1,
+            }),
+            Some(error_code) => Mining::SubmitSharesError(SubmitSharesError {
+                channel_id,
+                sequence_number,
+                error_code: error_code
+                    .to_string()
+                    .try_into()
+                    .expect("Internal error: this operation can not fail because the error code is shorter than 255 bytes"),
+            }),
+        })
+    }
+
+    /// Hash of the block of a share of a child proxy, None when its job is not a job of the
+    /// current prev hash anymore.
+    fn child_block_hash(
+        &self,
+        share: &SubmitSharesExtended<'static>,
+        coinbase: &[u8],
+    ) -> Option<BlockHash> {
+        let prev_hash = self.last_p_hash.as_ref()?;
+        let job = self
+            .current_jobs
+            .iter()
+            .find(|job| job.job_id == share.job_id)?;
+        let merkle_path = job
+            .merkle_path
+            .clone()
+            .into_static()
+            .0
+            .iter()
+            .map(|node| node.to_vec())
+            .collect();
+        Some(header_hash(
+            share.version,
+            roles_logic_sv2::utils::u256_to_block_hash(prev_hash.prev_hash.clone()),
+            coinbase,
+            merkle_path,
+            share.ntime,
+            prev_hash.nbits,
+            share.nonce,
+        ))
+    }
+
     #[allow(clippy::result_large_err)]
     pub fn on_new_sv1_connection(
         &mut self,
@@ -130,7 +346,7 @@ impl Bridge {
                             Error::TargetError(roles_logic_sv2::Error::PoisonLock(e.to_string()))
                         })?;
                     Ok(OpenSv1Downstream {
-                        channel_id: success.channel_id,
+                        channel_id: success.channel_id + self.connection_id_offset,
                         last_notify: self.last_notify.clone(),
                         extranonce,
                         extranonce2_len,
@@ -195,30 +411,53 @@ impl Bridge {
         mut rx_sv1_downstream: tokio::sync::mpsc::Receiver<DownstreamMessages>,
     ) -> JoinHandle<()> {
         tokio::task::spawn(async move {
+            let offset = match self_.safe_lock(|b| b.connection_id_offset) {
+                Ok(offset) => offset,
+                Err(_) => {
+                    error!("{}", Error::BridgeMutexPoisoned);
+                    ProxyState::update_translator_state(
+                        TranslatorState::Down,
+                        &Error::BridgeMutexPoisoned.to_string(),
+                    );
+                    return;
+                }
+            };
             loop {
                 let msg = match rx_sv1_downstream.recv().await {
                     Some(msg) => msg,
                     None => {
                         error!("Failed to receive message from downstream");
-                        ProxyState::update_translator_state(TranslatorState::Down);
+                        ProxyState::update_translator_state(
+                            TranslatorState::Down,
+                            "Failed to receive message from downstream",
+                        );
                         break;
                     }
                 };
 
                 match msg {
-                    DownstreamMessages::SubmitShares(share) => {
+                    DownstreamMessages::SubmitShares(mut share) => {
+                        // Back to the id of the channel in the channel factory
+                        share.channel_id -= offset;
                         if let Err(e) = Self::handle_submit_shares(self_.clone(), share).await {
                             error!("Failed to handle SubmitShareWithChannelId: {e}");
-                            ProxyState::update_translator_state(TranslatorState::Down);
+                            ProxyState::update_translator_state(
+                                TranslatorState::Down,
+                                &format!("Failed to handle SubmitShareWithChannelId: {e}"),
+                            );
                             break;
                         }
                     }
-                    DownstreamMessages::SetDownstreamTarget(new_target) => {
+                    DownstreamMessages::SetDownstreamTarget(mut new_target) => {
+                        new_target.channel_id -= offset;
                         if let Err(e) =
                             Self::handle_update_downstream_target(self_.clone(), new_target)
                         {
                             error!("Failed to handle SetDownstreamTarget: {e}");
-                            ProxyState::update_translator_state(TranslatorState::Down);
+                            ProxyState::update_translator_state(
+                                TranslatorState::Down,
+                                &format!("Failed to handle SetDownstreamTarget: {e}"),
+                            );
                             break;
                         };
                     }
@@ -250,6 +489,8 @@ impl Bridge {
         let channel_id = share.channel_id;
         let job_id = share.share.job_id.clone();
         let share_id = share.share.id;
+        let block_hash = share.block_hash;
+        let meets_network_target = block_hash.is_some();
         info!(
             "Bridge received share {:?} for channel {:?} and job {:?}",
             &share_id, &channel_id, &job_id
@@ -301,9 +542,10 @@ impl Bridge {
                     &share_id, &channel_id, &job_id, error_code
                 );
             }
-            Ok(OnNewShare::SendSubmitShareUpstream((s, _))) => {
+            Ok(OnNewShare::SendSubmitShareUpstream((s, _)))
+            | Ok(OnNewShare::ShareMeetBitcoinTarget((s, ..))) => {
                 if let Ok(is_rate_limited) = allow_submit_share() {
-                    if !is_rate_limited {
+                    if !is_rate_limited && !meets_network_target {
                         warn!("Share will not be sent upstream: Exceeded 70 shares/min limit");
                         return Ok(());
                     }
@@ -315,15 +557,34 @@ impl Bridge {
                         Share::Extended(share) => {
                             if tx_sv2_submit_shares_ext.send(share).await.is_err() {
                                 error!("Failed to send SubmitShareExtended downstream");
+                                if let Some(block_hash) = block_hash {
+                                    blocks::record_submission(
+                                        block_hash,
+                                        "upstream",
+                                        SubmissionResult::Failed {
+                                            error: "upstream channel closed".to_string(),
+                                        },
+                                    );
+                                }
                                 return Err(Error::AsyncChannelError);
                             }
+                            if let Some(block_hash) = block_hash {
+                                blocks::record_submission(
+                                    block_hash,
+                                    "upstream",
+                                    SubmissionResult::Sent,
+                                );
+                            }
                         }
                         // We are in an extended channel shares are extended
                         Share::Standard(_) => unreachable!(),
                     }
                 } else {
                     error!("Failed to record share: Bridge mutex poisoned");
-                    ProxyState::update_inconsistency(Some(1));
+                    ProxyState::update_inconsistency(
+                        Some(1),
+                        "Failed to record share: Bridge mutex poisoned",
+                    );
                     return Err(Error::BridgeMutexPoisoned);
                 }
             }
@@ -335,8 +596,6 @@ impl Bridge {
                     &share_id, &channel_id, &job_id
                 );
             }
-            // Proxy do not have JD capabilities
-            Ok(OnNewShare::ShareMeetBitcoinTarget(..)) => unreachable!(),
             Err(roles_logic_sv2::Error::NoValidJob) => {
                 let count = SUBMIT_FAIL_COUNTER.fetch_add(1, Ordering::Relaxed) + 1;
                 if count >= 10 {
@@ -438,7 +697,12 @@ impl Bridge {
         self_
             .safe_lock(|s| {
                 s.channel_factory
-                    .on_new_prev_hash(sv2_set_new_prev_hash.clone())
+                    .on_new_prev_hash(sv2_set_new_prev_hash.clone())?;
+                // No child proxy connected is not an error
+                let _ = s
+                    .tx_sv2_jobs
+                    .send(Mining::SetNewPrevHash(sv2_set_new_prev_hash.clone()));
+                Ok::<(), RolesLogicError>(())
             })
             .map_err(|_| Error::BridgeMutexPoisoned)??;
 
@@ -457,6 +721,12 @@ impl Bridge {
         let mut match_a_future_job = false;
         while let Some(job) = future_jobs.pop() {
             if job.job_id == sv2_set_new_prev_hash.job_id {
+                self_
+                    .safe_lock(|s| {
+                        s.current_jobs.clear();
+                        s.current_jobs.push_back(job.clone());
+                    })
+                    .map_err(|_| Error::BridgeMutexPoisoned)?;
                 // Create the mining.notify to be sent to the Downstream.
                 let notify = super::super::proxy::next_mining_notify::create_notify(
                     sv2_set_new_prev_hash.clone(),
@@ -469,7 +739,10 @@ impl Bridge {
                 if tx_sv1_notify.send(notify.clone()).is_err() {
                     error!("Failed to send mining.notify");
                     // Update translator state to down
-                    ProxyState::update_translator_state(TranslatorState::Down);
+                    ProxyState::update_translator_state(
+                        TranslatorState::Down,
+                        "Failed to send mining.notify",
+                    );
                 };
                 match_a_future_job = true;
                 self_
@@ -508,7 +781,10 @@ impl Bridge {
                         Some(set_new_prev_hash) => set_new_prev_hash,
                         None => {
                             error!("Failed to receive SetNewPrevHash");
-                            ProxyState::update_translator_state(TranslatorState::Down);
+                            ProxyState::update_translator_state(
+                                TranslatorState::Down,
+                                "Failed to receive SetNewPrevHash",
+                            );
                             break;
                         }
                     };
@@ -520,6 +796,11 @@ impl Bridge {
                     sv2_set_new_prev_hash.channel_id,
                     sv2_set_new_prev_hash.job_id
                 );
+                jobs::new_prev_hash(
+                    sv2_set_new_prev_hash.job_id,
+                    dbg_prev_hash.as_hex().to_string(),
+                );
+                ProxyState::update_jobs_available();
                 if let Err(e) = Self::handle_new_prev_hash_(
                     self_.clone(),
                     sv2_set_new_prev_hash,
@@ -528,7 +809,10 @@ impl Bridge {
                 .await
                 {
                     error!("Failed to handle SetNewPrevHash: {e}");
-                    ProxyState::update_upstream_state(UpstreamType::TranslatorUpstream);
+                    ProxyState::update_upstream_state(
+                        UpstreamType::TranslatorUpstream,
+                        &format!("Failed to handle SetNewPrevHash: {e}"),
+                    );
                     return;
                 }
             }
@@ -551,7 +835,20 @@ impl Bridge {
                 Error::RolesSv2Logic(RolesLogicError::JobIsNotFutureButPrevHashNotPresent)
             })?;
 
-        let extranonce_len = self_.safe_lock(|s| s.channel_factory.get_extranonce_len())?;
+        let extranonce_len = self_.safe_lock(|s| {
+            if !sv2_new_extended_mining_job.is_future() {
+                if s.current_jobs.len() == CURRENT_JOBS_HISTORY {
+                    s.current_jobs.pop_front();
+                }
+                s.current_jobs
+                    .push_back(sv2_new_extended_mining_job.clone());
+            }
+            // No child proxy connected is not an error
+            let _ = s.tx_sv2_jobs.send(Mining::NewExtendedMiningJob(
+                sv2_new_extended_mining_job.clone(),
+            ));
+            s.channel_factory.get_extranonce_len()
+        })?;
 
         // If future_job=true, this job is meant for a future SetNewPrevHash that the proxy
         // has yet to receive. Insert this new job into the job_mapper .
@@ -619,10 +916,17 @@ impl Bridge {
                         Some(sv2_new_extended_mining_job) => sv2_new_extended_mining_job,
                         None => {
                             error!("Failed to receive NewExtendedMiningJob from upstream");
-                            ProxyState::update_translator_state(TranslatorState::Down);
+                            ProxyState::update_translator_state(
+                                TranslatorState::Down,
+                                "Failed to receive NewExtendedMiningJob from upstream",
+                            );
                             break;
                         }
                     };
+                jobs::new_job(
+                    sv2_new_extended_mining_job.job_id,
+                    sv2_new_extended_mining_job.is_future(),
+                );
                 if let Err(e) = Self::handle_new_extended_mining_job_(
                     self_.clone(),
                     sv2_new_extended_mining_job,
@@ -631,7 +935,10 @@ impl Bridge {
                 .await
                 {
                     error!("Failed to handle NewExtendedMiningJob {e}",);
-                    ProxyState::update_translator_state(TranslatorState::Down);
+                    ProxyState::update_translator_state(
+                        TranslatorState::Down,
+                        &format!("Failed to handle NewExtendedMiningJob {e}"),
+                    );
                 };
                 super::super::upstream::upstream::IS_NEW_JOB_HANDLED
                     .store(true, std::sync::atomic::Ordering::SeqCst);
@@ -669,6 +976,7 @@ mod test {
                 extranonces,
                 Arc::new(Mutex::new(upstream_target)),
                 1,
+                0,
             )
             .map_err(|_| ())?;
             Ok(b)
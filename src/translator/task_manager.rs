@@ -17,6 +17,7 @@ enum Task {
     #[allow(clippy::enum_variant_names)]
     StartupTask(AbortOnDrop),
     Bridge(AbortOnDrop),
+    ChildrenListener(AbortOnDrop),
 }
 
 pub struct TaskManager {
@@ -80,6 +81,16 @@ impl TaskManager {
             .await
             .map_err(|_| ())
     }
+    pub async fn add_children_listener(
+        self_: Arc<Mutex<Self>>,
+        abortable: AbortOnDrop,
+    ) -> Result<(), ()> {
+        let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
+        send_task
+            .send(Task::ChildrenListener(abortable))
+            .await
+            .map_err(|_| ())
+    }
     pub async fn add_bridge(self_: Arc<Mutex<Self>>, abortable: AbortOnDrop) -> Result<(), ()> {
         let send_task = self_.safe_lock(|s| s.send_task.clone()).unwrap();
         send_task
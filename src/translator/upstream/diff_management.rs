@@ -28,6 +28,8 @@ impl Upstream {
         let (timeout, new_hashrate) = diff_mgmt
             .safe_lock(|d| (d.channel_diff_update_interval, d.channel_nominal_hashrate))
             .map_err(|_| Error::TranslatorDiffConfigMutexPoisoned)?;
+        // Curtailed miners do not hash, or only part of them while resuming
+        let new_hashrate = new_hashrate * crate::curtailment::hashrate_factor();
         // UPDATE CHANNEL
         let update_channel = UpdateChannel {
             channel_id,
@@ -295,7 +295,8 @@ impl Upstream {
                                                 error!(
                                                     "Failed to create a valid extended extranonce from {:?} {:?} {:?} {:?}: {:?}",
                                                     extranonce_prefix, range_0, range_1, range_2, e
-                                                ); ProxyState::update_upstream_state(UpstreamType::TranslatorUpstream);
+                                                ); ProxyState::update_upstream_state(UpstreamType::TranslatorUpstream, &format!("Failed to create a valid extended extranonce from {:?} {:?} {:?} {:?}: {:?}",
+                                                    extranonce_prefix, range_0, range_1, range_2, e));
                                                 break;
                                             }
                                         };
@@ -12,7 +12,7 @@ use bitcoin::{
     block::{Header, Version},
     hashes::{sha256d, Hash as BHash},
     hex::DisplayHex,
-    BlockHash, CompactTarget,
+    BlockHash, CompactTarget, Target,
 };
 use lazy_static::lazy_static;
 use roles_logic_sv2::utils::Mutex;
@@ -51,7 +51,10 @@ pub async fn check_share_rate_limit(downstream: Arc<Mutex<Downstream>>) {
             })
             .unwrap_or_else(|e| {
                 error!("Failed to lock SHARE_TIMESTAMPS: {:?}", e);
-                ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+                ProxyState::update_downstream_state(
+                    DownstreamType::TranslatorDownstream,
+                    &format!("Failed to lock SHARE_TIMESTAMPS: {:?}", e),
+                );
                 0
             });
 
@@ -97,13 +100,27 @@ pub fn allow_submit_share() -> crate::translator::error::ProxyResult<'static, bo
     Ok(true) // Share can be sent
 }
 
+/// A share that meets one of the downstream difficulties
+#[derive(Debug, Clone)]
+pub struct ValidShare {
+    pub difficulty: f32,
+    /// Set when the share also meets the network target
+    pub block: Option<BlockCandidate>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockCandidate {
+    pub block_hash: BlockHash,
+    pub coinbase: Vec<u8>,
+}
+
 pub fn validate_share(
     request: &client_to_server::Submit<'static>,
     job: &Notify<'static>,
     difficulties: &VecDeque<f32>,
     extranonce1: Vec<u8>,
     version_rolling_mask: Option<sv1_api::utils::HexU32Be>,
-) -> Option<f32> {
+) -> Option<ValidShare> {
     info!(
         "Validating share from request {} and job {}",
         request.id, request.job_id
@@ -148,8 +165,14 @@ pub fn validate_share(
         merkle_branch,
     );
 
+    let block_hash = BlockHash::from_byte_array(hash);
     hash.reverse(); //convert to little-endian
     info!("Share Hash: {:?}", hash.to_vec().as_hex());
+    let network_target = Target::from_compact(CompactTarget::from_consensus(job.bits.0));
+    let block = (hash <= network_target.to_be_bytes()).then(|| BlockCandidate {
+        block_hash,
+        coinbase: coinbase(job, extranonce),
+    });
     // Check against difficulties from latest to earliest
     // TODO: This is not a sound check - We should check against the difficulty of the specific job
     for &difficulty in difficulties.iter().rev() {
@@ -161,7 +184,7 @@ pub fn validate_share(
         );
         if hash <= target {
             info!("Share met Target: {:?}", target.to_vec().as_hex());
-            return Some(difficulty); // Return the difficulty met
+            return Some(ValidShare { difficulty, block }); // Return the difficulty met
         }
     }
 
@@ -178,14 +201,32 @@ pub fn get_hash(
     prev_hash: BlockHash,
     merkle_path: Vec<Vec<u8>>,
 ) -> [u8; 32] {
-    // Construct coinbase
-    let mut coinbase = Vec::new();
-    coinbase.extend_from_slice(job.coin_base1.as_ref());
-    coinbase.extend_from_slice(extranonce);
-    coinbase.extend_from_slice(job.coin_base2.as_ref());
+    let coinbase = coinbase(job, extranonce);
+    header_hash(
+        version,
+        prev_hash,
+        &coinbase,
+        merkle_path,
+        ntime,
+        job.bits.0,
+        nonce,
+    )
+    .to_byte_array()
+}
 
+/// Hash of the header of a share, with the merkle root computed from its coinbase and the merkle
+/// path of its job.
+pub fn header_hash(
+    version: u32,
+    prev_hash: BlockHash,
+    coinbase: &[u8],
+    merkle_path: Vec<Vec<u8>>,
+    ntime: u32,
+    nbits: u32,
+    nonce: u32,
+) -> BlockHash {
     // Calculate the Merkle root
-    let coinbase_hash = <sha256d::Hash as bitcoin::hashes::Hash>::hash(&coinbase);
+    let coinbase_hash = <sha256d::Hash as bitcoin::hashes::Hash>::hash(coinbase);
     let mut merkle_root = coinbase_hash.to_byte_array();
 
     for path in merkle_path {
@@ -201,13 +242,19 @@ pub fn get_hash(
         prev_blockhash: prev_hash,
         merkle_root: bitcoin::TxMerkleNode::from_byte_array(merkle_root),
         time: ntime,
-        bits: CompactTarget::from_consensus(job.bits.0),
+        bits: CompactTarget::from_consensus(nbits),
         nonce,
     };
 
-    // Calculate the block hash
-    let block_hash: [u8; 32] = header.block_hash().to_raw_hash().to_byte_array();
-    block_hash
+    header.block_hash()
+}
+
+fn coinbase(job: &Notify, extranonce: &[u8]) -> Vec<u8> {
+    let mut coinbase = Vec::new();
+    coinbase.extend_from_slice(job.coin_base1.as_ref());
+    coinbase.extend_from_slice(extranonce);
+    coinbase.extend_from_slice(job.coin_base2.as_ref());
+    coinbase
 }
 
 // Update share count for each miner
@@ -228,7 +275,10 @@ pub fn update_share_count(connection_id: u32) {
         })
         .unwrap_or_else(|_| {
             error!("Failed to lock SHARE_COUNTS");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream)
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                "Failed to lock SHARE_COUNTS",
+            )
         });
 }
 
@@ -249,7 +299,10 @@ pub fn get_share_count(connection_id: u32) -> f32 {
         })
         .unwrap_or_else(|_| {
             error!("Failed to lock SHARE_COUNTS");
-            ProxyState::update_downstream_state(DownstreamType::TranslatorDownstream);
+            ProxyState::update_downstream_state(
+                DownstreamType::TranslatorDownstream,
+                "Failed to lock SHARE_COUNTS",
+            );
             0.0
         });
     share_counts
@@ -0,0 +1,744 @@
+//! Upstreams the miners can be sent to besides the main pool: the split pool and the other DMND
+//! accounts.
+//!
+//! When `--split-pool-address` is set a second pool connection, translator and share accounter are
+//! started: miners whose address is in `--split-pool-miners`, or whose worker name starts with a
+//! prefix of `--split-pool-workers`, always go to the split pool. The others are assigned so that
+//! `--split-pool-ratio` percent of the hashrate mines on it or, when `--split-pool-period` is set,
+//! they all mine on it for that percent of every period and are disconnected when the period
+//! switches pool so that they reconnect to the other one.
+//!
+//! `--worker-tokens` maps worker name prefixes to the tokens of other DMND accounts, each account
+//! gets its own connection to the main pool, translator and share accounter. The first messages of
+//! a new miner are held until its `mining.authorize`, so that it is sent to the upstream of its
+//! worker. Miners that wait for the `mining.subscribe` response before authorizing are sent to the
+//! upstream of the worker last authorized from their address and, when they authorize as a worker
+//! of another account, they are disconnected so that they reconnect to the right one.
+//!
+//! Only the main pool gets declared jobs. The split pool and the accounts are restarted on their
+//! own when their pool connection fails: meanwhile the miners go to the main pool and the workers
+//! of the account are disconnected.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use key_utils::Secp256k1PublicKey;
+use lazy_static::lazy_static;
+use roles_logic_sv2::utils::Mutex;
+use serde::Serialize;
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender},
+    watch,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    api::stats::StatsSender,
+    config::Configuration,
+    proxy_state::{ProxyState, TranslatorState},
+    router::Router,
+    share_accounter,
+    shared::{supervisor, utils::AbortOnDrop},
+    translator,
+};
+
+/// Translator index of the main pool, see `translator::upstream_index`
+pub const MAIN_POOL: u32 = 0;
+/// Translator index of the split pool
+pub const SPLIT_POOL: u32 = 1;
+/// Translator index of the first other DMND account, the next ones follow in the order of
+/// `--worker-tokens`
+const FIRST_ACCOUNT: u32 = 2;
+
+/// Max time the first messages of a new miner are held waiting for its `mining.authorize`
+const AUTHORIZE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the pool of the period is checked when splitting by time
+const TIME_SPLIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+type NewDownstream = (Sender<String>, Receiver<String>, IpAddr);
+/// Senders of the new miner connections of the running translators, by translator index
+type Senders = Arc<Mutex<HashMap<u32, Sender<NewDownstream>>>>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveUpstream {
+    /// Translator index, see `translator::upstream_index`
+    pub index: u32,
+    /// `split` or `account-<n>`
+    pub name: String,
+    pub address: SocketAddr,
+    /// Worker name prefixes of the account, if it is one
+    pub worker_prefixes: Vec<String>,
+}
+
+lazy_static! {
+    // Upstreams running besides the main pool
+    static ref ACTIVE_UPSTREAMS: Mutex<Vec<ActiveUpstream>> = Mutex::new(Vec::new());
+    // Worker last authorized from each miner address, kept across the proxy restarts
+    static ref WORKERS_BY_ADDRESS: Mutex<HashMap<IpAddr, String>> = Mutex::new(HashMap::new());
+}
+
+/// Upstreams the miners are being sent to besides the main pool
+pub fn active_upstreams() -> Vec<ActiveUpstream> {
+    ACTIVE_UPSTREAMS
+        .safe_lock(|a| a.clone())
+        .unwrap_or_else(|_| {
+            error!("Active upstreams Mutex Corrupted");
+            Vec::new()
+        })
+}
+
+fn set_active_upstreams(upstreams: Vec<ActiveUpstream>) {
+    if ACTIVE_UPSTREAMS.safe_lock(|a| *a = upstreams).is_err() {
+        error!("Active upstreams Mutex Corrupted");
+    }
+}
+
+fn add_active_upstream(upstream: ActiveUpstream) {
+    if ACTIVE_UPSTREAMS
+        .safe_lock(|a| {
+            a.retain(|u| u.index != upstream.index);
+            a.push(upstream);
+            a.sort_by_key(|u| u.index);
+        })
+        .is_err()
+    {
+        error!("Active upstreams Mutex Corrupted");
+    }
+}
+
+fn remove_active_upstream(index: u32) {
+    if ACTIVE_UPSTREAMS
+        .safe_lock(|a| a.retain(|u| u.index != index))
+        .is_err()
+    {
+        error!("Active upstreams Mutex Corrupted");
+    }
+}
+
+/// Other DMND accounts with their worker name prefixes, in the order of `--worker-tokens`
+fn accounts() -> Vec<(String, Vec<String>)> {
+    let main_token = Configuration::token();
+    let mut accounts: Vec<(String, Vec<String>)> = Vec::new();
+    for (prefix, token) in Configuration::worker_tokens() {
+        if Some(&token) == main_token.as_ref() {
+            continue;
+        }
+        match accounts.iter_mut().find(|(t, _)| *t == token) {
+            Some((_, prefixes)) => prefixes.push(prefix),
+            None => accounts.push((token, vec![prefix])),
+        }
+    }
+    accounts
+}
+
+/// Translator index of the account `worker_name` mines for, None for the main account
+fn account_of(worker_name: &str) -> Option<u32> {
+    let token = Configuration::token_for_worker(worker_name)?;
+    accounts()
+        .iter()
+        .position(|(t, _)| *t == token)
+        .map(|position| FIRST_ACCOUNT + position as u32)
+}
+
+/// Token of the account mining on the upstream with translator index `index`
+pub fn token_of(index: u32) -> Option<String> {
+    match index {
+        MAIN_POOL => Configuration::token(),
+        SPLIT_POOL => Configuration::split_pool_token().or_else(Configuration::token),
+        index => accounts()
+            .into_iter()
+            .nth(index.checked_sub(FIRST_ACCOUNT)? as usize)
+            .map(|(token, _)| token)
+            .or_else(Configuration::token),
+    }
+}
+
+/// Starts the split pool and the other accounts upstreams that are configured. Returns the
+/// receiver of the new miner connections that the main translator must use, and the abort
+/// handles of the upstreams tasks.
+pub async fn start(
+    router: &Router,
+    downstreams: Receiver<NewDownstream>,
+    stats_sender: StatsSender,
+    signature: String,
+) -> (Receiver<NewDownstream>, Vec<(AbortOnDrop, String)>) {
+    set_active_upstreams(Vec::new());
+    let accounts = accounts();
+    if Configuration::split_pool_address().is_none() && accounts.is_empty() {
+        return (downstreams, vec![]);
+    }
+    let mut abortables = Vec::new();
+    let senders: Senders = Arc::new(Mutex::new(HashMap::new()));
+
+    if let Some(address) = Configuration::split_pool_address() {
+        let token = Configuration::split_pool_token()
+            .or_else(Configuration::token)
+            .expect("Checked at initialization");
+        let upstream = ActiveUpstream {
+            index: SPLIT_POOL,
+            name: "split".to_string(),
+            address,
+            worker_prefixes: Vec::new(),
+        };
+        abortables.push((
+            supervise_upstream(
+                router.clone(),
+                upstream,
+                Configuration::split_pool_authority_public_key(),
+                token,
+                senders.clone(),
+                stats_sender.clone(),
+                signature.clone(),
+            ),
+            "split_pool".to_string(),
+        ));
+    }
+
+    // The other accounts mine on the pool the main account is connected to
+    if let Some(address) = router.current_pool {
+        for (position, (token, worker_prefixes)) in accounts.into_iter().enumerate() {
+            let upstream = ActiveUpstream {
+                index: FIRST_ACCOUNT + position as u32,
+                name: format!("account-{}", position + 1),
+                address,
+                worker_prefixes,
+            };
+            abortables.push((
+                supervise_upstream(
+                    router.clone(),
+                    upstream,
+                    None,
+                    token,
+                    senders.clone(),
+                    stats_sender.clone(),
+                    signature.clone(),
+                ),
+                format!("account_{}", position + 1),
+            ));
+        }
+    }
+
+    let (main_sender, main_receiver) = channel(10);
+    if senders
+        .safe_lock(|s| s.insert(MAIN_POOL, main_sender))
+        .is_err()
+    {
+        error!("Upstream senders Mutex Corrupted");
+    }
+    abortables.push((
+        dispatch(downstreams, senders, stats_sender),
+        "upstreams_dispatcher".to_string(),
+    ));
+    (main_receiver, abortables)
+}
+
+/// Runs `upstream` and restarts it with a backoff every time its pool connection fails or can not
+/// be opened.
+fn supervise_upstream(
+    router: Router,
+    upstream: ActiveUpstream,
+    authority_public_key: Option<Secp256k1PublicKey>,
+    token: String,
+    senders: Senders,
+    stats_sender: StatsSender,
+    signature: String,
+) -> AbortOnDrop {
+    let name = match upstream.index {
+        SPLIT_POOL => "split_pool",
+        _ => "account_upstream",
+    };
+    supervisor::supervise(name, move || {
+        run_upstream(
+            router.clone(),
+            upstream.clone(),
+            authority_public_key,
+            token.clone(),
+            senders.clone(),
+            stats_sender.clone(),
+            signature.clone(),
+        )
+    })
+}
+
+/// Starts `upstream` and makes it available to the new miners until one of its tasks exits.
+async fn run_upstream(
+    router: Router,
+    upstream: ActiveUpstream,
+    authority_public_key: Option<Secp256k1PublicKey>,
+    token: String,
+    senders: Senders,
+    stats_sender: StatsSender,
+    signature: String,
+) {
+    let index = upstream.index;
+    let Some((sender, handles)) = start_upstream(
+        &router,
+        upstream.address,
+        authority_public_key,
+        &token,
+        index,
+        stats_sender,
+        signature,
+    )
+    .await
+    else {
+        match index {
+            SPLIT_POOL => warn!(
+                "Split pool {} is not reachable, all the miners go to the main pool",
+                upstream.address
+            ),
+            _ => error!(
+                "Pool connection of the account of the workers {:?} failed, they are disconnected until it is open",
+                upstream.worker_prefixes
+            ),
+        }
+        return;
+    };
+    match index {
+        SPLIT_POOL => match Configuration::split_pool_period() {
+            Some(period) => info!(
+                "Sending the miners to the split pool {} for {}% of every {:?}",
+                upstream.address,
+                Configuration::split_pool_ratio(),
+                period
+            ),
+            None => info!(
+                "Sending {}% of the hashrate to the split pool {}",
+                Configuration::split_pool_ratio(),
+                upstream.address
+            ),
+        },
+        _ => info!(
+            "Workers {:?} mine for their own account",
+            upstream.worker_prefixes
+        ),
+    }
+    if senders.safe_lock(|s| s.insert(index, sender)).is_err() {
+        error!("Upstream senders Mutex Corrupted");
+        return;
+    }
+    add_active_upstream(upstream);
+
+    loop {
+        if let Some((_, name)) = handles.iter().find(|(handle, _)| handle.is_finished()) {
+            error!("Task {} finished, closing upstream {}", name, index);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    if senders.safe_lock(|s| s.remove(&index)).is_err() {
+        error!("Upstream senders Mutex Corrupted");
+    }
+    remove_active_upstream(index);
+}
+
+/// Opens a pool connection with `token` and starts a translator with index `index` and a share
+/// accounter on it. Returns the sender of the new miner connections of the translator.
+async fn start_upstream(
+    router: &Router,
+    address: SocketAddr,
+    authority_public_key: Option<Secp256k1PublicKey>,
+    token: &str,
+    index: u32,
+    stats_sender: StatsSender,
+    signature: String,
+) -> Option<(Sender<NewDownstream>, Vec<(AbortOnDrop, String)>)> {
+    let (send_to_pool, recv_from_pool, pool_connection_abortable) = match router
+        .connect_pool_with_token(address, authority_public_key, token)
+        .await
+    {
+        Ok(connection) => connection,
+        Err(e) => {
+            error!("Failed to connect upstream {} to {}: {}", index, address, e);
+            return None;
+        }
+    };
+    let (sender, receiver) = channel(10);
+    let (translator_up_tx, mut translator_up_rx) = channel(10);
+    let translator_abortable =
+        match translator::start(receiver, translator_up_tx, stats_sender, signature, index).await {
+            Ok(abortable) => abortable,
+            Err(e) => {
+                error!("Impossible to initialize the translator of upstream {index}: {e}");
+                return None;
+            }
+        };
+    let Some((to_translator, from_translator, _)) = translator_up_rx.recv().await else {
+        error!(
+            "Translator of upstream {} failed before initialization",
+            index
+        );
+        return None;
+    };
+    let share_accounter_abortable =
+        match share_accounter::start(from_translator, to_translator, recv_from_pool, send_to_pool)
+            .await
+        {
+            Ok(abortable) => abortable,
+            Err(_) => {
+                error!("Failed to start the share_accounter of upstream {}", index);
+                return None;
+            }
+        };
+    Some((
+        sender,
+        vec![
+            (
+                pool_connection_abortable,
+                format!("pool_connection_{}", index),
+            ),
+            (translator_abortable, format!("translator_{}", index)),
+            (
+                share_accounter_abortable,
+                format!("share_accounter_{}", index),
+            ),
+        ],
+    ))
+}
+
+fn dispatch(
+    mut downstreams: Receiver<NewDownstream>,
+    senders: Senders,
+    stats_sender: StatsSender,
+) -> AbortOnDrop {
+    // Pool the miners that are not pinned mine on, when splitting by time
+    let (time_pool_sender, time_pool) = watch::channel(MAIN_POOL);
+    let mut abortable: AbortOnDrop = tokio::spawn(async move {
+        while let Some((send_to_miner, recv_from_miner, address)) = downstreams.recv().await {
+            tokio::spawn(route(
+                send_to_miner,
+                recv_from_miner,
+                address,
+                senders.clone(),
+                stats_sender.clone(),
+                time_pool.clone(),
+            ));
+        }
+    })
+    .into();
+    if let Some(period) = Configuration::split_pool_period() {
+        abortable.add_task(tokio::spawn(async move {
+            loop {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default();
+                let pool = time_split_pool(Configuration::split_pool_ratio(), period, now);
+                time_pool_sender.send_if_modified(|current| {
+                    let changed = *current != pool;
+                    *current = pool;
+                    changed
+                });
+                tokio::time::sleep(TIME_SPLIT_CHECK_INTERVAL).await;
+            }
+        }));
+    }
+    abortable
+}
+
+/// Sends a new miner to the upstream of its worker, see the module documentation.
+async fn route(
+    send_to_miner: Sender<String>,
+    mut recv_from_miner: Receiver<String>,
+    address: IpAddr,
+    senders: Senders,
+    stats_sender: StatsSender,
+    time_pool: watch::Receiver<u32>,
+) {
+    // Messages received before the worker name is known
+    let mut held = Vec::new();
+    let mut worker_name = None;
+    let deadline = tokio::time::Instant::now() + AUTHORIZE_TIMEOUT;
+    while worker_name.is_none() {
+        match tokio::time::timeout_at(deadline, recv_from_miner.recv()).await {
+            Ok(Some(message)) => {
+                worker_name = authorized_worker(&message);
+                held.push(message);
+            }
+            // The miner disconnected
+            Ok(None) => return,
+            Err(_) => break,
+        }
+    }
+    let worker_name = match worker_name {
+        Some(worker_name) => {
+            remember_worker(address, &worker_name);
+            Some(worker_name)
+        }
+        None => WORKERS_BY_ADDRESS
+            .safe_lock(|w| w.get(&address).cloned())
+            .unwrap_or_else(|_| {
+                error!("Workers by address Mutex Corrupted");
+                None
+            }),
+    };
+    let pinned = pinned_upstream(worker_name.as_deref(), address);
+    let index = match pinned {
+        Some(SPLIT_POOL) if !is_running(&senders, SPLIT_POOL) => MAIN_POOL,
+        Some(index) => index,
+        None if !is_running(&senders, SPLIT_POOL) => MAIN_POOL,
+        None if Configuration::split_pool_period().is_some() => *time_pool.borrow(),
+        None if to_split_pool(&stats_sender).await => SPLIT_POOL,
+        None => MAIN_POOL,
+    };
+    let sender = match senders.safe_lock(|s| s.get(&index).cloned()) {
+        Ok(Some(sender)) => sender,
+        Ok(None) => {
+            // Dropping the channels closes the connection
+            warn!(
+                "Account of worker {:?} is not connected, disconnecting miner {}",
+                worker_name, address
+            );
+            return;
+        }
+        Err(_) => {
+            error!("Upstream senders Mutex Corrupted");
+            return;
+        }
+    };
+    info!("Sending miner {} to upstream {}", address, index);
+    let (forward_sender, forward_receiver) = channel(10);
+    tokio::spawn(forward(
+        Routed {
+            address,
+            index,
+            pinned: pinned.is_some(),
+        },
+        held,
+        recv_from_miner,
+        forward_sender,
+        senders,
+        time_pool,
+    ));
+    if sender
+        .send((send_to_miner, forward_receiver, address))
+        .await
+        .is_err()
+    {
+        error!("Translator of upstream {} is gone", index);
+        ProxyState::update_translator_state(
+            TranslatorState::Down,
+            &format!("Translator of upstream {} is gone", index),
+        );
+    }
+}
+
+fn is_running(senders: &Senders, index: u32) -> bool {
+    senders
+        .safe_lock(|s| s.contains_key(&index))
+        .unwrap_or(false)
+}
+
+fn remember_worker(address: IpAddr, worker_name: &str) {
+    if WORKERS_BY_ADDRESS
+        .safe_lock(|w| w.insert(address, worker_name.to_string()))
+        .is_err()
+    {
+        error!("Workers by address Mutex Corrupted");
+    }
+}
+
+/// Upstream a miner always goes to, whatever the ratio or the time: the account of its worker,
+/// or the split pool for the miners and workers given for it.
+fn pinned_upstream(worker_name: Option<&str>, address: IpAddr) -> Option<u32> {
+    if let Some(account) = worker_name.and_then(account_of) {
+        return Some(account);
+    }
+    let split_worker = worker_name.is_some_and(|worker_name| {
+        Configuration::split_pool_workers()
+            .iter()
+            .any(|prefix| worker_name.starts_with(prefix.as_str()))
+    });
+    (split_worker || Configuration::split_pool_miners().contains(&address)).then_some(SPLIT_POOL)
+}
+
+/// True if a new miner should go to the split pool, according to the hashrate of the miners on
+/// the main and the split pools.
+async fn to_split_pool(stats_sender: &StatsSender) -> bool {
+    let stats = stats_sender.collect_stats().await.unwrap_or_else(|e| {
+        warn!("Failed to collect the hashrate of the miners: {}", e);
+        HashMap::new()
+    });
+    let hashrates: Vec<(u32, f64)> = translator::downstream_connection_ids()
+        .into_iter()
+        .filter_map(|connection_id| {
+            let index = translator::upstream_index(connection_id);
+            let hashrate = stats.get(&connection_id).map_or(0.0, |s| s.hashrate as f64);
+            (index == MAIN_POOL || index == SPLIT_POOL).then_some((index, hashrate))
+        })
+        .collect();
+    assign_to_split(Configuration::split_pool_ratio(), &hashrates)
+}
+
+/// True if a new miner should go to the split pool so that the share of the hashrate on it gets
+/// closest to `ratio` percent. `hashrates` are the hashrates of the miners by pool, the miners
+/// that have no hashrate yet, the new one included, count as the average miner.
+fn assign_to_split(ratio: f64, hashrates: &[(u32, f64)]) -> bool {
+    let measured: Vec<f64> = hashrates
+        .iter()
+        .map(|(_, hashrate)| *hashrate)
+        .filter(|hashrate| *hashrate > 0.0)
+        .collect();
+    let average = match measured.is_empty() {
+        true => 1.0,
+        false => measured.iter().sum::<f64>() / measured.len() as f64,
+    };
+    let (mut main, mut split) = (0.0, 0.0);
+    for (index, hashrate) in hashrates {
+        let hashrate = if *hashrate > 0.0 { *hashrate } else { average };
+        match *index {
+            SPLIT_POOL => split += hashrate,
+            _ => main += hashrate,
+        }
+    }
+    ratio / 100.0 * (main + split + average) - split >= average / 2.0
+}
+
+/// Pool the miners that are not pinned mine on at `now` when splitting by time: the split pool
+/// for the first `ratio` percent of every period.
+fn time_split_pool(ratio: f64, period: Duration, now: Duration) -> u32 {
+    let position = now.as_secs_f64() % period.as_secs_f64();
+    match position < ratio / 100.0 * period.as_secs_f64() {
+        true => SPLIT_POOL,
+        false => MAIN_POOL,
+    }
+}
+
+/// A miner sent to an upstream
+struct Routed {
+    address: IpAddr,
+    /// Translator index of the upstream
+    index: u32,
+    /// True if the miner always goes to that upstream, see `pinned_upstream`
+    pinned: bool,
+}
+
+/// Relays the messages of the miner to the translator of its upstream, starting with the messages
+/// held until the miner was routed. The miner is disconnected, so that it reconnects to the right
+/// upstream, when it authorizes as a worker of another upstream or, if it is not pinned, when the
+/// time split switches pool.
+async fn forward(
+    mut routed: Routed,
+    held: Vec<String>,
+    mut recv_from_miner: Receiver<String>,
+    send_to_translator: Sender<String>,
+    senders: Senders,
+    mut time_pool: watch::Receiver<u32>,
+) {
+    let Routed { address, index, .. } = routed;
+    for message in held {
+        if send_to_translator.send(message).await.is_err() {
+            return;
+        }
+    }
+    let mut splitting_by_time = Configuration::split_pool_period().is_some();
+    loop {
+        let message = tokio::select! {
+            message = recv_from_miner.recv() => match message {
+                Some(message) => message,
+                None => return,
+            },
+            changed = time_pool.changed(), if splitting_by_time => {
+                if changed.is_err() {
+                    splitting_by_time = false;
+                    continue;
+                }
+                let pool = *time_pool.borrow_and_update();
+                if !routed.pinned && index <= SPLIT_POOL && pool != index && is_running(&senders, pool) {
+                    info!(
+                        "Time split switched to upstream {}, disconnecting miner {} so that it reconnects to it",
+                        pool, address
+                    );
+                    return;
+                }
+                continue;
+            }
+        };
+        if let Some(worker_name) = authorized_worker(&message) {
+            remember_worker(address, &worker_name);
+            let pinned = pinned_upstream(Some(&worker_name), address);
+            let moved = match pinned {
+                Some(SPLIT_POOL) => index != SPLIT_POOL && is_running(&senders, SPLIT_POOL),
+                Some(account) => account != index,
+                None => index >= FIRST_ACCOUNT,
+            };
+            if moved {
+                info!(
+                    "Worker {} belongs to another upstream, disconnecting miner {} so that it reconnects to it",
+                    worker_name, address
+                );
+                return;
+            }
+            routed.pinned = pinned.is_some();
+        }
+        if send_to_translator.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn authorized_worker(message: &str) -> Option<String> {
+    let message: serde_json::Value = serde_json::from_str(message).ok()?;
+    if message.get("method")?.as_str()? != "mining.authorize" {
+        return None;
+    }
+    message.get("params")?.get(0)?.as_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn assigns_miners_according_to_the_ratio() {
+        let mut hashrates = Vec::new();
+        for _ in 0..100 {
+            match assign_to_split(30.0, &hashrates) {
+                true => hashrates.push((SPLIT_POOL, 0.0)),
+                false => hashrates.push((MAIN_POOL, 0.0)),
+            }
+        }
+        let split_count = hashrates.iter().filter(|(i, _)| *i == SPLIT_POOL).count();
+        assert_eq!(split_count, 30);
+        assert!(!assign_to_split(0.0, &[(MAIN_POOL, 0.0)]));
+        assert!(assign_to_split(100.0, &[(SPLIT_POOL, 0.0)]));
+    }
+
+    #[test]
+    fn assigns_miners_according_to_the_hashrate() {
+        // One big miner on the main pool outweighs several small ones on the split pool
+        let hashrates = [(MAIN_POOL, 300.0), (SPLIT_POOL, 50.0), (SPLIT_POOL, 50.0)];
+        assert!(assign_to_split(50.0, &hashrates));
+        let hashrates = [(MAIN_POOL, 100.0), (SPLIT_POOL, 100.0), (SPLIT_POOL, 100.0)];
+        assert!(!assign_to_split(50.0, &hashrates));
+    }
+
+    #[test]
+    fn splits_the_period_according_to_the_ratio() {
+        let period = Duration::from_secs(100);
+        assert_eq!(
+            time_split_pool(30.0, period, Duration::from_secs(1029)),
+            SPLIT_POOL
+        );
+        assert_eq!(
+            time_split_pool(30.0, period, Duration::from_secs(1030)),
+            MAIN_POOL
+        );
+        assert_eq!(time_split_pool(0.0, period, Duration::ZERO), MAIN_POOL);
+    }
+
+    #[test]
+    fn reads_the_authorized_worker() {
+        let authorize = r#"{"id":2,"method":"mining.authorize","params":["site-a.rig1","x"]}"#;
+        assert_eq!(
+            authorized_worker(authorize),
+            Some("site-a.rig1".to_string())
+        );
+        let subscribe = r#"{"id":1,"method":"mining.subscribe","params":["cgminer/4.11"]}"#;
+        assert_eq!(authorized_worker(subscribe), None);
+        assert_eq!(authorized_worker("not json"), None);
+    }
+}